@@ -0,0 +1,189 @@
+use failure::{Error, ResultExt};
+use git2::{ObjectType, Oid};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str;
+
+const HEX_SHA1_LEN: usize = 40;
+const SHA1_LEN: usize = 20;
+
+/// Objects obtained from a process speaking the `git cat-file --batch` protocol.
+pub struct ObjectStream {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    header: String,
+    buf: Vec<u8>,
+}
+
+pub struct Commit {
+    pub tree: Oid,
+    pub parents: Vec<Oid>,
+}
+
+fn parse_hex(owner: &Oid, hex: &[u8]) -> Result<Oid, Error> {
+    if hex.len() != HEX_SHA1_LEN {
+        bail!("Object {} contains an invalid object id: {:?}", owner, String::from_utf8_lossy(hex))
+    }
+    str::from_utf8(hex)
+        .ok()
+        .and_then(|hex| Oid::from_str(hex).ok())
+        .ok_or_else(|| {
+            format_err!(
+                "Object {} contains an invalid object id: {:?}",
+                owner,
+                String::from_utf8_lossy(hex)
+            )
+        })
+}
+
+/// The type and size of the `requested` object, or None if it is missing.
+fn parse_header(requested: &Oid, line: &str) -> Result<Option<(ObjectType, usize)>, Error> {
+    let tokens: Vec<_> = line.split(' ').collect();
+    match tokens.as_slice() {
+        &[hex, "missing"] if hex == requested.to_string() => Ok(None),
+        &[hex, kind, size] if hex == requested.to_string() => Ok(Some((
+            ObjectType::from_str(kind)
+                .ok_or_else(|| format_err!("Object {} has unknown type '{}'", requested, kind))?,
+            size.parse()
+                .with_context(|_| format!("Object {} has invalid size '{}'", requested, size))?,
+        ))),
+        _ => bail!(
+            "Protocol violation: expected header for object {}, got '{}'",
+            requested,
+            line
+        ),
+    }
+}
+
+/// Extract the tree and parents from a raw commit object. Headers we don't know are skipped,
+/// along with the continuation lines of multi-line headers like 'gpgsig' and 'mergetag'.
+pub fn parse_commit(oid: &Oid, data: &[u8]) -> Result<Commit, Error> {
+    let mut tree = None;
+    let mut parents = Vec::new();
+    for line in data.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(b" ") {
+            continue;
+        }
+        if line.starts_with(b"tree ") && tree.is_none() {
+            tree = Some(parse_hex(oid, &line[5..])?);
+        } else if line.starts_with(b"parent ") {
+            parents.push(parse_hex(oid, &line[7..])?);
+        }
+    }
+    Ok(Commit {
+        tree: tree.ok_or_else(|| format_err!("Commit {} has no tree", oid))?,
+        parents,
+    })
+}
+
+/// Extract the object an annotated tag points to from its raw object.
+pub fn parse_tag_target(oid: &Oid, data: &[u8]) -> Result<Oid, Error> {
+    data.split(|&b| b == b'\n')
+        .take_while(|l| !l.is_empty())
+        .find(|l| l.starts_with(b"object "))
+        .ok_or_else(|| format_err!("Tag {} does not point to an object", oid))
+        .and_then(|l| parse_hex(oid, &l[7..]))
+}
+
+/// Parse the entries of a raw tree object into `out`, along with the range of their name in
+/// `data`. Gitlinks are reported as commits.
+pub fn parse_tree(
+    oid: &Oid,
+    data: &[u8],
+    out: &mut Vec<(ObjectType, Oid, Range<usize>)>,
+) -> Result<(), Error> {
+    out.clear();
+    let mut ofs = 0;
+    while ofs < data.len() {
+        let entry = &data[ofs..];
+        let malformed = || format_err!("Tree {} is malformed", oid);
+        let space = entry.iter().position(|&b| b == b' ').ok_or_else(malformed)?;
+        let nul = space + entry[space..].iter().position(|&b| b == 0).ok_or_else(malformed)?;
+        let end = nul + 1 + SHA1_LEN;
+        if entry.len() < end {
+            return Err(malformed());
+        }
+        let kind = match &entry[..space] {
+            b"40000" | b"040000" => ObjectType::Tree,
+            b"160000" => ObjectType::Commit,
+            b"100644" | b"100755" | b"100664" | b"120000" => ObjectType::Blob,
+            _ => ObjectType::Any,
+        };
+        let name = ofs + space + 1..ofs + nul;
+        out.push((kind, Oid::from_bytes(&entry[nul + 1..end])?, name));
+        ofs += end;
+    }
+    Ok(())
+}
+
+impl ObjectStream {
+    /// Run `command` with `sh`, expecting it to behave like `git cat-file --batch`.
+    pub fn spawn(command: &str) -> Result<ObjectStream, Error> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|_| format!("Could not spawn object stream '{}'", command))?;
+        let input = child.stdin.take().expect("piped stdin");
+        let output = BufReader::new(child.stdout.take().expect("piped stdout"));
+        Ok(ObjectStream {
+            child,
+            input,
+            output,
+            header: String::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Request `oid` and return its type and raw data. The data is valid until the next read.
+    pub fn read(&mut self, oid: &Oid) -> Result<(ObjectType, &[u8]), Error> {
+        match self.try_read(oid)? {
+            Some(object) => Ok(object),
+            None => bail!("Object {} is missing from the object stream", oid),
+        }
+    }
+
+    /// Like `read()`, but returns None if the object is missing.
+    pub fn try_read(&mut self, oid: &Oid) -> Result<Option<(ObjectType, &[u8])>, Error> {
+        writeln!(self.input, "{}", oid)?;
+        self.input.flush()?;
+        self.header.clear();
+        if self.output.read_line(&mut self.header)? == 0 {
+            bail!("The object stream ended while waiting for object {}", oid)
+        }
+        let (kind, size) = match parse_header(oid, self.header.trim_right_matches('\n'))? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        self.buf.resize(size + 1, 0);
+        self.output
+            .read_exact(&mut self.buf)
+            .with_context(|_| format!("The object stream ended within object {}", oid))?;
+        if self.buf[size] != b'\n' {
+            bail!(
+                "Protocol violation: object {} of size {} is not followed by a newline",
+                oid,
+                size
+            )
+        }
+        Ok(Some((kind, &self.buf[..size])))
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        drop(self.input);
+        let mut child = self.child;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("The object stream exited with {}", status)
+        }
+        Ok(())
+    }
+}
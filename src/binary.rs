@@ -0,0 +1,193 @@
+//! A compact encoding of lookup results: a header with magic, version and the options
+//! fingerprint, followed by one record per blob with its id, the path it was requested for, the
+//! amount of commits and their ids. All integers are little-endian.
+
+use failure::{Error, ResultExt};
+use git2::Oid;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"GRLR";
+const VERSION: u32 = 2;
+/// Records of version 1 have no path.
+const VERSION_WITHOUT_PATHS: u32 = 1;
+const SHA1_LEN: usize = 20;
+/// Fingerprints are short, longer lengths indicate a corrupt file.
+const MAX_FINGERPRINT_LEN: usize = 1 << 16;
+/// Like fingerprints, paths are short.
+const MAX_PATH_LEN: usize = 1 << 16;
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+fn write_u32(out: &mut impl Write, value: u32) -> io::Result<()> {
+    out.write_all(&[
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ])
+}
+
+fn u32_from(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | u32::from(b))
+}
+
+pub fn write_header(out: &mut impl Write, fingerprint: &str) -> Result<(), Error> {
+    out.write_all(MAGIC)?;
+    write_u32(out, VERSION)?;
+    write_u32(out, fingerprint.len() as u32)?;
+    out.write_all(fingerprint.as_bytes())?;
+    Ok(())
+}
+
+/// Write the record of `blob`, requested for `path` if known, with the commits of the textual
+/// output `record`. Requests without path are stored with an empty one.
+pub fn write_record(
+    out: &mut impl Write,
+    blob: &Oid,
+    path: Option<&str>,
+    record: &str,
+) -> Result<(), Error> {
+    let commits: Vec<_> = record
+        .split(|c| c == ' ' || c == '\0' || c == '\n')
+        .filter(|c| !c.is_empty())
+        .map(Oid::from_str)
+        .collect::<Result<_, _>>()?;
+    let path = path.unwrap_or_default();
+    out.write_all(blob.as_bytes())?;
+    write_u32(out, path.len() as u32)?;
+    out.write_all(path.as_bytes())?;
+    write_u32(out, commits.len() as u32)?;
+    for commit in commits {
+        out.write_all(commit.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Read as many bytes as fit into `buf`, returning less only at the end of `input`.
+fn read_up_to(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads `len` bytes at `offset`, failing with the offset and `what` was expected there.
+/// The buffer only grows with the data actually read, so corrupt lengths can't exhaust memory.
+fn read_exactly(
+    input: &mut impl Read,
+    buf: &mut Vec<u8>,
+    len: usize,
+    offset: &mut u64,
+    what: &str,
+) -> Result<(), Error> {
+    buf.clear();
+    let read = input.by_ref().take(len as u64).read_to_end(buf)?;
+    if read < len {
+        bail!(
+            "Truncated {} at byte offset {}: expected {} bytes, got {}",
+            what,
+            *offset,
+            len,
+            read
+        )
+    }
+    *offset += len as u64;
+    Ok(())
+}
+
+/// Write the records of the binary result file at `path` in the textual output format,
+/// returning the amount of records.
+pub fn decode(path: &Path, nul_terminated: bool, out: &mut impl Write) -> Result<usize, Error> {
+    let file = File::open(path)
+        .with_context(|_| format!("Could not open binary results at '{}'", path.display()))?;
+    let mut input = BufReader::with_capacity(BUFFER_SIZE, file);
+    let mut buf = Vec::new();
+    let mut offset = 0;
+
+    read_exactly(&mut input, &mut buf, MAGIC.len() + 8, &mut offset, "header")?;
+    if &buf[..MAGIC.len()] != MAGIC {
+        bail!("'{}' does not contain binary results", path.display())
+    }
+    let version = u32_from(&buf[4..8]);
+    if version != VERSION && version != VERSION_WITHOUT_PATHS {
+        bail!(
+            "Binary results of version {} are not supported, only versions {} and {}",
+            version,
+            VERSION_WITHOUT_PATHS,
+            VERSION
+        )
+    }
+    let fingerprint_len = u32_from(&buf[8..12]) as usize;
+    if fingerprint_len > MAX_FINGERPRINT_LEN {
+        bail!(
+            "Invalid options fingerprint length {} at byte offset 8",
+            fingerprint_len
+        )
+    }
+    read_exactly(&mut input, &mut buf, fingerprint_len, &mut offset, "options fingerprint")?;
+    eprintln!("Options: {}", String::from_utf8_lossy(&buf));
+
+    let mut num_records = 0;
+    loop {
+        let record_offset = offset;
+        buf.resize(SHA1_LEN + 4, 0);
+        let read = read_up_to(&mut input, &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if read < buf.len() {
+            bail!(
+                "Truncated record at byte offset {}: expected {} bytes of blob id and length, got {}",
+                record_offset,
+                buf.len(),
+                read
+            )
+        }
+        offset += read as u64;
+        let mut len = u32_from(&buf[SHA1_LEN..]) as usize;
+        // Since version 2, the path and its length come before the amount of commits.
+        if version != VERSION_WITHOUT_PATHS {
+            if len > MAX_PATH_LEN {
+                bail!("Invalid path length {} in record at byte offset {}", len, record_offset)
+            }
+            read_exactly(&mut input, &mut buf, len + 4, &mut offset, "path of record")
+                .with_context(|_| format!("Record at byte offset {} is corrupt", record_offset))?;
+            if len > 0 {
+                let path = String::from_utf8_lossy(&buf[..len]);
+                write!(out, "{}{}", path, if nul_terminated { '\0' } else { '\t' })?;
+            }
+            len = u32_from(&buf[len..]) as usize;
+        }
+        let num_commits = len;
+        read_exactly(
+            &mut input,
+            &mut buf,
+            num_commits * SHA1_LEN,
+            &mut offset,
+            "commits of record",
+        ).with_context(|_| format!("Record at byte offset {} is corrupt", record_offset))?;
+        for (cid, commit) in buf.chunks(SHA1_LEN).enumerate() {
+            let commit = Oid::from_bytes(commit)?;
+            if nul_terminated {
+                write!(out, "{}\0", commit)?;
+            } else if cid == 0 {
+                write!(out, "{}", commit)?;
+            } else {
+                write!(out, " {}", commit)?;
+            }
+        }
+        write!(out, "{}", if nul_terminated { '\0' } else { '\n' })?;
+        num_records += 1;
+    }
+    Ok(num_records)
+}
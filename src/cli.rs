@@ -1,23 +1,760 @@
-use failure::Error;
+use failure::{Error, Fail, ResultExt};
 use lut;
-use std::{fs::{metadata, File, OpenOptions},
-          io::{stdin, stdout, BufRead, BufReader, BufWriter, Write}};
+use std::{cmp::Reverse, fmt, str::FromStr, collections::{BTreeSet, BinaryHeap, HashMap, HashSet},
+          fs::{self, metadata, File, OpenOptions}, path::{Path, PathBuf}, process,
+          io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Write}, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use git2::Oid;
 use {Options, Stack};
 use find;
+use replay::{self, Recorder};
+use notes::Notes;
+use pathspec;
+use binary;
+use coverage::{CoverageReport, GraphCoverage, Source};
 use indicatif::ProgressBar;
-use lut::{ReverseGraph, StorableReverseGraph};
+use lut::{BuildOptions, CacheHeader, CacheTrailer, ReverseGraph, StorableReverseGraph, Version};
+use git2::Repository;
 use lz4;
 
 const PROGRESS_RATE: usize = 25;
+pub const NO_COMMITS_EXIT_CODE: i32 = 2;
+pub const PARTIAL_MATCH_EXIT_CODE: i32 = 4;
+const LENIENT_PUNCTUATION: &[char] = &['"', '\'', '[', ']', ',', ';'];
+const VALIDATE_INPUT_MAX_BYTES: u64 = 1 << 30;
+pub const MALFORMED_RECORDS_SHOWN: usize = 20;
+const UPDATE_HINT_CONFIG_KEY: &str = "reconstruct.updateHint";
+const DEFAULT_UPDATE_HINT: &str = "Please update git-reconstruct.";
+const CACHE_FILE_PREFIX: &str = "cache-";
+const CACHE_FILE_SUFFIX: &str = ".bincode";
+const CACHE_LOCK_SUFFIX: &str = ".lock";
+const CACHE_TMP_SUFFIX: &str = ".tmp";
+const CACHE_LOCK_POLL_MS: u64 = 100;
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const DANGLING_SUFFIX: &str = ":dangling";
+const INDEX_LABEL: &str = ":index";
 
-fn deplete_requests_from_stdin(graph: ReverseGraph) -> Result<(), Error> {
-    let mut commits = Vec::new();
+/// A result which isn't an error, but should be signalled with a distinct exit code.
+#[derive(Debug)]
+pub struct Exit {
+    pub code: i32,
+    pub message: String,
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Fail for Exit {}
+
+/// What to do if a blob is not contained in any commit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnEmpty {
+    Blank,
+    Report,
+    Fail,
+}
+
+impl FromStr for OnEmpty {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "blank" => OnEmpty::Blank,
+            "report" => OnEmpty::Report,
+            "fail" => OnEmpty::Fail,
+            _ => return Err(format!("'{}' is not one of 'blank', 'report' or 'fail'", s)),
+        })
+    }
+}
+
+/// How records on stdin are compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputCompression {
+    None,
+    Lz4,
+    Auto,
+}
+
+impl FromStr for InputCompression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => InputCompression::None,
+            "lz4" => InputCompression::Lz4,
+            "auto" => InputCompression::Auto,
+            _ => return Err(format!("'{}' is not one of 'none', 'lz4' or 'auto'", s)),
+        })
+    }
+}
+
+/// What to find for each blob received on stdin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Lookup,
+    Siblings,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "lookup" => Mode::Lookup,
+            "siblings" => Mode::Siblings,
+            _ => return Err(format!("'{}' is not one of 'lookup' or 'siblings'", s)),
+        })
+    }
+}
+
+/// How lookup results are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Binary,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => OutputFormat::Text,
+            "binary" => OutputFormat::Binary,
+            _ => return Err(format!("'{}' is not one of 'text' or 'binary'", s)),
+        })
+    }
+}
+
+/// How records on stdin are to be interpreted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Oids,
+    RawDiff,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "oids" => InputFormat::Oids,
+            "raw-diff" => InputFormat::RawDiff,
+            _ => return Err(format!("'{}' is not one of 'oids' or 'raw-diff'", s)),
+        })
+    }
+}
+
+/// A blob to look up as hex-sha, possibly abbreviated, along with its path if the input has one.
+struct Request {
+    hexsha: String,
+    path: Option<String>,
+}
+
+/// Parse the header of a raw diff entry like ':100644 100644 <old> <new> M' into the hex-sha
+/// to use and the amount of paths following it, which is two for renames and copies.
+fn parse_raw_diff_header(header: &str, use_old_oid: bool) -> Option<(String, usize)> {
+    if !header.starts_with(':') {
+        return None;
+    }
+    let fields: Vec<_> = header[1..].split_whitespace().collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    let hexsha = fields[if use_old_oid { 2 } else { 3 }].trim_right_matches('.');
+    let num_paths = if fields[4].starts_with('R') || fields[4].starts_with('C') {
+        2
+    } else {
+        1
+    };
+    Some((hexsha.to_owned(), num_paths))
+}
+
+fn raw_diff_request(hexsha: String, mut paths: Vec<String>, use_old_oid: bool) -> Option<Request> {
+    if hexsha.is_empty() || hexsha.chars().all(|c| c == '0') {
+        return None;
+    }
+    let path = if use_old_oid {
+        paths.drain(..).next()
+    } else {
+        paths.pop()
+    };
+    Some(Request { hexsha, path })
+}
+
+/// Produces requests from 'git log --raw -z' output, in which headers and paths are separate
+/// records. Commit headers of 'git log' end up in front of the first entry header.
+struct RawDiffRecords<I> {
+    records: I,
+    use_old_oid: bool,
+}
+
+impl<I: Iterator<Item = String>> Iterator for RawDiffRecords<I> {
+    type Item = Request;
+    fn next(&mut self) -> Option<Request> {
+        loop {
+            let record = self.records.next()?;
+            let header = record.rsplit('\n').next().unwrap_or("");
+            let (hexsha, num_paths) = match parse_raw_diff_header(header, self.use_old_oid) {
+                Some(v) => v,
+                None => continue,
+            };
+            let paths = self.records.by_ref().take(num_paths).collect();
+            if let Some(request) = raw_diff_request(hexsha, paths, self.use_old_oid) {
+                return Some(request);
+            }
+        }
+    }
+}
+
+fn requests<'a>(read: impl BufRead + 'a, opts: &Options) -> Box<dyn Iterator<Item = Request> + 'a> {
+    let nul_terminated = opts.nul_terminated || opts.input_z;
+    let use_old_oid = opts.use_old_oid;
+    match (opts.input_format, nul_terminated) {
+        (InputFormat::Oids, _) => Box::new(
+            records(read, nul_terminated).map(|hexsha| Request { hexsha, path: None }),
+        ),
+        (InputFormat::RawDiff, true) => Box::new(RawDiffRecords {
+            records: records(read, true),
+            use_old_oid,
+        }),
+        (InputFormat::RawDiff, false) => Box::new(records(read, false).filter_map(move |line| {
+            let mut fields = line.split('\t');
+            let (hexsha, _) = parse_raw_diff_header(fields.next()?, use_old_oid)?;
+            raw_diff_request(hexsha, fields.map(ToOwned::to_owned).collect(), use_old_oid)
+        })),
+    }
+}
+
+fn lenient_trim(line: &str) -> &str {
+    line.trim_matches(|c: char| c.is_whitespace() || LENIENT_PUNCTUATION.contains(&c))
+}
+
+fn parse_oid(line: &str, lenient: bool) -> Result<Oid, Error> {
+    let hexsha = if lenient { lenient_trim(line) } else { line };
+    Oid::from_str(hexsha).map_err(Into::into)
+}
+
+/// Like `parse_oid`, but abbreviated hex-shas are resolved using the first graph knowing them.
+/// Unknown abbreviations are padded with zeros, and thus are looked up without result.
+fn resolve_oid(graphs: &[ReverseGraph], line: &str, lenient: bool) -> Result<Oid, Error> {
+    let hexsha = if lenient { lenient_trim(line) } else { line };
+    let oid = Oid::from_str(hexsha)?;
+    if hexsha.len() < 40 {
+        for graph in graphs {
+            if let Some(oid) = graph.resolve_prefix(hexsha)? {
+                return Ok(oid);
+            }
+        }
+    }
+    Ok(oid)
+}
+
+/// Fail if `oid` is known to the first graph knowing it as another kind than `kind`.
+fn check_kind(
+    graphs: &[ReverseGraph],
+    oid: &Oid,
+    kind: Option<lut::Kind>,
+    record_number: usize,
+) -> Result<(), Error> {
+    if let Some(kind) = kind {
+        if let Some(actual) = graphs.iter().filter_map(|g| g.kind_of_oid(oid)).next() {
+            if actual != kind {
+                bail!(
+                    "Input record {} is the {} {}, but --only-kind expects a {}",
+                    record_number,
+                    actual,
+                    oid,
+                    kind
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The times of commits, remembered as the same commits tend to be part of many results.
+struct CommitTimes {
+    repo: Repository,
+    times: HashMap<Oid, i64>,
+}
+
+impl CommitTimes {
+    fn open(repository: &Path) -> Result<CommitTimes, Error> {
+        Ok(CommitTimes {
+            repo: lut::open_repository(repository)
+                .with_context(|_| "Commit times can only be obtained from a repository")?,
+            times: HashMap::new(),
+        })
+    }
+    /// The time of `commit` in seconds since the epoch, or 0 if it can't be read.
+    fn get(&mut self, commit: Oid) -> i64 {
+        let repo = &self.repo;
+        *self.times.entry(commit).or_insert_with(|| {
+            repo.find_commit(commit)
+                .map(|c| c.time().seconds())
+                .unwrap_or(0)
+        })
+    }
+}
+
+fn milliseconds(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + f64::from(d.subsec_nanos()) / 1e6
+}
+
+/// Keeps the `capacity` slowest lookups seen so far.
+struct SlowestLookups {
+    capacity: usize,
+    heap: BinaryHeap<Reverse<(Duration, Oid, usize)>>,
+}
+
+impl SlowestLookups {
+    fn new(capacity: usize) -> Self {
+        SlowestLookups {
+            capacity,
+            heap: BinaryHeap::with_capacity(capacity + 1),
+        }
+    }
+    fn record(&mut self, elapsed: Duration, blob: Oid, num_commits: usize) {
+        self.heap.push(Reverse((elapsed, blob, num_commits)));
+        if self.heap.len() > self.capacity {
+            self.heap.pop();
+        }
+    }
+    fn report(self) {
+        eprintln!("Slowest {} lookups:", self.heap.len());
+        for Reverse((elapsed, blob, num_commits)) in self.heap.into_sorted_vec() {
+            eprintln!(
+                "{} {:.3}ms {} commits",
+                blob,
+                milliseconds(elapsed),
+                num_commits
+            );
+        }
+    }
+}
+
+/// Write the `path` a request was made for, separated from the rest of its record by a tab,
+/// or by NUL if records are NUL-terminated, as paths may contain tabs or newlines then.
+fn write_path(out: &mut impl Write, path: &str, nul_terminated: bool) -> io::Result<()> {
+    write!(out, "{}{}", path, if nul_terminated { '\0' } else { '\t' })
+}
+
+pub fn record_terminator(nul_terminated: bool) -> char {
+    if nul_terminated {
+        '\0'
+    } else {
+        '\n'
+    }
+}
+
+/// Call `f` with each commit containing `blob` in the first of `graphs` knowing it,
+/// or in all of them without duplicates if `union` is set.
+fn lookup_layered(
+    graphs: &[ReverseGraph],
+    union: bool,
+    blob: &Oid,
+    stack: &mut Stack,
+    mut f: impl FnMut(Oid),
+) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    for graph in graphs.iter().filter(|g| g.contains(blob)) {
+        graph.lookup_streaming(blob, stack, |commit_oid| {
+            if !union || seen.insert(commit_oid) {
+                f(commit_oid)
+            }
+        })?;
+        if !union {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The label of the repository `commit` was found in followed by ':', like 'fork:', if there
+/// are multiple repositories.
+fn repository_prefix(graphs: &[ReverseGraph], commit: &Oid) -> String {
+    graphs
+        .iter()
+        .filter_map(|g| g.repository_of(commit).map(|r| (g, r)))
+        .next()
+        .and_then(|(g, r)| g.repository_label(r))
+        .map_or_else(String::new, |label| format!("{}:", label))
+}
+
+/// The records following the lookup result of `blob`, telling it wasn't indexed due to its size
+/// or naming the submodule it was found in.
+fn blob_records(graphs: &[ReverseGraph], blob: &Oid, nul_terminated: bool) -> String {
+    let mut records = String::new();
+    if graphs.iter().any(|g| g.is_oversized(blob)) {
+        records.push_str(&format!(
+            "not-indexed {} size-filter{}",
+            blob,
+            record_terminator(nul_terminated)
+        ));
+    }
+    if let Some(path) = graphs.iter().filter_map(|g| g.submodule_of(blob)).next() {
+        records.push_str(&format!(
+            "submodule {} {}{}",
+            blob,
+            path,
+            record_terminator(nul_terminated)
+        ));
+    }
+    records
+}
+
+/// True if `blob` is staged in the index according to any of `graphs`.
+fn is_staged(graphs: &[ReverseGraph], blob: &Oid) -> bool {
+    graphs.iter().any(|graph| graph.is_staged(blob))
+}
+
+/// True if `commit` is dangling in any of `graphs`.
+fn is_dangling(graphs: &[ReverseGraph], commit: &Oid) -> bool {
+    graphs.iter().any(|graph| graph.is_dangling(commit))
+}
+
+/// The suffix of `commit` in lookup output, marking it as dangling or naming its stash entry.
+fn commit_suffix(graphs: &[ReverseGraph], commit: &Oid) -> String {
+    let mut suffix = String::new();
+    if is_dangling(graphs, commit) {
+        suffix.push_str(DANGLING_SUFFIX);
+    }
+    if let Some(label) = graphs.iter().filter_map(|graph| graph.stash_label(commit)).next() {
+        suffix.push(':');
+        suffix.push_str(label);
+    }
+    suffix
+}
+
+fn stream_commits(
+    graphs: &[ReverseGraph],
+    blob: &Oid,
+    stack: &mut Stack,
+    out: &mut impl Write,
+    opts: &Options,
+) -> Result<usize, Error> {
+    let nul_terminated = opts.nul_terminated;
+    let mut num_commits = 0;
+    let mut result = Ok(());
+    lookup_layered(graphs, opts.union_caches, blob, stack, |commit_oid| {
+        if result.is_ok() {
+            let prefix = repository_prefix(graphs, &commit_oid);
+            let suffix = commit_suffix(graphs, &commit_oid);
+            result = if nul_terminated {
+                write!(out, "{}{}{}\0", prefix, commit_oid, suffix)
+            } else if num_commits == 0 {
+                write!(out, "{}{}{}", prefix, commit_oid, suffix)
+            } else {
+                write!(out, " {}{}{}", prefix, commit_oid, suffix)
+            };
+            if num_commits == 0 {
+                result = result.and_then(|_| out.flush());
+            }
+        }
+        num_commits += 1;
+    })?;
+    result?;
+    if is_staged(graphs, blob) {
+        if nul_terminated {
+            write!(out, "{}\0", INDEX_LABEL)?;
+        } else if num_commits == 0 {
+            write!(out, "{}", INDEX_LABEL)?;
+        } else {
+            write!(out, " {}", INDEX_LABEL)?;
+        }
+    }
+    Ok(num_commits)
+}
+
+pub fn records<'a>(read: impl BufRead + 'a, nul_terminated: bool) -> Box<dyn Iterator<Item = String> + 'a> {
+    if nul_terminated {
+        Box::new(
+            read.split(b'\0')
+                .filter_map(Result::ok)
+                .map(|r| String::from_utf8_lossy(&r).into_owned()),
+        )
+    } else {
+        Box::new(read.lines().filter_map(Result::ok))
+    }
+}
+
+/// Stdin, decompressed as configured by `compression`.
+fn decompressed_stdin(compression: InputCompression) -> Result<Box<dyn BufRead>, Error> {
+    let mut input = BufReader::new(stdin());
+    let compression = match compression {
+        InputCompression::Auto => {
+            let head = input.fill_buf()?;
+            if head.starts_with(&LZ4_FRAME_MAGIC) {
+                InputCompression::Lz4
+            } else if head.starts_with(&GZIP_MAGIC) {
+                bail!("gzip-compressed input is not supported, please decompress it with 'gzip -dc' first")
+            } else {
+                InputCompression::None
+            }
+        }
+        compression => compression,
+    };
+    Ok(match compression {
+        InputCompression::Lz4 => Box::new(BufReader::new(lz4::Decoder::new(input)?)),
+        _ => Box::new(input),
+    })
+}
+
+fn buffer_input(mut read: impl Read) -> Result<Vec<u8>, Error> {
+    let mut input = Vec::new();
+    read.by_ref()
+        .take(VALIDATE_INPUT_MAX_BYTES + 1)
+        .read_to_end(&mut input)?;
+    if input.len() as u64 > VALIDATE_INPUT_MAX_BYTES {
+        bail!(
+            "Input is larger than {} bytes and can't be validated up-front",
+            VALIDATE_INPUT_MAX_BYTES
+        )
+    }
+    Ok(input)
+}
+
+/// Check the syntax of all records and fail if the fraction of malformed ones exceeds
+/// --max-invalid-fraction.
+fn validate_input(input: &[u8], opts: &Options) -> Result<(), Error> {
+    let (mut num_records, mut num_valid, mut num_duplicates) = (0, 0, 0);
+    let mut seen = HashSet::new();
+    let mut malformed = Vec::new();
+    for (rid, record) in requests(input, opts).map(|r| r.hexsha).enumerate() {
+        if opts.lenient && lenient_trim(&record).is_empty() {
+            continue;
+        }
+        num_records += 1;
+        match parse_oid(&record, opts.lenient) {
+            Ok(oid) => {
+                num_valid += 1;
+                if !seen.insert(oid) {
+                    num_duplicates += 1;
+                }
+            }
+            Err(_) => malformed.push((rid + 1, record)),
+        }
+    }
+    eprintln!(
+        "Validated {} records: {} valid object ids, {} malformed, {} duplicates",
+        num_records,
+        num_valid,
+        malformed.len(),
+        num_duplicates
+    );
+    for &(rid, ref record) in malformed.iter().take(MALFORMED_RECORDS_SHOWN) {
+        eprintln!("malformed record {}: {:?}", rid, record);
+    }
+    if malformed.len() > MALFORMED_RECORDS_SHOWN {
+        eprintln!("... and {} more", malformed.len() - MALFORMED_RECORDS_SHOWN);
+    }
+    let invalid_fraction = if num_records == 0 {
+        0.0
+    } else {
+        malformed.len() as f64 / num_records as f64
+    };
+    if invalid_fraction > opts.max_invalid_fraction {
+        bail!(
+            "{:.1}% of records are malformed, but --max-invalid-fraction allows only {:.1}%",
+            invalid_fraction * 100.0,
+            opts.max_invalid_fraction * 100.0
+        )
+    }
+    Ok(())
+}
+
+/// The options affecting the results of lookups, to be compared when checking replays.
+/// The options selecting the commits to traverse and changing the results of lookups, to tell
+/// whether recorded results are comparable.
+fn options_fingerprint(opts: &Options) -> String {
+    let mut parts = vec![
+        lut::traversal_fingerprint(&BuildOptions::from(opts)),
+        format!("lenient={}", opts.lenient),
+        format!("union-caches={}", opts.union_caches),
+    ];
+    if let Some(kind) = opts.only_kind {
+        parts.push(format!("only-kind={}", kind));
+    }
+    if opts.input_format == InputFormat::RawDiff {
+        parts.push("input-format=raw-diff".into());
+    }
+    parts.join(" ")
+}
+
+fn check_replay(graphs: &[ReverseGraph], path: &Path, opts: &Options) -> Result<(), Error> {
+    let mut stack = Stack::default();
+    let num_differing = replay::check(
+        path,
+        &options_fingerprint(opts),
+        replay::graph_digest(graphs),
+        |input| {
+            let oid = resolve_oid(graphs, input, opts.lenient)?;
+            let mut commits = Vec::new();
+            lookup_layered(graphs, opts.union_caches, &oid, &mut stack, |commit_oid| {
+                commits.push(commit_oid.to_string())
+            })?;
+            Ok((oid, commits))
+        },
+    )?;
+    if num_differing > 0 {
+        bail!(
+            "{} queries produced different results than recorded in '{}'",
+            num_differing,
+            path.display()
+        )
+    }
+    Ok(())
+}
+
+/// Compare the commits of `blob` found in `graphs` with the ones found by reading all trees of
+/// the repository, and fail listing each commit only one of them found.
+fn self_check(graphs: &[ReverseGraph], blob: &str, opts: &Options) -> Result<(), Error> {
+    let blob = resolve_oid(graphs, blob, opts.lenient)?;
+    let mut from_graph = BTreeSet::new();
+    lookup_layered(graphs, opts.union_caches, &blob, &mut Stack::default(), |commit_oid| {
+        from_graph.insert(commit_oid);
+    })?;
+    let from_repository: BTreeSet<_> = lut::commits_containing(&blob, &BuildOptions::from(opts))?
+        .into_iter()
+        .collect();
+    for commit in from_graph.difference(&from_repository) {
+        eprintln!("only in graph: {}", commit);
+    }
+    for commit in from_repository.difference(&from_graph) {
+        eprintln!("only in repository: {}", commit);
+    }
+    if from_graph != from_repository {
+        bail!(
+            "Self-check of blob {} failed: the graph found {} commits, reading the repository found {}",
+            blob,
+            from_graph.len(),
+            from_repository.len()
+        )
+    }
+    eprintln!(
+        "Self-check of blob {} passed: graph and repository agree on {} commits",
+        blob,
+        from_graph.len()
+    );
+    Ok(())
+}
 
-    let stdin = stdin();
+/// Write the blobs sharing a tree with each blob received on stdin, in the format of lookups.
+fn deplete_sibling_requests(
+    graphs: &[ReverseGraph],
+    read: impl BufRead,
+    opts: &Options,
+) -> Result<(), Error> {
+    if opts.stream || opts.output_dir.is_some() || opts.show_notes.is_some()
+        || opts.record_replay.is_some() || opts.format == OutputFormat::Binary
+    {
+        bail!("--mode=siblings can't be used with --stream, --output-dir, --show-notes, --record-replay or --format=binary")
+    }
     let stdout = stdout();
+    let mut out = stdout.lock();
+    let mut siblings = Vec::new();
+    let (mut num_blobs, mut total_siblings) = (0, 0);
+
+    eprintln!("Waiting for input...");
+    for (rid, request) in requests(read, opts).enumerate() {
+        let line = request.hexsha;
+        if opts.lenient && lenient_trim(&line).is_empty() {
+            continue;
+        }
+        num_blobs += 1;
+        let oid = resolve_oid(graphs, &line, opts.lenient)?;
+        check_kind(graphs, &oid, opts.only_kind, rid + 1)?;
+        siblings.clear();
+        for graph in graphs.iter().filter(|graph| graph.contains(&oid)) {
+            graph.siblings(&oid, opts.max_siblings, &mut siblings)?;
+            if !opts.union_caches {
+                break;
+            }
+        }
+        total_siblings += siblings.len();
+
+        if let Some(ref path) = request.path {
+            write_path(&mut out, path, opts.nul_terminated)?;
+        }
+        let len = siblings.len();
+        for (sid, sibling) in siblings.iter().enumerate() {
+            write!(out, "{}", sibling)?;
+            if opts.nul_terminated {
+                write!(out, "\0")?;
+            } else if sid + 1 < len {
+                write!(out, " ")?;
+            }
+        }
+        write!(out, "{}", record_terminator(opts.nul_terminated))?;
+        out.flush()?;
+    }
+    eprintln!(
+        "DONE: Found a total of {} siblings of {} blobs",
+        total_siblings, num_blobs
+    );
+    Ok(())
+}
 
-    let read = BufReader::new(stdin.lock());
+fn deplete_requests(
+    graphs: &[ReverseGraph],
+    read: impl BufRead,
+    opts: &Options,
+) -> Result<(), Error> {
+    if opts.mode == Mode::Siblings {
+        return deplete_sibling_requests(graphs, read, opts);
+    }
+    let mut commits = Vec::new();
+    let mut deduped = HashMap::new();
+    if opts.stream && opts.dedupe_input {
+        bail!("--stream and --dedupe-input are mutually exclusive")
+    }
+    if opts.stream && opts.show_notes.is_some() {
+        bail!("--stream and --show-notes are mutually exclusive")
+    }
+    let mut notes = opts.show_notes
+        .clone()
+        .map(|notes_ref| Notes::open(&opts.repository, notes_ref, opts.note_max_chars));
+    let mut commit_times = if opts.commit_time_output {
+        if opts.stream || opts.format == OutputFormat::Binary || opts.record_replay.is_some() {
+            bail!("--commit-time-output can't be used with --stream, --format=binary or --record-replay")
+        }
+        Some(CommitTimes::open(&opts.repository)?)
+    } else {
+        None
+    };
+    let mut binary_out = match opts.format {
+        OutputFormat::Binary => {
+            if opts.stream || opts.output_dir.is_some() || opts.show_notes.is_some() {
+                bail!("--format=binary can't be used with --stream, --output-dir or --show-notes")
+            }
+            let mut out = BufWriter::with_capacity(binary::BUFFER_SIZE, stdout());
+            binary::write_header(&mut out, &options_fingerprint(opts))?;
+            Some(out)
+        }
+        OutputFormat::Text => None,
+    };
+    let mut recorder = match opts.record_replay {
+        Some(ref path) => {
+            if opts.stream {
+                bail!("--stream and --record-replay are mutually exclusive")
+            }
+            Some(Recorder::create(
+                path,
+                options_fingerprint(opts),
+                replay::graph_digest(graphs),
+            )?)
+        }
+        None => None,
+    };
+    if let Some(ref output_dir) = opts.output_dir {
+        if opts.stream {
+            bail!("--stream and --output-dir are mutually exclusive")
+        }
+        fs::create_dir_all(output_dir).with_context(|_| {
+            format!("Could not create output directory at '{}'", output_dir.display())
+        })?;
+    }
+
+    let stdout = stdout();
     let mut out = stdout.lock();
     let mut obuf = String::new();
     let progress = ProgressBar::new_spinner();
@@ -25,27 +762,141 @@ fn deplete_requests_from_stdin(graph: ReverseGraph) -> Result<(), Error> {
     eprintln!("Waiting for input...");
     let mut total_commits = 0;
     let mut num_blobs = 0;
-    let mut stack = Stack::default();
-    for hexsha in read.lines().filter_map(Result::ok) {
+    let mut stack = Stack::with_subtree_memo(opts.subtree_cache_mb);
+    let mut slowest = opts.time_lookups.map(SlowestLookups::new);
+    let mut num_empty = 0;
+    let mut num_staged = 0;
+    let mut num_deduped = 0;
+    for (rid, request) in requests(read, opts).enumerate() {
+        let line = request.hexsha;
+        if opts.lenient && lenient_trim(&line).is_empty() {
+            continue;
+        }
         num_blobs += 1;
-        let oid = Oid::from_str(&hexsha)?;
+        let oid = resolve_oid(graphs, &line, opts.lenient)?;
+        check_kind(graphs, &oid, opts.only_kind, rid + 1)?;
+        if opts.test_check_lookups {
+            for graph in graphs {
+                graph.check_lookup_consistency(&oid, &mut stack)?;
+            }
+        }
 
-        graph.lookup(&oid, &mut stack, &mut commits);
-        total_commits += commits.len();
+        let start = Instant::now();
+        let (num_commits, elapsed) = if opts.stream {
+            if let Some(ref path) = request.path {
+                write_path(&mut out, path, opts.nul_terminated)?;
+            }
+            let num_commits = stream_commits(graphs, &oid, &mut stack, &mut out, opts)?;
+            let elapsed = start.elapsed();
+            write!(out, "{}", record_terminator(opts.nul_terminated))?;
+            write!(out, "{}", blob_records(graphs, &oid, opts.nul_terminated))?;
+            out.flush()?;
+            (num_commits, elapsed)
+        } else {
+            let previous = deduped.get(&oid).map(|&(ref output, num_commits)| {
+                obuf.clear();
+                obuf.push_str(output);
+                num_commits
+            });
+            let (num_commits, elapsed) = match previous {
+                Some(num_commits) => {
+                    num_deduped += 1;
+                    (num_commits, start.elapsed())
+                }
+                None => {
+                    commits.clear();
+                    lookup_layered(graphs, opts.union_caches, &oid, &mut stack, |commit_oid| {
+                        commits.push(commit_oid)
+                    })?;
+                    if let Some(ref mut times) = commit_times {
+                        commits.sort_by_key(|&commit| (times.get(commit), commit));
+                    }
+                    let elapsed = start.elapsed();
 
-        obuf.clear();
-        let len = commits.len();
-        for (cid, commit_oid) in commits.iter().enumerate() {
-            use std::fmt::Write;
-            write!(obuf, "{}", commit_oid)?;
-            if cid + 1 < len {
-                obuf.push(' ');
+                    obuf.clear();
+                    let staged = is_staged(graphs, &oid);
+                    let num_fields = commits.len() + if staged { 1 } else { 0 };
+                    for (cid, commit_oid) in commits.iter().enumerate() {
+                        use std::fmt::Write;
+                        write!(
+                            obuf,
+                            "{}{}",
+                            repository_prefix(graphs, commit_oid),
+                            commit_oid
+                        )?;
+                        if let Some(ref mut times) = commit_times {
+                            write!(obuf, ":{}", times.get(*commit_oid))?;
+                        }
+                        obuf.push_str(&commit_suffix(graphs, commit_oid));
+                        if opts.nul_terminated {
+                            obuf.push('\0');
+                        } else if cid + 1 < num_fields {
+                            obuf.push(' ');
+                        }
+                    }
+                    if staged {
+                        obuf.push_str(INDEX_LABEL);
+                        if opts.nul_terminated {
+                            obuf.push('\0');
+                        }
+                    }
+                    obuf.push(record_terminator(opts.nul_terminated));
+                    if opts.dedupe_input {
+                        deduped.insert(oid, (obuf.clone(), commits.len()));
+                    }
+                    (commits.len(), elapsed)
+                }
+            };
+
+            if let Some(ref mut recorder) = recorder {
+                recorder.record(&line, &oid, &obuf)?;
+            }
+            if let Some(ref mut notes) = notes {
+                let records = notes.records(&obuf, record_terminator(opts.nul_terminated));
+                obuf.push_str(&records);
+            }
+            // Binary records only hold the commits, not the records following them.
+            let commits_len = obuf.len();
+            obuf.push_str(&blob_records(graphs, &oid, opts.nul_terminated));
+            match opts.output_dir {
+                Some(ref output_dir) => {
+                    let path = output_dir.join(format!("{}.txt", oid));
+                    fs::write(&path, &obuf)
+                        .with_context(|_| format!("Could not write '{}'", path.display()))?;
+                }
+                None if binary_out.is_some() => {
+                    let binary_out = binary_out.as_mut().expect("checked above");
+                    let path = request.path.as_ref().map(String::as_str);
+                    binary::write_record(binary_out, &oid, path, &obuf[..commits_len])?;
+                }
+                None => {
+                    if let Some(ref path) = request.path {
+                        write_path(&mut out, path, opts.nul_terminated)?;
+                    }
+                    write!(out, "{}", obuf)?;
+                    out.flush()?;
+                }
             }
+            (num_commits, elapsed)
+        };
+        total_commits += num_commits;
+        let staged = is_staged(graphs, &oid);
+        if staged {
+            num_staged += 1;
+        }
+        if num_commits == 0 && opts.on_empty != OnEmpty::Blank {
+            if graphs.iter().any(|g| g.is_oversized(&oid)) {
+                eprintln!("no commits for {} as it is not indexed (size filter)", oid);
+            } else if staged {
+                eprintln!("no commits for {} as it is only staged in the index", oid);
+            } else {
+                eprintln!("no commits for {}", oid);
+            }
+            num_empty += 1;
+        }
+        if let Some(ref mut slowest) = slowest {
+            slowest.record(elapsed, oid, num_commits);
         }
-        obuf.push('\n');
-
-        write!(out, "{}", obuf)?;
-        out.flush()?;
 
         if num_blobs % PROGRESS_RATE == 0 {
             progress.set_message(&format!(
@@ -56,36 +907,592 @@ fn deplete_requests_from_stdin(graph: ReverseGraph) -> Result<(), Error> {
         }
     }
     eprintln!(
-        "DONE: Looked up {} blobs with a total of {} commits",
-        num_blobs, total_commits
+        "DONE: Looked up {} blobs with a total of {} commits{}{}",
+        num_blobs,
+        total_commits,
+        if num_staged > 0 {
+            format!(", {} blobs staged in the index", num_staged)
+        } else {
+            String::new()
+        },
+        if num_deduped > 0 {
+            format!(", {} repeated blobs answered from earlier lookups", num_deduped)
+        } else {
+            String::new()
+        }
     );
     progress.finish_and_clear();
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    if let Some(mut binary_out) = binary_out {
+        binary_out.flush()?;
+    }
+    if let Some(ref memo) = stack.subtree_memo {
+        eprintln!(
+            "Subtree cache: {} hits, {} misses, {} entries using {} bytes",
+            memo.hits,
+            memo.misses,
+            memo.len(),
+            memo.used_bytes()
+        );
+    }
+    if let Some(slowest) = slowest {
+        slowest.report();
+    }
+    if num_empty > 0 && opts.on_empty == OnEmpty::Fail {
+        return Err(Exit {
+            code: NO_COMMITS_EXIT_CODE,
+            message: format!("{} of {} blobs are not contained in any commit", num_empty, num_blobs),
+        }.into());
+    }
+    Ok(())
+}
+
+impl<'a> From<&'a Options> for BuildOptions {
+    fn from(opts: &'a Options) -> Self {
+        BuildOptions {
+            repository: opts.repository.clone(),
+            head_only: opts.head_only,
+            fsck_lite: opts.fsck_lite,
+            include_stash: opts.include_stash,
+            include_index: opts.include_index,
+            fetch_missing: opts.fetch_missing,
+            objects_from_batch: opts.objects_from_batch.clone(),
+            tips: opts.tips.clone(),
+            tips_from: opts.tips_from.clone(),
+            remote_patterns: opts.remote_patterns.clone(),
+            exclude_remotes: opts.exclude_remotes.clone(),
+            local_branches: opts.local_branches,
+            include_tags: opts.include_tags,
+            refs: opts.refs.clone(),
+            all_refs: opts.all,
+            rev_range: opts.rev_range.clone(),
+            exclude_revs: opts.exclude_revs.clone(),
+            exclude_refs: opts.exclude_refs.clone(),
+            include_worktrees: opts.include_worktrees,
+            no_commit_graph: opts.no_commit_graph,
+            no_gitlinks: opts.no_gitlinks,
+            paths: opts.paths.clone(),
+            since: opts.since.clone(),
+            until: opts.until.clone(),
+            first_parent: opts.first_parent,
+            include_dangling: opts.include_dangling,
+            no_replace_objects: opts.no_replace_objects,
+            allow_shallow: opts.allow_shallow,
+            other_repositories: opts.other_repositories.clone(),
+            recurse_submodules: opts.recurse_submodules,
+            report_memory: opts.report_memory,
+            with_forward_edges: opts.with_forward_edges,
+            max_tree_entries: opts.max_tree_entries,
+            max_blob_size: opts.max_blob_size,
+            max_commits: opts.max_commits,
+            threads: opts.threads,
+            hang_timeout: opts.hang_timeout,
+            hang_abort: opts.hang_abort,
+            test_hang_at: opts.test_hang_at.clone(),
+        }
+    }
+}
+
+fn dump_oids(graph: &ReverseGraph, kind: Option<lut::Kind>) -> Result<(), Error> {
+    let stdout = stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    for idx in 0..graph.len() {
+        if kind.map_or(true, |k| k == graph.kind_of(idx)) {
+            writeln!(
+                out,
+                "{} {} {} {}",
+                idx,
+                graph.oid_of(idx),
+                graph.kind_of(idx),
+                graph.edge_count(idx)
+            )?;
+        }
+    }
+    if let Some((idx, count)) = graph.max_edges() {
+        eprintln!(
+            "Most referenced vertex: {} {} with {} parent-edges",
+            graph.oid_of(idx),
+            graph.kind_of(idx),
+            count
+        );
+    }
+    Ok(())
+}
+
+fn update_hint(opts: &Options) -> String {
+    opts.update_hint
+        .clone()
+        .or_else(|| {
+            lut::open_repository(&opts.repository)
+                .ok()
+                .and_then(|r| r.config().ok())
+                .and_then(|c| c.get_string(UPDATE_HINT_CONFIG_KEY).ok())
+        })
+        .unwrap_or_else(|| DEFAULT_UPDATE_HINT.into())
+}
+
+/// The object directories used to build a cache, as recorded after its graph.
+fn object_directories(opts: &Options) -> Vec<String> {
+    lut::open_repository(&opts.repository)
+        .map(|repo| {
+            lut::object_directories(&repo)
+                .iter()
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn load(cache_path: &Path, opts: &Options) -> Result<(ReverseGraph, GraphCoverage), Error> {
+    let file = File::open(cache_path)?;
+    let age = file.metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+    let mut input = lz4::Decoder::new(BufReader::new(file))?;
+    CacheHeader::read(&mut input)
+        .and_then(|header| header.check(cache_path, Version::current(), &update_hint(opts)))
+        .with_context(|_| format!("Could not load cache at '{}'", cache_path.display()))?;
+    let mut graph = StorableReverseGraph::load(&mut input)?.into_memory()?;
+    let trailer = CacheTrailer::read(&mut input)
+        .with_context(|_| format!("Could not load cache at '{}'", cache_path.display()))?;
+    let current = object_directories(opts);
+    if trailer.object_directories != current {
+        eprintln!(
+            "WARNING: The cache at '{}' was built from the object directories {:?}, but now they are {:?}",
+            cache_path.display(),
+            trailer.object_directories,
+            current
+        );
+    }
+    let traversal = trailer.traversal.clone();
+    let current = lut::traversal_fingerprint(&BuildOptions::from(opts));
+    if traversal != current {
+        eprintln!(
+            "WARNING: The cache at '{}' was built with traversal '{}', but now it is '{}'",
+            cache_path.display(),
+            traversal,
+            current
+        );
+    }
+    let current = pathspec::normalize(&opts.paths);
+    if trailer.pathspecs != current {
+        bail!(
+            "The cache at '{}' indexes blobs at the paths {:?}, but now they are {:?}. Use the same --path options or another cache.",
+            cache_path.display(),
+            trailer.pathspecs,
+            current
+        )
+    }
+    trailer
+        .restore(&mut graph)
+        .with_context(|_| format!("Could not load cache at '{}'", cache_path.display()))?;
+    let coverage = GraphCoverage::new(
+        Source::Cache {
+            path: cache_path.to_owned(),
+            age,
+        },
+        Some(traversal),
+        &graph,
+    );
+    Ok((graph, coverage))
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// A lockfile next to a cache, held while the cache is built and saved, and removed when dropped.
+/// It contains the id of the owning process, which allows to break locks left behind by processes
+/// which were killed before they could remove it.
+struct CacheLock {
+    path: PathBuf,
+}
+
+impl CacheLock {
+    /// Wait until no other process holds the lock of the cache at `cache_path`, and take it.
+    /// Locks of processes which are not running anymore are removed, and waiting for a lock
+    /// fails after `timeout_secs` seconds, unless it is 0.
+    fn acquire(cache_path: &Path, timeout_secs: u64) -> Result<CacheLock, Error> {
+        let path = with_suffix(cache_path, CACHE_LOCK_SUFFIX);
+        let start = Instant::now();
+        let mut announced = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", process::id())?;
+                    return Ok(CacheLock { path });
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    if let Some(pid) = lock_owner(&path) {
+                        if !is_running(pid) {
+                            eprintln!(
+                                "Removing stale cache lock at '{}', as its process {} is not running anymore.",
+                                path.display(),
+                                pid
+                            );
+                            // Another waiter may have replaced the stale lock with its own already.
+                            if lock_owner(&path) == Some(pid) {
+                                fs::remove_file(&path).ok();
+                            }
+                            continue;
+                        }
+                    }
+                    if !announced {
+                        eprintln!(
+                            "Waiting for another process to save the cache, as '{}' exists. \
+                             If no such process is running, please remove it.",
+                            path.display()
+                        );
+                        announced = true;
+                    }
+                    if timeout_secs != 0 && start.elapsed() >= Duration::from_secs(timeout_secs) {
+                        bail!(
+                            "Gave up waiting for the cache lock at '{}' after {}s. \
+                             If no other process is saving the cache, please remove it.",
+                            path.display(),
+                            timeout_secs
+                        );
+                    }
+                    thread::sleep(Duration::from_millis(CACHE_LOCK_POLL_MS));
+                }
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "Could not create cache lock at '{}'",
+                        path.display()
+                    )).into())
+                }
+            }
+        }
+    }
+}
+
+/// The id of the process owning the lock at `path`, or None if it isn't known (yet).
+fn lock_owner(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok().and_then(|pid| pid.trim().parse().ok())
+}
+
+/// True if a process with the given `pid` exists, or if that can't be determined on this platform.
+/// On unix, `kill -0` probes the process without signalling it; a process owned by someone else
+/// makes it fail with a permission error, but still counts as running.
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    match process::Command::new("kill")
+        .args(&["-0", &pid.to_string()])
+        .env("LC_ALL", "C")
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .output()
+    {
+        Ok(output) => {
+            output.status.success() || !String::from_utf8_lossy(&output.stderr).contains("No such process")
+        }
+        Err(_) => true,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+fn save(
+    cache_path: &Path,
+    mut graph: ReverseGraph,
+    build_opts: &BuildOptions,
+    opts: &Options,
+) -> Result<ReverseGraph, Error> {
+    let mut header = CacheHeader::new(
+        opts.test_producer_version.unwrap_or_else(Version::current),
+        opts.min_reader_version,
+    );
+    if let Some(format) = opts.test_cache_format {
+        header = header.with_format(format);
+    }
+    let mut encoder = lz4::EncoderBuilder::new().build(BufWriter::new(OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(cache_path)?))?;
+    header.write(&mut encoder)?;
+    let trailer = CacheTrailer::take_from(
+        &mut graph,
+        object_directories(opts),
+        lut::traversal_fingerprint(build_opts),
+    );
+    let storage = graph.into_storage().save(&mut encoder)?;
+    trailer.write(&mut encoder)?;
+    let (mut out, result) = encoder.finish();
+    result?;
+    out.flush()?;
+    let mut graph = storage.into_memory()?;
+    trailer.restore(&mut graph)?;
+    Ok(graph)
+}
+
+/// Load the cache at `cache_path`, or build and save it. Saves are serialized by a lockfile and
+/// go to a temporary file which is renamed into place, so readers never see a partial cache.
+fn load_or_build(
+    cache_path: &Path,
+    build_opts: &BuildOptions,
+    opts: &Options,
+) -> Result<(ReverseGraph, GraphCoverage), Error> {
+    if metadata(cache_path).is_ok() {
+        return load(cache_path, opts);
+    }
+    let _lock = CacheLock::acquire(cache_path, opts.cache_lock_timeout)?;
+    // Another process may have saved the cache while we were waiting.
+    if metadata(cache_path).is_ok() {
+        return load(cache_path, opts);
+    }
+    let tmp_path = with_suffix(cache_path, CACHE_TMP_SUFFIX);
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&tmp_path)
+        .with_context(|_| format!("Could not write cache at '{}'", tmp_path.display()))?;
+    let graph = lut::build(build_opts)
+        .and_then(|graph| {
+            let coverage = GraphCoverage::new(
+                Source::BuildSavedAs(cache_path.to_owned()),
+                Some(lut::traversal_fingerprint(build_opts)),
+                &graph,
+            );
+            Ok((save(&tmp_path, graph, build_opts, opts)?, coverage))
+        })
+        .and_then(|graph| {
+            fs::rename(&tmp_path, cache_path).with_context(|_| {
+                format!("Could not move cache into place at '{}'", cache_path.display())
+            })?;
+            Ok(graph)
+        });
+    if graph.is_err() {
+        fs::remove_file(&tmp_path).ok();
+    }
+    graph
+}
+
+/// The caches in `dir`, newest first.
+fn caches_in(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut caches = Vec::new();
+    for entry in fs::read_dir(dir)
+        .with_context(|_| format!("Could not read cache directory '{}'", dir.display()))?
+    {
+        let path = entry?.path();
+        let is_cache = path.file_name().and_then(|n| n.to_str()).map_or(false, |name| {
+            name.starts_with(CACHE_FILE_PREFIX) && name.ends_with(CACHE_FILE_SUFFIX)
+        });
+        if is_cache && path.is_file() {
+            caches.push(path);
+        }
+    }
+    caches.sort_by(|a, b| b.cmp(a));
+    Ok(caches)
+}
+
+/// Delete all but the `keep` newest caches in `dir`.
+fn prune_caches(dir: &Path, keep: usize) -> Result<(), Error> {
+    for path in caches_in(dir)?.into_iter().skip(keep) {
+        eprintln!("Removing old cache at '{}'", path.display());
+        fs::remove_file(&path)
+            .with_context(|_| format!("Could not remove cache at '{}'", path.display()))?;
+    }
     Ok(())
 }
 
+fn load_or_build_in_dir(
+    dir: &Path,
+    build_opts: &BuildOptions,
+    opts: &Options,
+) -> Result<(ReverseGraph, GraphCoverage), Error> {
+    if !opts.refresh_cache {
+        for cache_path in caches_in(dir)? {
+            match load(&cache_path, opts) {
+                Ok(graph) => {
+                    eprintln!("Using cache at '{}'", cache_path.display());
+                    prune_caches(dir, opts.keep_caches)?;
+                    return Ok(graph);
+                }
+                Err(err) => eprintln!("Skipping cache at '{}': {}", cache_path.display(), err),
+            }
+        }
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let cache_path = dir.join(format!(
+        "{}{}{:03}{}",
+        CACHE_FILE_PREFIX,
+        now.as_secs(),
+        now.subsec_nanos() / 1_000_000,
+        CACHE_FILE_SUFFIX
+    ));
+    let graph = load_or_build(&cache_path, build_opts, opts)?;
+    prune_caches(dir, opts.keep_caches)?;
+    Ok(graph)
+}
+
 pub fn run(opts: Options) -> Result<(), Error> {
+    if let Some(ref path) = opts.decode {
+        let stdout = stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let num_records = binary::decode(path, opts.nul_terminated, &mut out)?;
+        out.flush()?;
+        eprintln!("Decoded {} records", num_records);
+        return Ok(());
+    }
+    lut::add_alternate_object_directories(&opts.alternate_objects)?;
+    if let Some(ref work_tree) = opts.work_tree {
+        lut::set_work_tree(work_tree)?;
+    }
     let tree = opts.tree.clone();
-    let graph = match &opts.cache_path {
-        Some(cache_path) => {
-            if metadata(cache_path).is_ok() {
-                StorableReverseGraph::load(lz4::Decoder::new(BufReader::new(File::open(
-                    &cache_path,
-                )?))?)?.into_memory()
+    if tree.is_some() && !opts.tree_from_repo.is_empty() {
+        bail!("The tree-to-integrate and --tree-from-repo are mutually exclusive")
+    }
+    let manifest = match opts.tree_manifest {
+        Some(ref path) => {
+            if tree.is_some() || !opts.tree_from_repo.is_empty() {
+                bail!("--tree-manifest can't be used with the tree-to-integrate or --tree-from-repo")
+            }
+            Some(if path.as_os_str() == "-" {
+                let stdin = stdin();
+                let input = stdin.lock();
+                find::read_manifest(input, opts.nul_terminated)?
             } else {
-                let mut encoder =
-                    lz4::EncoderBuilder::new().build(BufWriter::new(OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&cache_path)?))?;
-                let storage = lut::build(&opts)?.into_storage().save(&mut encoder)?;
-                encoder.finish();
-                storage.into_memory()
+                let file = File::open(path)
+                    .with_context(|_| format!("Could not open manifest at '{}'", path.display()))?;
+                find::read_manifest(BufReader::new(file), opts.nul_terminated)?
+            })
+        }
+        None => None,
+    };
+    let input = if opts.validate_input
+        && opts.dump_oids.is_none()
+        && opts.check_replay.is_none()
+        && opts.self_check.is_none()
+        && tree.is_none()
+        && opts.tree_from_repo.is_empty()
+        && manifest.is_none()
+    {
+        let input = buffer_input(decompressed_stdin(opts.input_compression)?)?;
+        validate_input(&input, &opts)?;
+        Some(input)
+    } else {
+        None
+    };
+    let build_opts = BuildOptions::from(&opts);
+    let graphs = match opts.cache_path.as_slice() {
+        [] => {
+            let graph = lut::build(&build_opts)?;
+            let coverage = GraphCoverage::new(
+                Source::Build,
+                Some(lut::traversal_fingerprint(&build_opts)),
+                &graph,
+            );
+            vec![(graph, coverage)]
+        }
+        [cache_path] if cache_path.is_dir() => {
+            vec![load_or_build_in_dir(cache_path, &build_opts, &opts)?]
+        }
+        [cache_path] => vec![load_or_build(cache_path, &build_opts, &opts)?],
+        cache_paths => {
+            if opts.dump_oids.is_some() || tree.is_some() || !opts.tree_from_repo.is_empty()
+                || manifest.is_some()
+            {
+                bail!("Multiple caches can only be queried with blobs from stdin")
             }
+            let mut graphs = Vec::with_capacity(cache_paths.len());
+            for cache_path in cache_paths {
+                if metadata(cache_path).is_err() {
+                    bail!(
+                        "Cache at '{}' does not exist - when using multiple caches, all of them must exist",
+                        cache_path.display()
+                    )
+                }
+                graphs.push(load(cache_path, &opts)?);
+            }
+            graphs
         }
-        None => lut::build(&opts)?,
     };
-    match tree {
-        None => deplete_requests_from_stdin(graph),
-        Some(tree) => find::commit(&tree, graph, &opts),
+    let (mut graphs, mut coverage): (Vec<_>, Vec<_>) = graphs.into_iter().unzip();
+    if let Some(ref blob) = opts.test_inject_cycle {
+        let blob = Oid::from_str(blob)?;
+        for graph in &mut graphs {
+            graph.inject_cycle(&blob)?;
+        }
+    }
+    let is_shallow = lut::open_repository(&opts.repository)
+        .map(|repo| repo.is_shallow())
+        .unwrap_or(false);
+    if is_shallow {
+        for graph in &mut coverage {
+            graph.caveat(
+                "the repository is shallow, so commits beyond its boundary are missing".into(),
+            );
+        }
+    }
+    let is_partial_clone = lut::open_repository(&opts.repository)
+        .map(|repo| lut::is_partial_clone(&repo))
+        .unwrap_or(false);
+    if is_partial_clone && !opts.fetch_missing {
+        for graph in &mut coverage {
+            graph.caveat(
+                "the repository is a partial clone, so trees not available locally are missing"
+                    .into(),
+            );
+        }
+    }
+    let result = query(graphs, tree, manifest, input, &opts);
+    eprintln!("{}", CoverageReport(coverage));
+    result
+}
+
+/// Answer the queries of the run using `graphs`.
+fn query(
+    mut graphs: Vec<ReverseGraph>,
+    tree: Option<PathBuf>,
+    manifest: Option<Vec<find::Entry>>,
+    input: Option<Vec<u8>>,
+    opts: &Options,
+) -> Result<(), Error> {
+    if let Some(kind) = opts.dump_oids {
+        return dump_oids(&graphs[0], kind);
+    }
+    if let Some(ref blob) = opts.self_check {
+        return self_check(&graphs, blob, opts);
+    }
+    if let Some(blobs) = manifest {
+        return find::commit(blobs, graphs.remove(0), opts);
+    }
+    match (tree, opts.tree_from_repo.as_slice()) {
+        (Some(ref tree), _) if tree.as_os_str() == "-" => {
+            let stdin = stdin();
+            let blobs = find::read_listing(stdin.lock())?;
+            find::commit(blobs, graphs.remove(0), opts)
+        }
+        (Some(tree), _) => find::commit(find::hash_tree(&tree, opts)?, graphs.remove(0), opts),
+        (None, &[ref repository, ref treeish]) => find::commit(
+            find::tree_entries(repository.as_ref(), treeish)?,
+            graphs.remove(0),
+            opts,
+        ),
+        (None, _) if opts.check_replay.is_some() => check_replay(
+            &graphs,
+            opts.check_replay.as_ref().expect("checked above"),
+            opts,
+        ),
+        (None, _) => match input {
+            Some(input) => deplete_requests(&graphs, input.as_slice(), opts),
+            None => deplete_requests(&graphs, decompressed_stdin(opts.input_compression)?, opts),
+        },
     }
 }
@@ -0,0 +1,355 @@
+use failure::{Error, ResultExt};
+use git2::Oid;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"CGPH";
+const VERSION: u8 = 1;
+const HASH_VERSION_SHA1: u8 = 1;
+const HEADER_LEN: usize = 8;
+const CHUNK_ENTRY_LEN: usize = 12;
+const FANOUT_LEN: usize = 256 * 4;
+const SHA1_LEN: usize = 20;
+const COMMIT_DATA_LEN: usize = SHA1_LEN + 16;
+const PARENT_NONE: u32 = 0x7000_0000;
+const PARENT_EXTRA_EDGES: u32 = 0x8000_0000;
+const COMMIT_TIME_MASK: u64 = 0x3_ffff_ffff;
+
+/// A single commit-graph file, either standalone or part of a split chain.
+struct GraphFile {
+    data: Vec<u8>,
+    fanout: usize,
+    oid_lookup: usize,
+    commit_data: usize,
+    extra_edges: Option<Range<usize>>,
+    num_commits: usize,
+}
+
+fn be_u32(data: &[u8], ofs: usize) -> u32 {
+    data[ofs..ofs + 4]
+        .iter()
+        .fold(0, |acc, &b| (acc << 8) | u32::from(b))
+}
+
+fn be_u64(data: &[u8], ofs: usize) -> u64 {
+    data[ofs..ofs + 8]
+        .iter()
+        .fold(0, |acc, &b| (acc << 8) | u64::from(b))
+}
+
+impl GraphFile {
+    fn parse(path: &Path, data: Vec<u8>) -> Result<GraphFile, Error> {
+        let malformed = |what: &str| format_err!("Commit-graph '{}' {}", path.display(), what);
+        if data.len() < HEADER_LEN || &data[..4] != SIGNATURE {
+            return Err(malformed("has no valid header"));
+        }
+        if data[4] != VERSION || data[5] != HASH_VERSION_SHA1 {
+            return Err(malformed("has an unsupported version or hash"));
+        }
+        let num_chunks = data[6] as usize;
+        if data.len() < HEADER_LEN + (num_chunks + 1) * CHUNK_ENTRY_LEN {
+            return Err(malformed("is truncated within its chunk table"));
+        }
+
+        let (mut fanout, mut oid_lookup, mut commit_data, mut extra_edges) = (None, None, None, None);
+        for cid in 0..num_chunks {
+            let entry = HEADER_LEN + cid * CHUNK_ENTRY_LEN;
+            // Each chunk ends where the next one starts, the last one at the terminating entry.
+            let (start, end) = (be_u64(&data, entry + 4), be_u64(&data, entry + CHUNK_ENTRY_LEN + 4));
+            if start > end || end > data.len() as u64 {
+                return Err(malformed("has a chunk beyond its end"));
+            }
+            let range = Some(start as usize..end as usize);
+            match &data[entry..entry + 4] {
+                b"OIDF" => fanout = range,
+                b"OIDL" => oid_lookup = range,
+                b"CDAT" => commit_data = range,
+                b"EDGE" => extra_edges = range,
+                _ => continue,
+            }
+        }
+        let (fanout, oid_lookup, commit_data) = match (fanout, oid_lookup, commit_data) {
+            (Some(f), Some(l), Some(c)) => (f, l, c),
+            _ => return Err(malformed("lacks a required chunk")),
+        };
+        if fanout.len() != FANOUT_LEN {
+            return Err(malformed("has a fanout table of unexpected size"));
+        }
+        let mut num_commits = 0;
+        for first in 0..256 {
+            let count = be_u32(&data, fanout.start + first * 4) as usize;
+            if count < num_commits {
+                return Err(malformed("has a fanout table which isn't sorted"));
+            }
+            num_commits = count;
+        }
+        if oid_lookup.len() != num_commits * SHA1_LEN
+            || commit_data.len() != num_commits * COMMIT_DATA_LEN
+        {
+            return Err(malformed("has chunks not matching the amount of commits in its fanout table"));
+        }
+        Ok(GraphFile {
+            data,
+            fanout: fanout.start,
+            oid_lookup: oid_lookup.start,
+            commit_data: commit_data.start,
+            extra_edges,
+            num_commits,
+        })
+    }
+
+    fn oid_at(&self, pos: usize) -> &[u8] {
+        let ofs = self.oid_lookup + pos * SHA1_LEN;
+        &self.data[ofs..ofs + SHA1_LEN]
+    }
+
+    fn position(&self, oid: &Oid) -> Option<usize> {
+        let first = oid.as_bytes()[0] as usize;
+        let mut lo = if first == 0 {
+            0
+        } else {
+            be_u32(&self.data, self.fanout + (first - 1) * 4) as usize
+        };
+        let mut hi = be_u32(&self.data, self.fanout + first * 4) as usize;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.oid_at(mid).cmp(oid.as_bytes()) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    fn tree_at(&self, pos: usize) -> Oid {
+        let ofs = self.commit_data + pos * COMMIT_DATA_LEN;
+        Oid::from_bytes(&self.data[ofs..ofs + SHA1_LEN]).expect("20 bytes to be a valid oid")
+    }
+
+    /// Append the graph positions of the parents of the commit at `pos` to `out`. Octopus
+    /// merges keep all but their first parent in the extra edge list.
+    fn parents_at(&self, pos: usize, out: &mut Vec<usize>) -> Result<(), Error> {
+        let ofs = self.commit_data + pos * COMMIT_DATA_LEN + SHA1_LEN;
+        let (first, second) = (be_u32(&self.data, ofs), be_u32(&self.data, ofs + 4));
+        if first == PARENT_NONE {
+            return Ok(());
+        }
+        out.push(first as usize);
+        if second == PARENT_NONE {
+            return Ok(());
+        }
+        if second & PARENT_EXTRA_EDGES == 0 {
+            out.push(second as usize);
+            return Ok(());
+        }
+        let extra_edges = self.extra_edges
+            .as_ref()
+            .ok_or_else(|| format_err!("Commit-graph refers to extra edges but has none"))?;
+        let mut ofs = extra_edges.start + (second & !PARENT_EXTRA_EDGES) as usize * 4;
+        loop {
+            if ofs + 4 > extra_edges.end {
+                bail!("Commit-graph is truncated within its extra edges")
+            }
+            let edge = be_u32(&self.data, ofs);
+            out.push((edge & !PARENT_EXTRA_EDGES) as usize);
+            if edge & PARENT_EXTRA_EDGES != 0 {
+                return Ok(());
+            }
+            ofs += 4;
+        }
+    }
+
+    fn commit_time_at(&self, pos: usize) -> u64 {
+        let ofs = self.commit_data + pos * COMMIT_DATA_LEN + SHA1_LEN + 8;
+        be_u64(&self.data, ofs) & COMMIT_TIME_MASK
+    }
+}
+
+/// The commit-graph of a repository, which knows the tree of each commit it contains
+/// without the need to read and parse commit objects.
+pub struct CommitGraph {
+    files: Vec<GraphFile>,
+}
+
+fn graph_paths(objects_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let info = objects_dir.join("info");
+    let chain = info.join("commit-graphs").join("commit-graph-chain");
+    if chain.is_file() {
+        let mut paths = Vec::new();
+        for line in BufReader::new(fs::File::open(&chain)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                paths.push(
+                    info.join("commit-graphs")
+                        .join(format!("graph-{}.graph", line.trim())),
+                );
+            }
+        }
+        Ok(paths)
+    } else {
+        let single = info.join("commit-graph");
+        Ok(if single.is_file() { vec![single] } else { Vec::new() })
+    }
+}
+
+impl CommitGraph {
+    /// Read the commit-graph of the object database at `objects_dir`, if there is one. A
+    /// malformed one is ignored with a warning, so that commits are enumerated without it.
+    pub fn open(objects_dir: &Path) -> Result<Option<CommitGraph>, Error> {
+        let paths = graph_paths(objects_dir)?;
+        if paths.is_empty() {
+            return Ok(None);
+        }
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let data = fs::read(&path)
+                .with_context(|_| format!("Could not read commit-graph '{}'", path.display()))?;
+            match GraphFile::parse(&path, data) {
+                Ok(file) => files.push(file),
+                Err(err) => {
+                    eprintln!("WARNING: {}, ignoring it", err);
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(CommitGraph { files }))
+    }
+
+    /// The amount of commits in all files of the commit-graph.
+    pub fn num_commits(&self) -> usize {
+        self.files.iter().map(|f| f.num_commits).sum()
+    }
+
+    /// The position of `commit` within all files of the chain, as used for parents.
+    fn position(&self, commit: &Oid) -> Option<usize> {
+        let mut base = 0;
+        for file in &self.files {
+            if let Some(pos) = file.position(commit) {
+                return Some(base + pos);
+            }
+            base += file.num_commits;
+        }
+        None
+    }
+
+    /// The file containing the graph position `pos` and the position within it.
+    fn file_at(&self, mut pos: usize) -> Result<(&GraphFile, usize), Error> {
+        for file in &self.files {
+            if pos < file.num_commits {
+                return Ok((file, pos));
+            }
+            pos -= file.num_commits;
+        }
+        bail!("Commit-graph refers to a commit beyond its end")
+    }
+
+    /// The commits reachable from `tips` but not from `hidden` in topological order, children
+    /// before their parents, or None if one of them isn't in the commit-graph as it is stale.
+    /// With `by_time`, the most recently committed of the ready commits comes first.
+    pub fn traverse(
+        &self,
+        tips: &[Oid],
+        hidden: &[Oid],
+        first_parent: bool,
+        by_time: bool,
+    ) -> Result<Option<Vec<Oid>>, Error> {
+        let positions = |oids: &[Oid]| {
+            oids.iter()
+                .map(|oid| self.position(oid))
+                .collect::<Option<Vec<_>>>()
+        };
+        let (tips, hidden) = match (positions(tips), positions(hidden)) {
+            (Some(tips), Some(hidden)) => (tips, hidden),
+            _ => return Ok(None),
+        };
+        let mut parents = Vec::new();
+        let parents_of = |pos: usize, parents: &mut Vec<usize>| -> Result<(), Error> {
+            parents.clear();
+            let (file, pos) = self.file_at(pos)?;
+            file.parents_at(pos, parents)?;
+            if first_parent {
+                parents.truncate(1);
+            }
+            Ok(())
+        };
+
+        let num_commits = self.num_commits();
+        let mut uninteresting = vec![false; num_commits];
+        let mut stack = hidden;
+        while let Some(pos) = stack.pop() {
+            if pos >= num_commits || uninteresting[pos] {
+                continue;
+            }
+            uninteresting[pos] = true;
+            parents_of(pos, &mut parents)?;
+            stack.extend_from_slice(&parents);
+        }
+
+        // Count the children of each commit to emit it only once all of them were emitted.
+        let mut num_children = vec![0u32; num_commits];
+        let mut seen = vec![false; num_commits];
+        let mut stack = tips.clone();
+        while let Some(pos) = stack.pop() {
+            if pos >= num_commits || uninteresting[pos] || seen[pos] {
+                continue;
+            }
+            seen[pos] = true;
+            parents_of(pos, &mut parents)?;
+            for &parent in &parents {
+                if parent < num_commits && !uninteresting[parent] {
+                    num_children[parent] += 1;
+                    stack.push(parent);
+                }
+            }
+        }
+
+        // Among commits committed at the same time, the one which became ready last comes first.
+        let mut ready = BinaryHeap::new();
+        let mut num_ready = 0;
+        for pos in tips {
+            if seen[pos] && num_children[pos] == 0 {
+                // Mark duplicate tips so they are queued only once.
+                num_children[pos] = 1;
+                let time = if by_time { self.commit_time(pos)? } else { 0 };
+                num_ready += 1;
+                ready.push((time, num_ready, pos));
+            }
+        }
+        let mut commits = Vec::new();
+        while let Some((_, _, pos)) = ready.pop() {
+            let (file, file_pos) = self.file_at(pos)?;
+            commits.push(Oid::from_bytes(file.oid_at(file_pos)).expect("20 bytes to be a valid oid"));
+            parents_of(pos, &mut parents)?;
+            for &parent in &parents {
+                if parent >= num_commits || uninteresting[parent] {
+                    continue;
+                }
+                num_children[parent] -= 1;
+                if num_children[parent] == 0 {
+                    let time = if by_time { self.commit_time(parent)? } else { 0 };
+                    num_ready += 1;
+                    ready.push((time, num_ready, parent));
+                }
+            }
+        }
+        Ok(Some(commits))
+    }
+
+    fn commit_time(&self, pos: usize) -> Result<u64, Error> {
+        let (file, pos) = self.file_at(pos)?;
+        Ok(file.commit_time_at(pos))
+    }
+
+    /// The tree of `commit`, if the commit is contained in the commit-graph.
+    pub fn tree_of(&self, commit: &Oid) -> Option<Oid> {
+        self.files
+            .iter()
+            .filter_map(|f| f.position(commit).map(|pos| f.tree_at(pos)))
+            .next()
+    }
+}
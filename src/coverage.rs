@@ -0,0 +1,92 @@
+//! A statement of what the graphs of a run cover, printed at the end of every run, so that
+//! blobs which aren't found can be explained by how the graphs were obtained.
+
+use lut::ReverseGraph;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where a graph came from.
+pub enum Source {
+    Build,
+    BuildSavedAs(PathBuf),
+    Cache { path: PathBuf, age: Option<Duration> },
+}
+
+/// What a single graph covers.
+pub struct GraphCoverage {
+    source: Source,
+    traversal: Option<String>,
+    num_commits: usize,
+    caveats: Vec<String>,
+}
+
+impl GraphCoverage {
+    /// The coverage of `graph`, obtained from `source` by traversing as described by `traversal`
+    /// if it is known.
+    pub fn new(source: Source, traversal: Option<String>, graph: &ReverseGraph) -> GraphCoverage {
+        GraphCoverage {
+            source,
+            traversal,
+            num_commits: graph.num_commits(),
+            caveats: graph.caveats(),
+        }
+    }
+    /// Note that the graph lacks something it would otherwise cover.
+    pub fn caveat(&mut self, caveat: String) {
+        self.caveats.push(caveat);
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs if secs < 60 => "less than a minute".into(),
+        secs if secs < 3600 => format!("{} minutes", secs / 60),
+        secs if secs < 86_400 => format!("{} hours", secs / 3600),
+        secs => format!("{} days", secs / 86_400),
+    }
+}
+
+impl fmt::Display for GraphCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            Source::Build => writeln!(f, "COVERAGE: fresh build")?,
+            Source::BuildSavedAs(ref path) => {
+                writeln!(f, "COVERAGE: fresh build, saved as cache at '{}'", path.display())?
+            }
+            Source::Cache { ref path, age } => writeln!(
+                f,
+                "COVERAGE: cache at '{}', written {} ago",
+                path.display(),
+                age.map_or_else(|| "an unknown time".into(), format_age)
+            )?,
+        }
+        writeln!(
+            f,
+            "  traversal: {}",
+            self.traversal
+                .as_ref()
+                .map_or("unknown, as the cache predates recording it", |t| t.as_str())
+        )?;
+        write!(f, "  commits:   {}", self.num_commits)?;
+        for caveat in &self.caveats {
+            write!(f, "\n  caveat:    {}", caveat)?;
+        }
+        Ok(())
+    }
+}
+
+/// The coverage of all graphs of a run.
+pub struct CoverageReport(pub Vec<GraphCoverage>);
+
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (gid, graph) in self.0.iter().enumerate() {
+            if gid > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", graph)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Parsing of the dates accepted by --since and --until, a subset of what git understands.
+
+use failure::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// The seconds since the epoch of `spec` relative to `now`, which is one of
+/// '@<seconds since the epoch>', 'now', an ISO 8601 date like '2017-03-21', '2017-03-21T10:20:30Z'
+/// or '2017-03-21 10:20:30 +0100', or a relative date like '2.years.ago' or '3 weeks ago'.
+/// Dates without time zone are in UTC.
+pub fn parse(spec: &str, now: i64) -> Result<i64, Error> {
+    let spec = spec.trim();
+    if spec == "now" {
+        return Ok(now);
+    }
+    if spec.starts_with('@') {
+        return spec[1..]
+            .parse()
+            .map_err(|_| format_err!("'{}' is not a valid amount of seconds since the epoch", spec));
+    }
+    relative(spec, now)
+        .or_else(|| absolute(spec))
+        .ok_or_else(|| {
+            format_err!(
+                "Could not parse date '{}' - use ISO 8601 like '2017-03-21T10:20:30Z', a relative date like '2.years.ago' or '@<seconds since the epoch>'",
+                spec
+            )
+        })
+}
+
+/// The current time in seconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn relative(spec: &str, now: i64) -> Option<i64> {
+    let words: Vec<_> = spec.split(|c| c == '.' || c == ' ')
+        .filter(|w| !w.is_empty())
+        .collect();
+    match words.as_slice() {
+        &[amount, unit, "ago"] => {
+            let amount: i64 = amount.parse().ok()?;
+            let unit = match unit.trim_right_matches('s') {
+                "second" => 1,
+                "minute" => MINUTE,
+                "hour" => HOUR,
+                "day" => DAY,
+                "week" => 7 * DAY,
+                "month" => 30 * DAY,
+                "year" => 365 * DAY,
+                _ => return None,
+            };
+            Some(now - amount * unit)
+        }
+        _ => None,
+    }
+}
+
+fn absolute(spec: &str) -> Option<i64> {
+    let (date, rest) = if spec.len() > 10 && spec.is_char_boundary(10) {
+        spec.split_at(10)
+    } else {
+        (spec, "")
+    };
+    let mut fields = date.splitn(3, '-');
+    let (year, month, day): (i64, i64, i64) = (
+        fields.next()?.parse().ok()?,
+        fields.next()?.parse().ok()?,
+        fields.next()?.parse().ok()?,
+    );
+    if month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+    let rest = rest.trim_left_matches(|c| c == 'T' || c == ' ');
+    let (time, zone) = match rest.find(|c| c == 'Z' || c == '+' || c == '-' || c == ' ') {
+        Some(pos) => (&rest[..pos], rest[pos..].trim()),
+        None => (rest, ""),
+    };
+    let mut seconds = 0;
+    if !time.is_empty() {
+        let mut fields = time.splitn(3, ':');
+        let (hours, minutes): (i64, i64) = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+        let secs: i64 = match fields.next() {
+            Some(secs) => secs.parse().ok()?,
+            None => 0,
+        };
+        seconds = hours * HOUR + minutes * MINUTE + secs;
+    }
+    Some(days_from_civil(year, month, day) * DAY + seconds - offset(zone)?)
+}
+
+/// The offset of `zone` to UTC in seconds, like '+0100' or '-05:00', or 0 for 'Z' or none.
+fn offset(zone: &str) -> Option<i64> {
+    if zone.is_empty() || zone == "Z" {
+        return Some(0);
+    }
+    let sign = match zone.as_bytes()[0] {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = zone[1..].chars().filter(|&c| c != ':').collect();
+    if digits.len() != 4 {
+        return None;
+    }
+    let (hours, minutes): (i64, i64) = (digits[..2].parse().ok()?, digits[2..].parse().ok()?);
+    Some(sign * (hours * HOUR + minutes * MINUTE))
+}
+
+/// The days since 1970-01-01 of the given date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
@@ -1,42 +1,220 @@
 use fixedbitset::FixedBitSet;
 use failure::{Error, ResultExt};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::io::{self, stdout, BufRead, Write};
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, fmt, str::FromStr,
+          sync::atomic::{AtomicBool, Ordering as AtomicOrdering}, time::{Duration, Instant}};
 use walkdir::WalkDir;
-use git2::ObjectType;
+use std::fs::File;
+use std::process::{Command, Stdio};
+use git2::{ObjectType, Repository, Tree};
 use indicatif::ProgressBar;
 use Stack;
-use lut::ReverseGraph;
+use lut::{self, ReverseGraph};
 use crossbeam_channel;
 use crossbeam;
 use num_cpus;
 use git2::Oid;
 use Options;
+use gitignore::Ignores;
+use notes::Notes;
+use hash_cache::HashCache;
+use watchdog::Watchdog;
+use cli::{record_terminator, records, Exit, MALFORMED_RECORDS_SHOWN, NO_COMMITS_EXIT_CODE,
+          PARTIAL_MATCH_EXIT_CODE};
 
 const HASHING_PROGRESS_RATE: usize = 25;
+const INFER_RELATIVE_CANDIDATES: usize = 3;
+const INFER_RELATIVE_PREFIXES: usize = 3;
+const PROGRESSIVE_INTERVAL_MS: u64 = 2000;
+/// The gain of an evidence blob in a directory not yet covered by other evidence, in units of
+/// rivals it is absent from.
+const EVIDENCE_DIVERSITY_BONUS: f64 = 0.5;
 
-fn compact(c: Vec<FixedBitSet>, graph: ReverseGraph) -> Vec<(Oid, FixedBitSet)> {
-    let mut nc: Vec<_> = c.into_iter()
-        .enumerate()
-        .filter_map(|(cid, bits)| {
-            if bits.len() > 0 {
-                Some((graph.oid_of(cid), bits))
-            } else {
-                None
+#[derive(Debug, Clone, Copy)]
+pub enum RankBy {
+    Coverage,
+    Rarity,
+    Combined,
+}
+
+impl FromStr for RankBy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "coverage" => RankBy::Coverage,
+            "rarity" => RankBy::Rarity,
+            "combined" => RankBy::Combined,
+            _ => {
+                return Err(format!(
+                    "'{}' is not one of 'coverage', 'rarity' or 'combined'",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+impl fmt::Display for RankBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            RankBy::Coverage => "coverage",
+            RankBy::Rarity => "rarity",
+            RankBy::Combined => "combined",
+        })
+    }
+}
+
+/// A commit containing at least one blob of the tree-to-integrate.
+pub struct Candidate {
+    pub commit: Oid,
+    pub matched: usize,
+    /// The fraction of blobs of the tree-to-integrate contained in the commit.
+    pub coverage: f64,
+    /// The fraction of rarity-weight of all blobs contained in the commit.
+    pub rarity: f64,
+    /// The indices of the blobs of the tree-to-integrate contained in the commit.
+    pub blobs: FixedBitSet,
+}
+
+impl Candidate {
+    pub fn score(&self, rank_by: RankBy) -> f64 {
+        match rank_by {
+            RankBy::Coverage => self.coverage,
+            RankBy::Rarity => self.rarity,
+            RankBy::Combined => (self.coverage + self.rarity) / 2.0,
+        }
+    }
+}
+
+/// A blob shared by many trees is weak evidence, hence each blob is weighted by the inverse
+/// of its amount of parents. Blobs unknown to the graph have no weight.
+fn rarity_weights(blobs: &[Entry], graph: &ReverseGraph) -> Vec<f64> {
+    blobs
+        .iter()
+        .map(|b| match graph.num_parents(&b.oid) {
+            Some(0) | None => 0.0,
+            Some(n) => 1.0 / n as f64,
+        })
+        .collect()
+}
+
+fn rank(
+    candidates: Vec<(Oid, FixedBitSet)>,
+    weights: &[f64],
+    rank_by: RankBy,
+) -> Vec<Candidate> {
+    let total_weight: f64 = weights.iter().sum();
+    let mut ranked: Vec<_> = candidates
+        .into_iter()
+        .map(|(commit, bits)| {
+            let (mut matched, mut weight) = (0, 0.0);
+            for bid in (0..weights.len()).filter(|&bid| bits.contains(bid)) {
+                matched += 1;
+                weight += weights[bid];
+            }
+            Candidate {
+                commit,
+                matched,
+                coverage: matched as f64 / weights.len() as f64,
+                rarity: if total_weight > 0.0 {
+                    weight / total_weight
+                } else {
+                    0.0
+                },
+                blobs: bits,
             }
         })
         .collect();
-    nc.shrink_to_fit();
-    nc
+    ranked.sort_by(|a, b| {
+        b.score(rank_by)
+            .partial_cmp(&a.score(rank_by))
+            .unwrap_or(Ordering::Equal)
+            .then(b.matched.cmp(&a.matched))
+            .then(a.commit.cmp(&b.commit))
+    });
+    ranked
+}
+
+fn compact(c: HashMap<usize, FixedBitSet>, graph: &ReverseGraph) -> Vec<(Oid, FixedBitSet)> {
+    c.into_iter()
+        .map(|(cid, bits)| (graph.oid_of(cid), bits))
+        .collect()
+}
+
+/// A blob of the tree-to-integrate along with its path relative to the tree's root.
+pub struct Entry {
+    pub path: PathBuf,
+    pub oid: Oid,
+}
+
+/// The worktree of the repository at `repository` and the canonical `tree` if `tree` is
+/// located within this worktree.
+fn containing_workdir(tree: &Path, repository: &Path) -> Option<(PathBuf, PathBuf)> {
+    let workdir = lut::open_repository(repository)
+        .ok()?
+        .workdir()?
+        .canonicalize()
+        .ok()?;
+    let tree = tree.canonicalize().ok()?;
+    if tree.starts_with(&workdir) {
+        Some((workdir, tree))
+    } else {
+        None
+    }
+}
+
+/// Hash the output of `command` run with `sh`, receiving the file at `file` on stdin and
+/// its `path` relative to the tree-to-integrate as first argument, like a git clean filter.
+fn hash_filtered(command: &str, file: &Path, path: &Path) -> Result<Oid, Error> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("sh")
+        .arg(path)
+        .stdin(Stdio::from(File::open(file)?))
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|_| format!("Could not spawn hash filter '{}'", command))?;
+    if !output.status.success() {
+        bail!("Hash filter '{}' failed with {}", command, output.status)
+    }
+    Oid::hash_object(ObjectType::Blob, &output.stdout).map_err(Into::into)
 }
 
-pub fn commit(tree: &Path, graph: ReverseGraph, opts: &Options) -> Result<(), Error> {
+/// Hash all files in `tree`. If it is within the worktree of REPOSITORY, '.git' directories
+/// and files ignored by git are excluded as they can't possibly be part of any commit.
+pub fn hash_tree(tree: &Path, opts: &Options) -> Result<Vec<Entry>, Error> {
     let progress = ProgressBar::new_spinner();
     let mut blobs = Vec::new();
+    let (workdir, tree) = match containing_workdir(tree, &opts.repository) {
+        Some((workdir, tree)) => {
+            eprintln!(
+                "WARNING: The tree-to-integrate is inside the worktree at '{}' - excluding '.git' and ignored files",
+                workdir.display()
+            );
+            (Some(workdir), tree)
+        }
+        None => (None, tree.to_owned()),
+    };
+    let tree = tree.as_path();
+    let mut ignores = Ignores::new(tree, workdir.as_ref().map(|w| w.as_path()), opts)?;
+    let mut hash_cache = match opts.hash_cache {
+        Some(ref path) => Some(HashCache::open(
+            path,
+            opts.hash_filter.as_ref().map(|f| f.as_str()),
+        )?),
+        None => None,
+    };
     for (eid, entry) in WalkDir::new(tree)
         .sort_by(|a, b| a.file_name().cmp(b.file_name()))
         .min_depth(1)
         .follow_links(false)
         .into_iter()
+        .filter_entry(|e| {
+            !(workdir.is_some() && e.file_name() == ".git")
+                && ignores.as_mut().map_or(true, |i| !i.is_ignored(e))
+        })
         .enumerate()
     {
         let entry = entry?;
@@ -44,36 +222,649 @@ pub fn commit(tree: &Path, graph: ReverseGraph, opts: &Options) -> Result<(), Er
         if !entry.file_type().is_file() {
             continue;
         }
-        blobs.push(Oid::hash_file(ObjectType::Blob, entry.path())
-            .with_context(|_| format!("Could not hash file '{}'", entry.path().display()))?);
+        let path = entry
+            .path()
+            .strip_prefix(tree)
+            .unwrap_or_else(|_| entry.path())
+            .to_owned();
+        let metadata = match hash_cache {
+            Some(_) => Some(entry.metadata()?),
+            None => None,
+        };
+        let cached = match (hash_cache.as_mut(), metadata.as_ref()) {
+            (Some(cache), Some(metadata)) => cache.get(&path, metadata),
+            _ => None,
+        };
+        let oid = match cached {
+            Some(oid) => oid,
+            None => {
+                let oid = match opts.hash_filter {
+                    Some(ref command) => hash_filtered(command, entry.path(), &path),
+                    None => Oid::hash_file(ObjectType::Blob, entry.path()).map_err(Into::into),
+                }.with_context(|_| format!("Could not hash file '{}'", entry.path().display()))?;
+                if let (Some(cache), Some(metadata)) = (hash_cache.as_mut(), metadata.as_ref()) {
+                    cache.insert(&path, metadata, oid)?;
+                }
+                oid
+            }
+        };
+        blobs.push(Entry { oid, path });
         if eid % HASHING_PROGRESS_RATE == 0 {
             progress.set_message(&format!("Hashed {} files...", eid));
             progress.tick();
         }
     }
+    progress.finish_and_clear();
+    if let Some(ignores) = ignores {
+        eprintln!("Ignored {} entries", ignores.num_ignored);
+    }
+    if let Some(hash_cache) = hash_cache {
+        hash_cache.finish()?;
+    }
+    Ok(blobs)
+}
+
+fn collect_tree_entries(repo: &Repository, tree: &Tree, prefix: &Path, out: &mut Vec<Entry>) {
+    for item in tree.iter() {
+        let path = prefix.join(String::from_utf8_lossy(item.name_bytes()).as_ref());
+        match item.kind() {
+            Some(ObjectType::Tree) => match repo.find_tree(item.id()) {
+                Ok(subtree) => collect_tree_entries(repo, &subtree, &path, out),
+                Err(err) => eprintln!(
+                    "WARNING: Skipping tree at '{}': {}",
+                    path.display(),
+                    err.message()
+                ),
+            },
+            Some(ObjectType::Blob) => out.push(Entry {
+                oid: item.id(),
+                path,
+            }),
+            _ => continue,
+        }
+    }
+}
+
+/// Obtain all blobs of the tree `treeish` resolves to in the repository at `repository`.
+/// No hashing is needed as object ids are the same in all repositories.
+pub fn tree_entries(repository: &Path, treeish: &str) -> Result<Vec<Entry>, Error> {
+    let repo = lut::open_repository(repository)?;
+    let tree = repo.revparse_single(treeish)?
+        .peel(ObjectType::Tree)?
+        .into_tree()
+        .map_err(|_| format_err!("'{}' does not point to a tree", treeish))?;
+    let mut blobs = Vec::new();
+    collect_tree_entries(&repo, &tree, Path::new(""), &mut blobs);
+    eprintln!(
+        "Read {} blobs from git tree {} ('{}') of repository '{}'",
+        blobs.len(),
+        tree.id(),
+        treeish,
+        repository.display()
+    );
+    Ok(blobs)
+}
+
+fn parse_listing_line(line: &str) -> Result<Option<Entry>, Error> {
+    let mut fields = line.splitn(2, '\t');
+    let (meta, path) = match (fields.next(), fields.next()) {
+        (Some(meta), Some(path)) => (meta, path),
+        _ => bail!("Expected '<mode> [<type>] <oid>\\t<path>', got '{}'", line),
+    };
+    let tokens: Vec<_> = meta.split_whitespace().collect();
+    let (mode, kind, hexsha) = match tokens.as_slice() {
+        &[mode, hexsha] => (mode, None, hexsha),
+        &[mode, kind, hexsha] => (mode, Some(kind), hexsha),
+        _ => bail!("Expected '<mode> [<type>] <oid>\\t<path>', got '{}'", line),
+    };
+    if kind.map_or(false, |k| k != "blob") || mode == "040000" || mode == "160000" {
+        return Ok(None);
+    }
+    Ok(Some(Entry {
+        oid: Oid::from_str(hexsha).with_context(|_| format!("Invalid object id in '{}'", line))?,
+        path: PathBuf::from(path),
+    }))
+}
+
+/// Read a listing in the format of `git ls-tree -r`, with the object type being optional.
+/// Only blobs are kept.
+pub fn read_listing(input: impl BufRead) -> Result<Vec<Entry>, Error> {
+    let mut blobs = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        blobs.extend(parse_listing_line(&line)?);
+    }
+    eprintln!("Read {} blobs from listing", blobs.len());
+    Ok(blobs)
+}
+
+/// Read a manifest of '<oid> <path>' records, like the ones of build systems which know the
+/// blob ids of their outputs already. All malformed records are reported before failing.
+pub fn read_manifest(input: impl BufRead, nul_terminated: bool) -> Result<Vec<Entry>, Error> {
+    let mut blobs = Vec::new();
+    let mut num_malformed = 0;
+    for (rid, record) in records(input, nul_terminated).enumerate() {
+        if record.is_empty() {
+            continue;
+        }
+        let mut fields = record.splitn(2, ' ');
+        let parsed = match (fields.next(), fields.next()) {
+            (Some(hexsha), Some(path))
+                if hexsha.len() == 40 && hexsha.chars().all(|c| c.is_ascii_hexdigit())
+                    && !path.is_empty() =>
+            {
+                Oid::from_str(hexsha).ok().map(|oid| Entry {
+                    oid,
+                    path: PathBuf::from(path),
+                })
+            }
+            _ => None,
+        };
+        match parsed {
+            Some(entry) => blobs.push(entry),
+            None => {
+                if num_malformed < MALFORMED_RECORDS_SHOWN {
+                    eprintln!("malformed manifest record {}: {:?}", rid + 1, record);
+                }
+                num_malformed += 1;
+            }
+        }
+    }
+    if num_malformed > 0 {
+        bail!(
+            "{} manifest records are malformed, expected '<40-hex-oid> <path>'",
+            num_malformed
+        )
+    }
+    eprintln!("Read {} blobs from manifest", blobs.len());
+    Ok(blobs)
+}
+
+fn count_blobs(
+    repo: &Repository,
+    tree: &Tree,
+    counts: &mut HashMap<Oid, usize>,
+) -> Result<usize, Error> {
+    if let Some(&count) = counts.get(&tree.id()) {
+        return Ok(count);
+    }
+    let mut count = 0;
+    for item in tree.iter() {
+        match item.kind() {
+            Some(ObjectType::Tree) => {
+                count += count_blobs(repo, &repo.find_tree(item.id())?, counts)?;
+            }
+            Some(ObjectType::Blob) => count += 1,
+            _ => continue,
+        }
+    }
+    counts.insert(tree.id(), count);
+    Ok(count)
+}
+
+/// The tree of `commit`, or its subtree at `relative_to` if given and present.
+fn commit_tree<'r>(
+    repo: &'r Repository,
+    commit: Oid,
+    relative_to: Option<&Path>,
+) -> Result<Option<Tree<'r>>, Error> {
+    let tree = repo.find_commit(commit)?.tree()?;
+    Ok(match relative_to {
+        None => Some(tree),
+        Some(path) => match tree.get_path(path) {
+            Ok(ref entry) if entry.kind() == Some(ObjectType::Tree) => {
+                Some(repo.find_tree(entry.id())?)
+            }
+            _ => None,
+        },
+    })
+}
 
-    let mut commit_indices_to_blobs = vec![FixedBitSet::with_capacity(0); graph.len()];
+fn collect_paths(
+    repo: &Repository,
+    tree: &Tree,
+    prefix: &Path,
+    out: &mut HashMap<Oid, Vec<PathBuf>>,
+) -> Result<(), Error> {
+    for item in tree.iter() {
+        let path = prefix.join(String::from_utf8_lossy(item.name_bytes()).as_ref());
+        match item.kind() {
+            Some(ObjectType::Tree) => collect_paths(repo, &repo.find_tree(item.id())?, &path, out)?,
+            Some(ObjectType::Blob) => out.entry(item.id()).or_insert_with(Vec::new).push(path),
+            _ => continue,
+        }
+    }
+    Ok(())
+}
+
+/// Find the repository directories the tree-to-integrate most likely corresponds to, by
+/// looking up where the best candidates keep the blobs of the tree-to-integrate.
+/// Returns prefixes along with the amount of blobs fitting them, best first.
+fn infer_prefixes(
+    candidates: &[Candidate],
+    blobs: &[Entry],
+    opts: &Options,
+) -> Result<Vec<(PathBuf, usize)>, Error> {
+    let repo = lut::open_repository(&opts.repository)?;
+    let mut votes = HashMap::new();
+    for candidate in candidates.iter().take(INFER_RELATIVE_CANDIDATES) {
+        let mut paths = HashMap::new();
+        collect_paths(
+            &repo,
+            &repo.find_commit(candidate.commit)?.tree()?,
+            Path::new(""),
+            &mut paths,
+        )?;
+        for blob in blobs {
+            for path in paths.get(&blob.oid).into_iter().flat_map(|p| p.iter()) {
+                if !path.ends_with(&blob.path) {
+                    continue;
+                }
+                let num_components = path.components().count() - blob.path.components().count();
+                let prefix: PathBuf = path.components().take(num_components).collect();
+                *votes.entry(prefix).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut prefixes: Vec<_> = votes.into_iter().collect();
+    prefixes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(prefixes)
+}
+
+/// Keep only candidates whose tree contains exactly the blobs of the tree-to-integrate,
+/// reporting the best of the others along with how many blobs they have in excess.
+/// If `relative_to` is set, the subtree at this path is used instead of the whole tree.
+fn strict_matches(
+    candidates: Vec<Candidate>,
+    num_blobs: usize,
+    relative_to: Option<&Path>,
+    opts: &Options,
+) -> Result<Vec<Candidate>, Error> {
+    let repo = lut::open_repository(&opts.repository)?;
+    let mut counts = HashMap::new();
+    let mut matches = Vec::new();
+    let mut num_near_misses = 0;
+    for candidate in candidates {
+        let tree = match commit_tree(&repo, candidate.commit, relative_to)? {
+            Some(tree) => tree,
+            None => {
+                if num_near_misses < opts.max_candidates {
+                    num_near_misses += 1;
+                    eprintln!(
+                        "Near miss: {} contains {}/{} blobs, but has no directory at '{}'",
+                        candidate.commit,
+                        candidate.matched,
+                        num_blobs,
+                        relative_to.unwrap_or_else(|| Path::new("")).display()
+                    );
+                }
+                continue;
+            }
+        };
+        let count = count_blobs(&repo, &tree, &mut counts)?;
+        if count == num_blobs && candidate.matched == num_blobs {
+            matches.push(candidate);
+        } else if num_near_misses < opts.max_candidates {
+            num_near_misses += 1;
+            eprintln!(
+                "Near miss: {} contains {}/{} blobs, its tree has {:+} blobs",
+                candidate.commit,
+                candidate.matched,
+                num_blobs,
+                count as isize - num_blobs as isize
+            );
+        }
+    }
+    eprintln!("Found {} commits with exactly matching trees", matches.len());
+    Ok(matches)
+}
+
+/// Write a record of `fields` separated by spaces, or each followed by NUL if `nul_terminated`
+/// is set, so fields like paths may contain spaces and newlines.
+fn write_fields(out: &mut impl Write, fields: &[String], nul_terminated: bool) -> io::Result<()> {
+    if nul_terminated {
+        for field in fields {
+            write!(out, "{}\0", field)?;
+        }
+    } else {
+        write!(out, "{}", fields.join(" "))?;
+    }
+    write!(out, "{}", record_terminator(nul_terminated))
+}
+
+/// Write the first commit containing all blobs, or the best ranked one, and signal which
+/// one it was with the exit code.
+fn best(
+    candidates: &[Candidate],
+    num_blobs: usize,
+    nul_terminated: bool,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let (candidate, exact) = match candidates.iter().find(|c| c.matched == num_blobs) {
+        Some(candidate) => (candidate, true),
+        None => match candidates.first() {
+            Some(candidate) => (candidate, false),
+            None => {
+                return Err(Exit {
+                    code: NO_COMMITS_EXIT_CODE,
+                    message: "No commit contains any blob of the tree-to-integrate".into(),
+                }.into())
+            }
+        },
+    };
+    write_fields(
+        out,
+        &[
+            candidate.commit.to_string(),
+            String::from(if exact { "exact" } else { "partial" }),
+            format!("{}/{}", candidate.matched, num_blobs),
+            format!("coverage={:.1}%", candidate.coverage * 100.0),
+            format!("rarity={:.1}%", candidate.rarity * 100.0),
+        ],
+        nul_terminated,
+    )?;
+    if exact {
+        Ok(())
+    } else {
+        Err(Exit {
+            code: PARTIAL_MATCH_EXIT_CODE,
+            message: format!(
+                "No commit contains all {} blobs, the best one contains {}",
+                num_blobs, candidate.matched
+            ),
+        }.into())
+    }
+}
+
+/// Greedily pick up to `n` blobs of `winner` maximizing the amount of not yet discriminated
+/// `rivals` they are absent from, their rarity, and the amount of directories they come from.
+fn select_evidence(
+    winner: &Candidate,
+    rivals: &[Candidate],
+    blobs: &[Entry],
+    weights: &[f64],
+    n: usize,
+) -> Vec<usize> {
+    let mut available = winner.blobs.clone();
+    let mut discriminated = vec![false; rivals.len()];
+    let mut directories = HashSet::new();
+    let mut selected = Vec::new();
+    while selected.len() < n {
+        let mut best: Option<(f64, usize)> = None;
+        for bid in (0..blobs.len()).filter(|&bid| available.contains(bid)) {
+            let num_discriminated = rivals
+                .iter()
+                .zip(&discriminated)
+                .filter(|&(rival, &done)| !done && !rival.blobs.contains(bid))
+                .count();
+            let diversity = if directories.contains(&blobs[bid].path.parent()) {
+                0.0
+            } else {
+                EVIDENCE_DIVERSITY_BONUS
+            };
+            let gain = num_discriminated as f64 + weights[bid] + diversity;
+            if best.map_or(true, |(best_gain, _)| gain > best_gain) {
+                best = Some((gain, bid));
+            }
+        }
+        let bid = match best {
+            Some((_, bid)) => bid,
+            None => break,
+        };
+        available.set(bid, false);
+        for (rival, done) in rivals.iter().zip(discriminated.iter_mut()) {
+            if !rival.blobs.contains(bid) {
+                *done = true;
+            }
+        }
+        directories.insert(blobs[bid].path.parent());
+        selected.push(bid);
+    }
+    selected
+}
+
+/// Write up to `n` blobs proving the match of the best candidate, after verifying that its tree
+/// really contains them if the repository is available.
+fn evidence(
+    candidates: &[Candidate],
+    blobs: &[Entry],
+    weights: &[f64],
+    n: usize,
+    opts: &Options,
+    out: &mut impl Write,
+) -> Result<(), Error> {
+    let (winner, rivals) = match candidates.split_first() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let rivals = &rivals[..rivals.len().min(opts.max_candidates)];
+    let mut selected = select_evidence(winner, rivals, blobs, weights, n);
+    match lut::open_repository(&opts.repository) {
+        Ok(repo) => {
+            let mut paths = HashMap::new();
+            collect_paths(
+                &repo,
+                &repo.find_commit(winner.commit)?.tree()?,
+                Path::new(""),
+                &mut paths,
+            )?;
+            let num_selected = selected.len();
+            selected.retain(|&bid| paths.contains_key(&blobs[bid].oid));
+            if selected.len() < num_selected {
+                eprintln!(
+                    "WARNING: Dropped {} evidence blobs not contained in the tree of {}",
+                    num_selected - selected.len(),
+                    winner.commit
+                );
+            }
+        }
+        Err(_) => eprintln!("WARNING: Could not open the repository to verify the evidence"),
+    }
+    eprintln!(
+        "Selected {} evidence blobs for {} against {} rivals",
+        selected.len(),
+        winner.commit,
+        rivals.len()
+    );
+    for bid in selected {
+        let absent_from: Vec<_> = rivals
+            .iter()
+            .filter(|rival| !rival.blobs.contains(bid))
+            .map(|rival| rival.commit.to_string())
+            .collect();
+        write_fields(
+            out,
+            &[
+                "evidence".into(),
+                blobs[bid].oid.to_string(),
+                format!("rarity={:.3}", weights[bid]),
+                format!(
+                    "absent-from={}",
+                    if absent_from.is_empty() {
+                        "-".into()
+                    } else {
+                        absent_from.join(",")
+                    }
+                ),
+                blobs[bid].path.display().to_string(),
+            ],
+            opts.nul_terminated,
+        )?;
+    }
+    Ok(())
+}
+
+/// The amount and rarity-weight of matched blobs of all commits seen so far, along with the
+/// current leader by the score of the active ranking.
+struct Race<'a> {
+    weights: &'a [f64],
+    total_weight: f64,
+    rank_by: RankBy,
+    matched: HashMap<usize, (usize, f64)>,
+    leader: Option<(usize, f64)>,
+    runner_up: f64,
+    num_processed: usize,
+    processed_weight: f64,
+}
+
+impl<'a> Race<'a> {
+    fn new(weights: &'a [f64], rank_by: RankBy) -> Self {
+        Race {
+            weights,
+            total_weight: weights.iter().sum(),
+            rank_by,
+            matched: HashMap::new(),
+            leader: None,
+            runner_up: 0.0,
+            num_processed: 0,
+            processed_weight: 0.0,
+        }
+    }
+
+    /// The score of a commit matching `matched` blobs of the given rarity-`weight`, as computed
+    /// when ranking the candidates.
+    fn score(&self, matched: usize, weight: f64) -> f64 {
+        let coverage = matched as f64 / self.weights.len() as f64;
+        let rarity = if self.total_weight > 0.0 {
+            weight / self.total_weight
+        } else {
+            0.0
+        };
+        match self.rank_by {
+            RankBy::Coverage => coverage,
+            RankBy::Rarity => rarity,
+            RankBy::Combined => (coverage + rarity) / 2.0,
+        }
+    }
+
+    /// Count the commits containing the processed blob `bid`. As scores only increase, the
+    /// leader and the score of the runner-up can be kept up to date incrementally.
+    fn record(&mut self, bid: usize, commits: &[usize]) {
+        let blob_weight = self.weights[bid];
+        self.num_processed += 1;
+        self.processed_weight += blob_weight;
+        for &commit in commits {
+            let (matched, weight) = {
+                let entry = self.matched.entry(commit).or_insert((0, 0.0));
+                entry.0 += 1;
+                entry.1 += blob_weight;
+                *entry
+            };
+            let score = self.score(matched, weight);
+            self.leader = match self.leader {
+                Some((leader, _)) if leader == commit => Some((commit, score)),
+                Some((leader, leader_score)) if score <= leader_score => {
+                    self.runner_up = self.runner_up.max(score);
+                    Some((leader, leader_score))
+                }
+                Some((_, leader_score)) => {
+                    self.runner_up = leader_score;
+                    Some((commit, score))
+                }
+                None => Some((commit, score)),
+            };
+        }
+    }
+
+    fn lead(&self) -> f64 {
+        self.leader.map_or(0.0, |(_, score)| score - self.runner_up)
+    }
+
+    /// The score any commit could still gain if it contained all blobs not processed yet.
+    fn remaining_score(&self) -> f64 {
+        self.score(
+            self.weights.len() - self.num_processed,
+            (self.total_weight - self.processed_weight).max(0.0),
+        )
+    }
+
+    /// True if the leader stays ahead even if the runner-up matches all remaining blobs.
+    fn is_decided(&self) -> bool {
+        self.leader.is_some() && self.lead() > self.remaining_score()
+    }
+
+    fn report(&self, num_leaders: usize, graph: &ReverseGraph) {
+        let mut leaders: Vec<_> = self.matched
+            .iter()
+            .map(|(&c, &(m, w))| (self.score(m, w), m, c))
+            .collect();
+        leaders.sort_by(|a, b| {
+            b.0.partial_cmp(&a.0)
+                .unwrap_or(Ordering::Equal)
+                .then(b.1.cmp(&a.1))
+                .then(a.2.cmp(&b.2))
+        });
+        let num_blobs = self.weights.len();
+        eprintln!(
+            "After {}/{} blobs: leading by {:.6} {} score with {} blobs worth at most {:.6} remaining{}",
+            self.num_processed,
+            num_blobs,
+            self.lead(),
+            self.rank_by,
+            num_blobs - self.num_processed,
+            self.remaining_score(),
+            if self.is_decided() {
+                " - the leader can't be caught"
+            } else {
+                ""
+            }
+        );
+        for &(score, matched, commit) in leaders.iter().take(num_leaders) {
+            eprintln!(
+                "{} {}/{} score={:.6}",
+                graph.oid_of(commit),
+                matched,
+                num_blobs,
+                score
+            );
+        }
+    }
+}
+
+pub fn commit(blobs: Vec<Entry>, graph: ReverseGraph, opts: &Options) -> Result<(), Error> {
+    let progress = ProgressBar::new_spinner();
+    // Only commits containing any blob get a bitset, which are few compared to all vertices.
+    let mut commit_indices_to_blobs = HashMap::new();
     let num_threads = opts.threads.unwrap_or(num_cpus::get_physical());
-    crossbeam::scope(|scope| {
+    let watchdog = Watchdog::spawn(opts.hang_timeout, opts.hang_abort);
+    let stop = AtomicBool::new(false);
+    let weights = rarity_weights(&blobs, &graph);
+    let lookup_error = crossbeam::scope(|scope| {
         let or = {
             let (is, ir) = crossbeam_channel::bounded::<(usize, Oid)>(num_threads);
-            let (os, or) = crossbeam_channel::bounded::<(usize, Vec<usize>)>(num_threads);
+            let (os, or) =
+                crossbeam_channel::bounded::<(usize, Result<Vec<usize>, Error>)>(num_threads);
             let blobs = &blobs;
+            let stop = &stop;
             scope.spawn(move || {
-                for bid_and_blob in blobs.iter().cloned().enumerate() {
-                    is.send(bid_and_blob);
+                for (bid, blob) in blobs.iter().enumerate() {
+                    if stop.load(AtomicOrdering::Relaxed) {
+                        break;
+                    }
+                    is.send((bid, blob.oid));
                 }
             });
-            for _ in 0..num_threads {
+            for tid in 0..num_threads {
                 let graph = &graph;
+                let watchdog = &watchdog;
                 let ir = ir.clone();
                 let os = os.clone();
                 scope.spawn(move || {
-                    let mut stack = Stack::default();
+                    let heartbeat = watchdog.register(&format!("lookup-{}", tid), "blob");
+                    let mut stack = Stack::with_subtree_memo(opts.subtree_cache_mb);
                     for (bid, blob) in ir {
+                        heartbeat.beat(blob);
                         let mut commits = Vec::new();
-                        graph.lookup_idx(&blob, &mut stack, &mut commits);
-                        os.send((bid, commits));
+                        let result = if opts.test_check_lookups {
+                            graph.check_lookup_consistency(&blob, &mut stack)
+                        } else {
+                            Ok(())
+                        }.and_then(|_| graph.lookup_idx(&blob, &mut stack, &mut commits))
+                            .map(|_| commits);
+                        os.send((bid, result));
                     }
                 });
             }
@@ -81,13 +872,35 @@ pub fn commit(tree: &Path, graph: ReverseGraph, opts: &Options) -> Result<(), Er
         };
 
         let mut total_commits = 0;
+        let mut first_error = None;
+        let mut race = Race::new(&weights, opts.rank_by);
+        let mut last_report = Instant::now();
         for (bid, commits) in or {
-            for &commit_index in &commits {
-                let bits = unsafe { commit_indices_to_blobs.get_unchecked_mut(commit_index) };
-                if bits.len() == 0 {
-                    bits.grow(blobs.len());
+            let commits = match commits {
+                Ok(commits) => commits,
+                Err(err) => {
+                    first_error = first_error.or(Some(err));
+                    continue;
+                }
+            };
+            if opts.progressive || opts.early_stop {
+                race.record(bid, &commits);
+                let interval = Duration::from_millis(PROGRESSIVE_INTERVAL_MS);
+                if opts.progressive && last_report.elapsed() >= interval {
+                    race.report(opts.max_candidates, &graph);
+                    last_report = Instant::now();
                 }
-                bits.put(bid);
+                if opts.early_stop && !stop.load(AtomicOrdering::Relaxed)
+                    && race.is_decided()
+                {
+                    stop.store(true, AtomicOrdering::Relaxed);
+                }
+            }
+            for &commit_index in &commits {
+                commit_indices_to_blobs
+                    .entry(commit_index)
+                    .or_insert_with(|| FixedBitSet::with_capacity(blobs.len()))
+                    .put(bid);
             }
             total_commits += commits.len();
             progress.set_message(&format!(
@@ -104,9 +917,90 @@ pub fn commit(tree: &Path, graph: ReverseGraph, opts: &Options) -> Result<(), Er
             blobs.len(),
             total_commits
         );
+        if opts.progressive || opts.early_stop {
+            race.report(opts.max_candidates, &graph);
+            if stop.load(AtomicOrdering::Relaxed) {
+                eprintln!(
+                    "Stopped early after {} of {} blobs as the leader can't be caught, skipping {} blobs",
+                    race.num_processed,
+                    blobs.len(),
+                    blobs.len() - race.num_processed
+                );
+            } else {
+                eprintln!("Processed all {} blobs", race.num_processed);
+            }
+        }
+        first_error
     });
-    let _commit_indices_to_blobs = compact(commit_indices_to_blobs, graph);
+    drop(watchdog);
+    if let Some(err) = lookup_error {
+        return Err(err);
+    }
+    let mut candidates = rank(
+        compact(commit_indices_to_blobs, &graph),
+        &weights,
+        opts.rank_by,
+    );
+    let mut relative_to = opts.relative_to.clone();
+    if opts.infer_relative && relative_to.is_none() {
+        let prefixes = infer_prefixes(&candidates, &blobs, opts)?;
+        for &(ref prefix, num_blobs) in prefixes.iter().take(INFER_RELATIVE_PREFIXES) {
+            eprintln!("Prefix '{}' fits {} blobs", prefix.display(), num_blobs);
+        }
+        relative_to = prefixes.into_iter().next().map(|(prefix, _)| prefix);
+        if let Some(ref prefix) = relative_to {
+            eprintln!("Best fitting prefix: '{}'", prefix.display());
+        }
+    }
+    if opts.strict_tree_match {
+        candidates = strict_matches(
+            candidates,
+            blobs.len(),
+            relative_to.as_ref().map(|p| p.as_path()),
+            opts,
+        )?;
+    }
 
-    eprintln!("unimplemented");
+    eprintln!(
+        "Ranked {} candidate commits by {} score",
+        candidates.len(),
+        opts.rank_by
+    );
+    let stdout = stdout();
+    let mut out = stdout.lock();
+    if opts.best {
+        let result = best(&candidates, blobs.len(), opts.nul_terminated, &mut out);
+        if let Some(n) = opts.evidence {
+            evidence(&candidates, &blobs, &weights, n, opts, &mut out)?;
+        }
+        return result;
+    }
+    let mut notes = opts.show_notes
+        .clone()
+        .map(|notes_ref| Notes::open(&opts.repository, notes_ref, opts.note_max_chars));
+    for candidate in candidates.iter().take(opts.max_candidates) {
+        write_fields(
+            &mut out,
+            &[
+                candidate.commit.to_string(),
+                format!("{}/{}", candidate.matched, blobs.len()),
+                format!("coverage={:.1}%", candidate.coverage * 100.0),
+                format!("rarity={:.1}%", candidate.rarity * 100.0),
+            ],
+            opts.nul_terminated,
+        )?;
+        if let Some(ref mut notes) = notes {
+            if let Some(note) = notes.get(candidate.commit) {
+                write_fields(
+                    &mut out,
+                    &["note".into(), candidate.commit.to_string(), note.into()],
+                    opts.nul_terminated,
+                )?;
+            }
+        }
+    }
+    if let Some(n) = opts.evidence {
+        evidence(&candidates, &blobs, &weights, n, opts, &mut out)?;
+    }
     Ok(())
 }
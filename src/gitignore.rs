@@ -0,0 +1,134 @@
+use failure::{Error, ResultExt};
+use std::{env, path::{Path, PathBuf}};
+use git2::Repository;
+use lut;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use walkdir::DirEntry;
+use Options;
+
+const IGNORE_FILE: &str = ".gitignore";
+
+/// Decides which entries of the tree-to-integrate are excluded from hashing, using the
+/// same rules git would apply.
+pub struct Ignores {
+    repository: Option<Gitignore>,
+    tree: Option<Vec<(usize, Gitignore)>>,
+    pub num_ignored: usize,
+}
+
+fn global_excludes_file(repo: &Repository) -> Option<PathBuf> {
+    repo.config()
+        .ok()
+        .and_then(|c| c.get_path("core.excludesFile").ok())
+        .or_else(|| {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+                .map(|c| c.join("git").join("ignore"))
+        })
+}
+
+fn compile(root: &Path, sources: &[PathBuf]) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    for source in sources.iter().filter(|p| p.is_file()) {
+        if let Some(err) = builder.add(source) {
+            return Err(Error::from(err)
+                .context(format!("Could not read ignore rules from '{}'", source.display()))
+                .into());
+        }
+    }
+    Ok(builder.build()?)
+}
+
+fn repository_rules(root: &Path, repository: &Path) -> Result<Gitignore, Error> {
+    let repo = lut::open_repository(repository).with_context(|_| {
+        format!(
+            "Could not open repository at '{}' to read ignore rules",
+            repository.display()
+        )
+    })?;
+    let mut sources: Vec<_> = repo.workdir()
+        .map(|w| w.join(IGNORE_FILE))
+        .into_iter()
+        .collect();
+    sources.push(repo.path().join("info").join("exclude"));
+    sources.extend(global_excludes_file(&repo));
+    compile(root, &sources)
+}
+
+impl Ignores {
+    /// If `workdir` is set, `tree` is within the worktree of REPOSITORY, whose rules
+    /// are applied as if --use-gitignore and --use-tree-gitignore were given.
+    pub fn new(
+        tree: &Path,
+        workdir: Option<&Path>,
+        opts: &Options,
+    ) -> Result<Option<Ignores>, Error> {
+        let root = workdir.unwrap_or(tree);
+        let repository = match (&opts.use_gitignore, workdir) {
+            (&Some(ref repository), _) => Some(repository_rules(
+                root,
+                repository.as_ref().unwrap_or(&opts.repository),
+            )?),
+            (&None, Some(_)) => Some(repository_rules(root, &opts.repository)?),
+            (&None, None) => None,
+        };
+        let tree_rules = if opts.use_tree_gitignore || workdir.is_some() {
+            Some(vec![(0, compile(tree, &[tree.join(IGNORE_FILE)])?)])
+        } else {
+            None
+        };
+        Ok(match (repository, tree_rules) {
+            (None, None) => None,
+            (repository, tree) => Some(Ignores {
+                repository,
+                tree,
+                num_ignored: 0,
+            }),
+        })
+    }
+
+    /// Must be called for entries in the order they are produced by a depth-first walk.
+    /// Ignored directories are expected not to be descended into.
+    pub fn is_ignored(&mut self, entry: &DirEntry) -> bool {
+        let is_dir = entry.file_type().is_dir();
+        let mut decision = None;
+        if let Some(ref mut stack) = self.tree {
+            while stack
+                .last()
+                .map_or(false, |&(depth, _)| depth >= entry.depth())
+            {
+                stack.pop();
+            }
+            decision = stack
+                .iter()
+                .rev()
+                .map(|&(_, ref rules)| rules.matched(entry.path(), is_dir))
+                .find(|m| !m.is_none())
+                .map(|m| m.is_ignore());
+        }
+        let ignored = decision.unwrap_or_else(|| match self.repository {
+            Some(ref rules) => match rules.matched(entry.path(), is_dir) {
+                Match::Ignore(_) => true,
+                _ => false,
+            },
+            None => false,
+        });
+
+        if ignored {
+            self.num_ignored += 1;
+        } else if is_dir {
+            if let Some(ref mut stack) = self.tree {
+                let rules_file = entry.path().join(IGNORE_FILE);
+                if rules_file.is_file() {
+                    match compile(entry.path(), &[rules_file]) {
+                        Ok(rules) => stack.push((entry.depth(), rules)),
+                        Err(err) => eprintln!("WARNING: {}", err),
+                    }
+                }
+            }
+        }
+        ignored
+    }
+}
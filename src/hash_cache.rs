@@ -0,0 +1,212 @@
+//! Remembers the object ids of hashed files along with their size and modification time, so that
+//! unchanged files don't have to be hashed again. Records are NUL-terminated and appended while
+//! hashing, which allows an interrupted run to be resumed without hashing everything again.
+
+use failure::{Error, ResultExt};
+use git2::Oid;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::UNIX_EPOCH;
+
+const MAGIC: &str = "git-reconstruct-hash-cache";
+const VERSION: u32 = 1;
+/// The amount of newly hashed files after which they are written to the cache file.
+const CHECKPOINT_RATE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl Stamp {
+    fn of(metadata: &Metadata) -> Option<Stamp> {
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+fn header(filter: Option<&str>) -> String {
+    format!("{} {} {}", MAGIC, VERSION, filter.unwrap_or("-"))
+}
+
+fn write_record(out: &mut impl Write, path: &str, stamp: Stamp, oid: Oid) -> Result<(), Error> {
+    write!(
+        out,
+        "{} {}.{} {} {}\0",
+        stamp.size, stamp.mtime_secs, stamp.mtime_nanos, oid, path
+    )?;
+    Ok(())
+}
+
+fn parse_record(record: &str) -> Option<(String, Stamp, Oid)> {
+    let mut fields = record.splitn(4, ' ');
+    let size = fields.next()?.parse().ok()?;
+    let mut mtime = fields.next()?.splitn(2, '.');
+    let (mtime_secs, mtime_nanos) = (mtime.next()?.parse().ok()?, mtime.next()?.parse().ok()?);
+    let oid = Oid::from_str(fields.next()?).ok()?;
+    let path = fields.next()?;
+    if path.is_empty() {
+        return None;
+    }
+    Some((
+        path.to_owned(),
+        Stamp {
+            size,
+            mtime_secs,
+            mtime_nanos,
+        },
+        oid,
+    ))
+}
+
+/// Reads the records of the cache at `path` written for `filter`, or nothing if it was written
+/// for another filter. The last record lacks its NUL if the writing run was interrupted,
+/// and is ignored.
+fn read_records(
+    path: &Path,
+    filter: Option<&str>,
+) -> Result<Option<HashMap<String, (Stamp, Oid)>>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let mut input = BufReader::new(file);
+    let mut record = Vec::new();
+    input.read_until(b'\0', &mut record)?;
+    match record.split_last() {
+        Some((&0, header_record)) if header_record == header(filter).as_bytes() => {}
+        _ => {
+            eprintln!(
+                "WARNING: Ignoring hash cache at '{}' as it was written by another version or with another --hash-filter",
+                path.display()
+            );
+            return Ok(None);
+        }
+    }
+    let mut known = HashMap::new();
+    loop {
+        record.clear();
+        if input.read_until(b'\0', &mut record)? == 0 || record.pop() != Some(0) {
+            break;
+        }
+        if let Some((path, stamp, oid)) = str::from_utf8(&record).ok().and_then(parse_record) {
+            known.insert(path, (stamp, oid));
+        }
+    }
+    Ok(Some(known))
+}
+
+pub struct HashCache {
+    path: PathBuf,
+    filter: Option<String>,
+    known: HashMap<String, (Stamp, Oid)>,
+    current: Vec<(String, Stamp, Oid)>,
+    out: BufWriter<File>,
+    num_pending: usize,
+    pub num_reused: usize,
+    pub num_hashed: usize,
+}
+
+impl HashCache {
+    /// Open the cache at `path`, which is created if needed, for files hashed with `filter`.
+    pub fn open(path: &Path, filter: Option<&str>) -> Result<HashCache, Error> {
+        let known = read_records(path, filter)?;
+        let mut options = OpenOptions::new();
+        options.create(true);
+        if known.is_some() {
+            options.append(true);
+        } else {
+            options.write(true).truncate(true);
+        }
+        let mut out = BufWriter::new(options
+            .open(path)
+            .with_context(|_| format!("Could not open hash cache at '{}'", path.display()))?);
+        if known.is_none() {
+            write!(out, "{}\0", header(filter))?;
+            out.flush()?;
+        }
+        let known = known.unwrap_or_default();
+        if !known.is_empty() {
+            eprintln!(
+                "Loaded {} hashes from hash cache at '{}'",
+                known.len(),
+                path.display()
+            );
+        }
+        Ok(HashCache {
+            path: path.to_owned(),
+            filter: filter.map(ToOwned::to_owned),
+            known,
+            current: Vec::new(),
+            out,
+            num_pending: 0,
+            num_reused: 0,
+            num_hashed: 0,
+        })
+    }
+
+    /// The object id of the file at `path` if it didn't change since it was hashed.
+    pub fn get(&mut self, path: &Path, metadata: &Metadata) -> Option<Oid> {
+        let (path, stamp) = (path.to_str()?, Stamp::of(metadata)?);
+        match self.known.get(path) {
+            Some(&(known_stamp, oid)) if known_stamp == stamp => {
+                self.current.push((path.to_owned(), stamp, oid));
+                self.num_reused += 1;
+                Some(oid)
+            }
+            _ => None,
+        }
+    }
+
+    /// Remember `oid` as object id of the file at `path`. It is written to the cache file
+    /// every few files.
+    pub fn insert(&mut self, path: &Path, metadata: &Metadata, oid: Oid) -> Result<(), Error> {
+        self.num_hashed += 1;
+        let (path, stamp) = match (path.to_str(), Stamp::of(metadata)) {
+            (Some(path), Some(stamp)) => (path, stamp),
+            _ => return Ok(()),
+        };
+        write_record(&mut self.out, path, stamp, oid)?;
+        self.current.push((path.to_owned(), stamp, oid));
+        self.num_pending += 1;
+        if self.num_pending >= CHECKPOINT_RATE {
+            self.out.flush()?;
+            self.num_pending = 0;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the cache file to contain only the files seen in this run.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.out.flush()?;
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut out = BufWriter::new(File::create(&tmp_path).with_context(|_| {
+                format!("Could not write hash cache at '{}'", tmp_path.display())
+            })?);
+            write!(out, "{}\0", header(self.filter.as_ref().map(|f| f.as_str())))?;
+            for &(ref path, stamp, oid) in &self.current {
+                write_record(&mut out, path, stamp, oid)?;
+            }
+            out.flush()?;
+        }
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|_| format!("Could not write hash cache at '{}'", self.path.display()))?;
+        eprintln!(
+            "Hash cache: reused {} hashes, hashed {} files",
+            self.num_reused, self.num_hashed
+        );
+        Ok(())
+    }
+}
@@ -1,24 +1,715 @@
-use failure::Error;
-use std::collections::{BTreeMap, btree_map::Entry};
-use git2::{ObjectType, Oid, Repository, Revwalk, Tree};
+use failure::{Error, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use git2::{ObjectType, Odb, Oid, Reference, Repository, Revwalk, Tree};
 use indicatif::ProgressBar;
 use Stack;
-use Options;
 use git2;
 use bincode::{deserialize_from, serialize_into};
-use std::io;
+use std::io::{self, BufRead, BufReader};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use batch::{self, ObjectStream};
+use watchdog::{self, Heartbeat, Watchdog};
+use commit_graph::CommitGraph;
+use pathspec::{self, Match};
+use memory;
+use date;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{cmp, env, ffi::OsStr, fmt, iter, mem, str::FromStr, thread, time::Duration,
+          hash::Hasher, process::{Command, Stdio}};
+use fixedbitset::FixedBitSet;
+use crossbeam;
+use num_cpus;
 
 const COMMIT_PROGRESS_RATE: usize = 100;
+const TREE_ENTRY_PROGRESS_RATE: usize = 10_000;
+const FSCK_SAMPLE_COMMITS: usize = 100;
+const LAST_VISITED_ON_CYCLE: usize = 4;
+const SUBTREE_MEMO_MIN_VISITS: u32 = 2;
+const SUBTREE_MEMO_MAX_TRACKED: usize = 1 << 20;
+const SUBTREE_MEMO_ENTRY_OVERHEAD: usize = 64;
+const OBJECT_DIRECTORY_ENV: &str = "GIT_OBJECT_DIRECTORY";
+const ALTERNATES_ENV: &str = "GIT_ALTERNATE_OBJECT_DIRECTORIES";
+const WORK_TREE_ENV: &str = "GIT_WORK_TREE";
+const GIT_DIR_ENV: &str = "GIT_DIR";
+/// The REPOSITORY used if none is given, which is searched upwards like git does.
+const DEFAULT_REPOSITORY: &str = ".";
+const NO_REPLACE_OBJECTS_ENV: &str = "GIT_NO_REPLACE_OBJECTS";
+const REPLACE_REFS_PREFIX: &str = "refs/replace/";
+const STASH_REF: &str = "refs/stash";
+const PACK_INDEX_MAGIC: [u8; 4] = [0xff, 0x74, 0x4f, 0x63];
+const PACK_INDEX_HEADER_LEN: usize = 8;
+const PACK_INDEX_FANOUT_LEN: usize = 256 * 4;
+
+/// Everything needed to build a `ReverseGraph`, independent of how it was configured.
+#[derive(Default, Debug, Clone)]
+pub struct BuildOptions {
+    pub repository: PathBuf,
+    pub head_only: bool,
+    pub fsck_lite: bool,
+    pub fetch_missing: bool,
+    pub objects_from_batch: Option<String>,
+    pub tips: Vec<String>,
+    pub tips_from: Option<PathBuf>,
+    pub remote_patterns: Vec<String>,
+    pub exclude_remotes: Vec<String>,
+    pub local_branches: bool,
+    pub include_tags: bool,
+    pub refs: Vec<String>,
+    pub all_refs: bool,
+    pub rev_range: Option<String>,
+    pub exclude_revs: Vec<String>,
+    pub exclude_refs: Vec<String>,
+    pub include_worktrees: bool,
+    pub include_stash: bool,
+    pub include_index: bool,
+    pub no_commit_graph: bool,
+    pub no_gitlinks: bool,
+    pub paths: Vec<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub first_parent: bool,
+    pub include_dangling: bool,
+    pub no_replace_objects: bool,
+    pub allow_shallow: bool,
+    pub other_repositories: Vec<PathBuf>,
+    pub recurse_submodules: bool,
+    pub report_memory: bool,
+    pub with_forward_edges: bool,
+    pub max_tree_entries: Option<usize>,
+    pub max_blob_size: Option<u64>,
+    pub max_commits: Option<usize>,
+    pub threads: Option<usize>,
+    pub hang_timeout: u64,
+    pub hang_abort: bool,
+    pub test_hang_at: Option<String>,
+}
+
+/// The kind of object a vertex represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Kind {
+    Commit,
+    Tree,
+    Blob,
+    /// The commit of a submodule a tree entry points to, which isn't a commit of the graph.
+    Gitlink,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "commit" => Kind::Commit,
+            "tree" => Kind::Tree,
+            "blob" => Kind::Blob,
+            "gitlink" => Kind::Gitlink,
+            _ => return Err(format!("'{}' is not one of 'commit', 'tree', 'blob' or 'gitlink'", s)),
+        })
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Kind::Commit => "commit",
+            Kind::Tree => "tree",
+            Kind::Blob => "blob",
+            Kind::Gitlink => "gitlink",
+        })
+    }
+}
+
+/// The commits reachable from trees traversed by multiple lookups, so that later lookups
+/// passing through these trees don't have to walk up to the commits again.
+/// Entries are evicted least-recently-used first once they exceed the memory budget.
+/// Trees are keyed by the id of their graph along with their index, as one memo serves lookups
+/// in all graphs.
+pub struct SubtreeMemo {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    entries: HashMap<(usize, usize), (Vec<usize>, u64)>,
+    by_age: BTreeMap<u64, (usize, usize)>,
+    visits: HashMap<(usize, usize), u32>,
+    indices: Vec<usize>,
+    visited: FixedBitSet,
+    visited_indices: Vec<usize>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl SubtreeMemo {
+    pub fn new(budget_bytes: usize) -> SubtreeMemo {
+        SubtreeMemo {
+            budget: budget_bytes,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            by_age: BTreeMap::new(),
+            visits: HashMap::new(),
+            indices: Vec::new(),
+            visited: FixedBitSet::with_capacity(0),
+            visited_indices: Vec::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used
+    }
+
+    fn entry_size(commits: &[usize]) -> usize {
+        commits.len() * mem::size_of::<usize>() + SUBTREE_MEMO_ENTRY_OVERHEAD
+    }
+
+    /// Returns true once `tree` was seen often enough to be worth memoizing.
+    fn is_frequently_visited(&mut self, tree: (usize, usize)) -> bool {
+        if self.visits.len() > SUBTREE_MEMO_MAX_TRACKED {
+            self.visits.clear();
+        }
+        let visits = self.visits.entry(tree).or_insert(0);
+        *visits += 1;
+        *visits >= SUBTREE_MEMO_MIN_VISITS
+    }
+
+    fn insert(&mut self, tree: (usize, usize), commits: Vec<usize>) -> bool {
+        let size = Self::entry_size(&commits);
+        if size > self.budget {
+            return false;
+        }
+        while self.used + size > self.budget {
+            let (&tick, &oldest) = self.by_age.iter().next().expect("entries while over budget");
+            self.by_age.remove(&tick);
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.used -= Self::entry_size(&evicted);
+            }
+        }
+        self.used += size;
+        self.visits.remove(&tree);
+        self.entries.insert(tree, (commits, 0));
+        true
+    }
+
+    /// The commits reachable from `tree` of `graph` if they are memoized or worth memoizing now.
+    fn commits_of(&mut self, graph: &ReverseGraph, tree_idx: usize) -> Option<&[usize]> {
+        let tree = (graph.id, tree_idx);
+        if self.entries.contains_key(&tree) {
+            self.hits += 1;
+        } else {
+            if !self.is_frequently_visited(tree) {
+                return None;
+            }
+            self.misses += 1;
+            let commits = graph.commits_reachable_from(
+                tree_idx,
+                &mut self.indices,
+                &mut self.visited,
+                &mut self.visited_indices,
+            );
+            if !self.insert(tree, commits) {
+                return None;
+            }
+        }
+        self.tick += 1;
+        let entry = self.entries.get_mut(&tree).expect("entry to be present");
+        self.by_age.remove(&entry.1);
+        entry.1 = self.tick;
+        self.by_age.insert(self.tick, tree);
+        Some(&entry.0)
+    }
+}
+
+/// A fast, non-cryptographic hasher in the manner of FxHash. Object ids are uniformly
+/// distributed already, so mixing their words in cheaply is enough.
+#[derive(Default)]
+pub struct FxHasher(u64);
+
+const FX_SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FxHasher {
+    #[inline]
+    fn add(&mut self, word: u64) {
+        self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(FX_SEED);
+    }
+}
+
+impl Hasher for FxHasher {
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            self.add(chunk.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b)));
+        }
+    }
+    #[inline]
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A slot of a `VertexTable` which holds no vertex.
+const EMPTY_SLOT: u32 = u32::max_value();
+
+/// The vertices of a graph while building, found by the object id they have in
+/// `vertices_to_oid`. Only their indices are stored, in an open-addressing table which is at
+/// most three quarters full, so object ids aren't stored a second time as keys.
+#[derive(Default)]
+struct VertexTable {
+    slots: Vec<u32>,
+    len: usize,
+}
+
+impl VertexTable {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The slot holding the vertex of `oid`, or the empty slot it would be inserted at.
+    #[inline]
+    fn slot_of(&self, oid: &Oid, oids: &[Oid]) -> Result<usize, usize> {
+        let mut hasher = FxHasher::default();
+        hasher.write(oid.as_bytes());
+        let mask = self.slots.len() - 1;
+        let mut slot = hasher.finish() as usize & mask;
+        loop {
+            match self.slots[slot] {
+                EMPTY_SLOT => return Err(slot),
+                idx if oids[idx as usize] == *oid => return Ok(slot),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+    #[inline]
+    fn get(&self, oid: &Oid, oids: &[Oid]) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+        self.slot_of(oid, oids).ok().map(|slot| self.slots[slot])
+    }
+    /// Add the vertex `idx` whose object id in `oids` isn't in the table yet.
+    fn insert(&mut self, idx: u32, oids: &[Oid]) {
+        if (self.len + 1) * 4 > self.slots.len() * 3 {
+            let slots = mem::replace(
+                &mut self.slots,
+                vec![EMPTY_SLOT; cmp::max(16, self.slots.len() * 2)],
+            );
+            for idx in slots.into_iter().filter(|&idx| idx != EMPTY_SLOT) {
+                let slot = self.slot_of(&oids[idx as usize], oids).unwrap_err();
+                self.slots[slot] = idx;
+            }
+        }
+        let slot = self.slot_of(&oids[idx as usize], oids).unwrap_err();
+        self.slots[slot] = idx;
+        self.len += 1;
+    }
+}
+
+/// The length of chunks to split `len` items into to process them with `num_threads` threads.
+fn chunk_len(len: usize, num_threads: usize) -> usize {
+    let num_threads = cmp::max(1, num_threads);
+    cmp::max(1, (len + num_threads - 1) / num_threads)
+}
+
+/// Sort the parents of each vertex and remove duplicates, using `num_threads` threads. Returns
+/// the amount of duplicates removed from vertices other than trees, as only their edges are
+/// counted as parent-edges.
+fn dedup_parents(lists: &mut [Vec<u32>], kinds: &[Kind], num_threads: usize) -> usize {
+    let chunk_len = chunk_len(lists.len(), num_threads);
+    crossbeam::scope(|scope| {
+        let handles: Vec<_> = lists
+            .chunks_mut(chunk_len)
+            .zip(kinds.chunks(chunk_len))
+            .map(|(lists, kinds)| {
+                scope.spawn(move || {
+                    let mut removed = 0;
+                    for (parents, &kind) in lists.iter_mut().zip(kinds) {
+                        let len = parents.len();
+                        parents.sort_unstable();
+                        parents.dedup();
+                        if kind != Kind::Tree {
+                            removed += len - parents.len();
+                        }
+                    }
+                    removed
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join()).sum()
+    })
+}
+
+/// The most vertices a graph can have, as their indices are stored as u32 and the largest one
+/// marks empty slots of `VertexTable`.
+const MAX_VERTICES: usize = u32::max_value() as usize - 1;
+
+/// `idx` as stored in the graph. Fails instead of truncating indices beyond `MAX_VERTICES`.
+#[inline]
+fn vertex_index(idx: usize) -> Result<u32, Error> {
+    if idx > MAX_VERTICES {
+        bail!(
+            "The graph can't hold more than {} objects, vertex {} is out of range",
+            MAX_VERTICES,
+            idx
+        )
+    }
+    Ok(idx as u32)
+}
+
+/// The direct parents of all vertices in compressed sparse row layout: the parents of the vertex
+/// at `idx` are `targets[offsets[idx]..offsets[idx + 1]]`.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Edges {
+    offsets: Vec<u32>,
+    targets: Vec<u32>,
+}
+
+impl Edges {
+    /// The edges of `lists`, each of which holds the parents of a vertex, copied by
+    /// `num_threads` threads in parallel. Lists are freed as soon as they are copied.
+    /// Fails if there are more edges than offsets can address.
+    fn from_lists(mut lists: Vec<Vec<u32>>, num_threads: usize) -> Result<Edges, Error> {
+        let num_edges: usize = lists.iter().map(Vec::len).sum();
+        if num_edges > MAX_VERTICES {
+            bail!(
+                "The graph can't hold more than {} parent-edges, but has {}",
+                MAX_VERTICES,
+                num_edges
+            )
+        }
+        let mut offsets = Vec::with_capacity(lists.len() + 1);
+        offsets.push(0);
+        let mut end = 0;
+        for parents in &lists {
+            end += parents.len();
+            offsets.push(end as u32);
+        }
+        let mut targets = vec![0; num_edges];
+        let chunk_len = chunk_len(lists.len(), num_threads);
+        {
+            let offsets = &offsets;
+            crossbeam::scope(|scope| {
+                let mut remaining: &mut [u32] = &mut targets;
+                for (cid, chunk) in lists.chunks_mut(chunk_len).enumerate() {
+                    let first = cid * chunk_len;
+                    let len = (offsets[first + chunk.len()] - offsets[first]) as usize;
+                    let (own, rest) = { remaining }.split_at_mut(len);
+                    remaining = rest;
+                    scope.spawn(move || {
+                        let mut pos = 0;
+                        for parents in chunk {
+                            own[pos..pos + parents.len()].copy_from_slice(parents);
+                            pos += parents.len();
+                            *parents = Vec::new();
+                        }
+                    });
+                }
+            });
+        }
+        Ok(Edges { offsets, targets })
+    }
+    /// The bytes used to store the edges.
+    fn used_bytes(&self) -> usize {
+        (self.offsets.capacity() + self.targets.capacity()) * mem::size_of::<u32>()
+    }
+    #[inline]
+    fn of(&self, idx: usize) -> &[u32] {
+        unsafe {
+            let start = *self.offsets.get_unchecked(idx) as usize;
+            let end = *self.offsets.get_unchecked(idx + 1) as usize;
+            self.targets.get_unchecked(start..end)
+        }
+    }
+    /// The amount of vertices edges are known for.
+    fn len(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+    /// Fail unless these edges are those of `num_vertices` vertices, each pointing to one of
+    /// them, which `of` relies on without checking bounds.
+    fn validate(&self, num_vertices: usize) -> Result<(), Error> {
+        if num_vertices == 0 && self.offsets.is_empty() && self.targets.is_empty() {
+            return Ok(());
+        }
+        if self.offsets.len() != num_vertices + 1 {
+            bail!(
+                "The edges of {} vertices were loaded for a graph of {} vertices",
+                self.len(),
+                num_vertices
+            )
+        }
+        if self.offsets.windows(2).any(|w| w[0] > w[1])
+            || self.offsets[num_vertices] as usize != self.targets.len()
+        {
+            bail!("The edge offsets of the graph are not ascending up to its amount of edges")
+        }
+        if let Some(target) = self.targets.iter().find(|&&t| t as usize >= num_vertices) {
+            bail!(
+                "An edge points to vertex {} of a graph with {} vertices",
+                target,
+                num_vertices
+            )
+        }
+        Ok(())
+    }
+}
+
+/// The id of the next graph which is built or loaded.
+static NEXT_GRAPH_ID: AtomicUsize = AtomicUsize::new(1);
 
 #[derive(Default)]
 pub struct ReverseGraph {
+    /// Tells this graph apart from others in memory, assigned once it is built or loaded.
+    id: usize,
     vertices_to_oid: Vec<Oid>,
-    vertices_to_edges: Vec<Vec<usize>>,
-    oids_to_vertices: BTreeMap<Oid, usize>,
+    vertices_to_kind: Vec<Kind>,
+    /// The direct parents of each vertex while building, until `compact` moves them into `edges`.
+    vertices_to_parents: Vec<Vec<u32>>,
+    /// The direct parents of each vertex once built.
+    edges: Edges,
+    /// The vertex of each object id while building, until `compact` replaces it with
+    /// `vertices_by_oid`.
+    oids_to_vertices: VertexTable,
+    /// The indices of all vertices ordered by their object id once built. Vertices are found by
+    /// binary search, so object ids are stored only once.
+    vertices_by_oid: Vec<u32>,
+    /// The amount of tree entries which are neither blob, tree nor gitlink, seen while building.
+    num_skipped_entries: usize,
+    /// The amount of trees which could not be read while building. Their commits and parent
+    /// trees are still part of the graph.
+    num_missing_trees: usize,
+    /// The amount of traversed commits which couldn't be read, as they aren't available locally.
+    num_missing_commits: usize,
+    /// The amount of missing trees obtained with --fetch-missing.
+    num_fetched_trees: usize,
+    /// The amount of edges from blobs or gitlinks to a tree they were already attached to, as
+    /// they appear at multiple paths of it. They are counted but not stored.
+    num_duplicate_edges: usize,
+    /// The amount of trees with more than the maximum amount of entries, and the amount of
+    /// entries skipped in them while building.
+    num_truncated_trees: usize,
+    num_entries_beyond_limit: usize,
+    max_tree_entries: Option<usize>,
+    /// The amount of commits the traversal was capped at, if there were more.
+    commits_capped_at: Option<usize>,
+    /// The direct children of each vertex, if built with forward edges. The topology is not
+    /// optimized then, so the edges of each vertex are its direct parents, too.
+    vertices_to_children: Option<Vec<Vec<u32>>>,
+    /// The commits which are not reachable from any traversed ref, and may be garbage-collected.
+    dangling_commits: HashSet<usize>,
+    /// The index of the repository given with --repository each commit was found in, starting
+    /// at 1. Commits of REPOSITORY are not listed.
+    commits_to_repository: HashMap<usize, usize>,
+    /// A distinct name for REPOSITORY and each repository given with --repository, to prefix
+    /// their commits with, or empty if there is only REPOSITORY.
+    repository_labels: Vec<String>,
+    /// The paths of the submodules indexed with --recurse-submodules, and the index of the
+    /// submodule each blob was first found in. Blobs of the superproject are not listed.
+    submodule_paths: Vec<String>,
+    blobs_to_submodule: HashMap<usize, usize>,
+    /// The amount of gitlinks whose commit wasn't found in any submodule while building.
+    num_missing_submodule_commits: usize,
+    /// The pathspecs given with --path. If set, only blobs at matching paths are indexed.
+    pathspecs: Vec<String>,
+    /// The blobs which weren't indexed as they are larger than --max-blob-size.
+    oversized_blobs: HashSet<Oid>,
+    /// The labels like 'stash@{2}' of commits indexed with --include-stash.
+    stash_labels: HashMap<usize, String>,
+    /// The blobs staged in the index with --include-index.
+    staged_blobs: HashSet<Oid>,
+}
+
+/// The amounts of problems and limits met while building a graph, which are its caveats and
+/// hence stored along with it.
+#[derive(Default, Deserialize, Serialize)]
+pub struct BuildCounters {
+    num_skipped_entries: usize,
+    num_missing_trees: usize,
+    num_missing_commits: usize,
+    num_truncated_trees: usize,
+    num_entries_beyond_limit: usize,
+    max_tree_entries: Option<usize>,
+    commits_capped_at: Option<usize>,
+    num_missing_submodule_commits: usize,
+}
+
+pub const CACHE_FORMAT: u32 = 6;
+/// The oldest version able to read caches of `CACHE_FORMAT`.
+const CACHE_FORMAT_MIN_READER: Version = Version(1, 5, 0);
+
+/// A semantic version of this program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Version(pub u64, pub u64, pub u64);
+
+impl Version {
+    pub fn current() -> Version {
+        env!("CARGO_PKG_VERSION")
+            .parse()
+            .expect("crate version to be valid")
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.').map(|p| p.parse::<u64>());
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                Ok(Version(major, minor, patch))
+            }
+            _ => Err(format!("'{}' is not a version like '1.2.3'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Written before the graph of a cache to tell readers whether they can read it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheHeader {
+    format: u32,
+    producer: Version,
+    min_reader: Version,
+}
+
+impl CacheHeader {
+    /// A header for caches written by `producer`, which can be read by `min_reader` or newer
+    /// if given. A warning is printed if `min_reader` is newer than what the format requires.
+    pub fn new(producer: Version, min_reader: Option<Version>) -> CacheHeader {
+        if let Some(min_reader) = min_reader {
+            if min_reader > CACHE_FORMAT_MIN_READER {
+                eprintln!(
+                    "WARNING: --min-reader-version {} is higher than the {} required to read cache format {}",
+                    min_reader, CACHE_FORMAT_MIN_READER, CACHE_FORMAT
+                );
+            }
+        }
+        CacheHeader {
+            format: CACHE_FORMAT,
+            producer,
+            min_reader: min_reader
+                .map_or(CACHE_FORMAT_MIN_READER, |v| v.max(CACHE_FORMAT_MIN_READER)),
+        }
+    }
+    /// The header claiming the cache to be of `format`, which is for testing only.
+    pub fn with_format(self, format: u32) -> CacheHeader {
+        CacheHeader { format, ..self }
+    }
+    pub fn write(&self, out: impl io::Write) -> Result<(), Error> {
+        serialize_into(out, self).map_err(Into::into)
+    }
+    pub fn read(input: impl io::Read) -> Result<CacheHeader, Error> {
+        deserialize_from(input).map_err(Into::into)
+    }
+    /// Fail with a message naming the version needed if `running` can't read this cache.
+    /// Caches of newer major versions are assumed to be incompatible. Caches of older formats
+    /// can't be read by any version, and have to be rebuilt.
+    pub fn check(&self, path: &Path, running: Version, update_hint: &str) -> Result<(), Error> {
+        if self.format < CACHE_FORMAT {
+            bail!(
+                "Cache format {} is no longer supported, this version reads format {}. Delete or rebuild the cache at '{}'.",
+                self.format,
+                CACHE_FORMAT,
+                path.display()
+            )
+        }
+        if self.format != CACHE_FORMAT || self.producer.0 > running.0 || self.min_reader > running {
+            bail!(
+                "The cache was written by version {} and needs at least version {} to be read, but this is version {}. {}",
+                self.producer,
+                self.min_reader.max(Version(self.producer.0, 0, 0)),
+                running,
+                update_hint
+            )
+        }
+        if self.producer > running {
+            eprintln!(
+                "NOTE: The cache was written by the newer version {}",
+                self.producer
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Everything stored in a cache after the graph, which is versioned by `CACHE_FORMAT` along
+/// with it. It is read in one piece, so any part which can't be decoded fails the load.
+#[derive(Deserialize, Serialize)]
+pub struct CacheTrailer {
+    /// The object directories and the traversal the graph was built from.
+    pub object_directories: Vec<String>,
+    pub traversal: String,
+    forward_edges: Option<Vec<Vec<u32>>>,
+    dangling_commits: Vec<usize>,
+    repository_labels: Vec<String>,
+    commit_repositories: Vec<(usize, usize)>,
+    submodule_paths: Vec<String>,
+    submodule_blobs: Vec<(usize, usize)>,
+    pub pathspecs: Vec<String>,
+    oversized_blobs: Vec<Sha1>,
+    stash_labels: Vec<(usize, String)>,
+    staged_blobs: Vec<Sha1>,
+    counters: BuildCounters,
+}
+
+impl CacheTrailer {
+    /// The parts of `graph` which aren't stored with `into_storage`, taking its forward edges.
+    pub fn take_from(
+        graph: &mut ReverseGraph,
+        object_directories: Vec<String>,
+        traversal: String,
+    ) -> CacheTrailer {
+        let (repository_labels, commit_repositories) = graph.commit_repositories();
+        let (submodule_paths, submodule_blobs) = graph.submodule_blobs();
+        CacheTrailer {
+            object_directories,
+            traversal,
+            forward_edges: graph.take_forward_edges(),
+            dangling_commits: graph.dangling_commits(),
+            repository_labels,
+            commit_repositories,
+            submodule_paths,
+            submodule_blobs,
+            pathspecs: graph.pathspecs().to_vec(),
+            oversized_blobs: graph.oversized_blobs(),
+            stash_labels: graph.stash_labels(),
+            staged_blobs: graph.staged_blobs(),
+            counters: graph.build_counters(),
+        }
+    }
+    pub fn write(&self, out: impl io::Write) -> Result<(), Error> {
+        serialize_into(out, self).map_err(Into::into)
+    }
+    pub fn read(input: impl io::Read) -> Result<CacheTrailer, Error> {
+        deserialize_from(input).map_err(Into::into)
+    }
+    /// Put the parts back into `graph`, failing if they don't fit it.
+    pub fn restore(self, graph: &mut ReverseGraph) -> Result<(), Error> {
+        graph.set_forward_edges(self.forward_edges)?;
+        graph.set_dangling_commits(self.dangling_commits)?;
+        graph.set_commit_repositories(self.repository_labels, self.commit_repositories)?;
+        graph.set_submodule_blobs(self.submodule_paths, self.submodule_blobs)?;
+        graph.set_pathspecs(self.pathspecs);
+        graph.set_oversized_blobs(self.oversized_blobs);
+        graph.set_stash_labels(self.stash_labels)?;
+        graph.set_staged_blobs(self.staged_blobs);
+        graph.set_build_counters(self.counters);
+        Ok(())
+    }
 }
 
+/// An object id as stored in caches.
 #[derive(Deserialize, Serialize)]
-struct Sha1([u8; 20]);
+pub struct Sha1([u8; 20]);
 
 impl From<Oid> for Sha1 {
     fn from(f: Oid) -> Self {
@@ -37,8 +728,9 @@ impl From<Sha1> for Oid {
 #[derive(Default, Deserialize, Serialize)]
 pub struct StorableReverseGraph {
     vertices_to_oid: Vec<Sha1>,
-    vertices_to_edges: Vec<Vec<usize>>,
-    oids_to_vertices: Vec<(Sha1, usize)>,
+    vertices_to_kind: Vec<Kind>,
+    edges: Edges,
+    vertices_by_oid: Vec<u32>,
 }
 
 impl StorableReverseGraph {
@@ -51,18 +743,62 @@ impl StorableReverseGraph {
         eprintln!("Loading graph...");
         deserialize_from(input).map_err(Into::into)
     }
-    pub fn into_memory(self) -> ReverseGraph {
-        ReverseGraph {
-            vertices_to_oid: self.vertices_to_oid.into_iter().map(Into::into).collect(),
-            vertices_to_edges: self.vertices_to_edges,
-            oids_to_vertices: self.oids_to_vertices.into_iter().fold(
-                BTreeMap::new(),
-                |mut acc, (oid, vtx)| {
-                    acc.insert(oid.into(), vtx);
-                    acc
-                },
-            ),
+    /// The graph in memory, after checking that its parts fit together, as they may come from
+    /// a corrupt cache.
+    pub fn into_memory(self) -> Result<ReverseGraph, Error> {
+        let num_vertices = self.vertices_to_oid.len();
+        if self.vertices_to_kind.len() != num_vertices {
+            bail!(
+                "The kinds of {} vertices were loaded for a graph of {} vertices",
+                self.vertices_to_kind.len(),
+                num_vertices
+            )
+        }
+        self.edges.validate(num_vertices)?;
+        // `position_of` reads object ids at these indices without checking bounds.
+        let mut seen = vec![false; num_vertices];
+        for &idx in &self.vertices_by_oid {
+            match seen.get_mut(idx as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => bail!("The vertices ordered by object id refer to vertex {} more than once or beyond the graph", idx),
+            }
+        }
+        if self.vertices_by_oid.len() != num_vertices {
+            bail!(
+                "{} of {} vertices are ordered by object id",
+                self.vertices_by_oid.len(),
+                num_vertices
+            )
         }
+        Ok(ReverseGraph {
+            id: NEXT_GRAPH_ID.fetch_add(1, Ordering::Relaxed),
+            vertices_to_oid: self.vertices_to_oid.into_iter().map(Into::into).collect(),
+            vertices_to_kind: self.vertices_to_kind,
+            vertices_to_parents: Vec::new(),
+            edges: self.edges,
+            oids_to_vertices: VertexTable::default(),
+            vertices_by_oid: self.vertices_by_oid,
+            num_skipped_entries: 0,
+            num_missing_trees: 0,
+            num_missing_commits: 0,
+            num_fetched_trees: 0,
+            num_duplicate_edges: 0,
+            num_truncated_trees: 0,
+            num_entries_beyond_limit: 0,
+            max_tree_entries: None,
+            commits_capped_at: None,
+            vertices_to_children: None,
+            dangling_commits: HashSet::new(),
+            commits_to_repository: HashMap::new(),
+            repository_labels: Vec::new(),
+            submodule_paths: Vec::new(),
+            blobs_to_submodule: HashMap::new(),
+            num_missing_submodule_commits: 0,
+            pathspecs: Vec::new(),
+            oversized_blobs: HashSet::new(),
+            stash_labels: HashMap::new(),
+            staged_blobs: HashSet::new(),
+        })
     }
 }
 
@@ -71,14 +807,37 @@ impl ReverseGraph {
     pub fn oid_of(&self, idx: usize) -> Oid {
         self.vertices_to_oid[idx]
     }
+    #[inline]
+    pub fn kind_of(&self, idx: usize) -> Kind {
+        self.vertices_to_kind[idx]
+    }
+    /// The amount of direct parents of the vertex at `idx`.
+    #[inline]
+    pub fn edge_count(&self, idx: usize) -> usize {
+        self.parents_of(idx).len()
+    }
+    /// The direct parents of the vertex at `idx`, which must be a vertex of the built graph.
+    #[inline]
+    fn parents_of(&self, idx: usize) -> &[u32] {
+        debug_assert!(idx < self.edges.len());
+        self.edges.of(idx)
+    }
+    /// The index of the vertex with the most direct parents and their amount, or None if the
+    /// graph is empty. The first such vertex is returned if there are multiple.
+    pub fn max_edges(&self) -> Option<(usize, usize)> {
+        (0..self.edges.len())
+            .map(|idx| (idx, self.edge_count(idx)))
+            .fold(None, |max, (idx, count)| match max {
+                Some((_, max_count)) if max_count >= count => max,
+                _ => Some((idx, count)),
+            })
+    }
     pub fn into_storage(self) -> StorableReverseGraph {
         StorableReverseGraph {
             vertices_to_oid: self.vertices_to_oid.into_iter().map(Into::into).collect(),
-            vertices_to_edges: self.vertices_to_edges,
-            oids_to_vertices: self.oids_to_vertices
-                .into_iter()
-                .map(|(oid, vtx)| (oid.into(), vtx))
-                .collect(),
+            vertices_to_kind: self.vertices_to_kind,
+            edges: self.edges,
+            vertices_by_oid: self.vertices_by_oid,
         }
     }
     fn optimize_topology(&mut self) -> Option<(usize, usize)> {
@@ -103,11 +862,12 @@ impl ReverseGraph {
         let mut parents_to_adjust = Vec::new();
 
         for vtx in 0..self.len() {
-            let edges = &self.vertices_to_edges[vtx];
+            let edges = &self.vertices_to_parents[vtx];
             if edges.len() == 1 {
-                let parent_vtx = edges[0];
-                let parent_edges = &self.vertices_to_edges[parent_vtx];
-                if parent_edges.len() == 2 {
+                let parent_vtx = edges[0] as usize;
+                let parent_edges = &self.vertices_to_parents[parent_vtx];
+                // Commits with parents are gitlinks of the superproject, and must not be skipped.
+                if parent_edges.len() == 2 && self.vertices_to_kind[parent_vtx] != Kind::Commit {
                     parents_to_adjust.push((vtx, parent_vtx));
                 }
             }
@@ -115,173 +875,2515 @@ impl ReverseGraph {
 
         let removed = parents_to_adjust.len();
         for (child, parent_to_skip) in parents_to_adjust {
-            let parent_edges = self.vertices_to_edges[parent_to_skip].clone();
-            self.vertices_to_edges[child] = parent_edges;
+            let parent_edges = self.vertices_to_parents[parent_to_skip].clone();
+            self.vertices_to_parents[child] = parent_edges;
         }
 
         removed
     }
-    fn compact(&mut self) {
-        for mut edges in &mut self.vertices_to_edges {
-            edges.shrink_to_fit();
+    fn add_forward_edges(&mut self) {
+        let mut children = vec![Vec::new(); self.len()];
+        for (child, parents) in self.vertices_to_parents.iter().enumerate() {
+            for &parent in parents {
+                children[parent as usize].push(child as u32);
+            }
+        }
+        self.vertices_to_children = Some(children);
+    }
+    /// Remove the forward edges, to store them separately from the rest of the graph.
+    pub fn take_forward_edges(&mut self) -> Option<Vec<Vec<u32>>> {
+        self.vertices_to_children.take()
+    }
+    pub fn set_forward_edges(&mut self, children: Option<Vec<Vec<u32>>>) -> Result<(), Error> {
+        if let Some(ref children) = children {
+            if children.len() != self.len() {
+                bail!(
+                    "Forward edges of {} vertices don't belong to a graph with {} vertices",
+                    children.len(),
+                    self.len()
+                )
+            }
+        }
+        self.vertices_to_children = children;
+        Ok(())
+    }
+    /// Move the parents of all vertices into a single allocation using `num_threads` threads and
+    /// drop the object ids keying the map of vertices in favor of vertices ordered by object id,
+    /// which ends the build. Returns a summary of the memory reclaimed from parent-edges.
+    fn compact(&mut self, num_threads: usize) -> Result<String, Error> {
+        self.num_duplicate_edges += dedup_parents(
+            &mut self.vertices_to_parents,
+            &self.vertices_to_kind,
+            num_threads,
+        );
+        let lists_bytes = self.vertices_to_parents.capacity() * mem::size_of::<Vec<u32>>()
+            + self.vertices_to_parents
+                .iter()
+                .map(|parents| parents.capacity() * mem::size_of::<u32>())
+                .sum::<usize>();
+        self.edges = Edges::from_lists(
+            mem::replace(&mut self.vertices_to_parents, Vec::new()),
+            num_threads,
+        )?;
+        self.id = NEXT_GRAPH_ID.fetch_add(1, Ordering::Relaxed);
+        let edges_bytes = self.edges.used_bytes();
+        self.oids_to_vertices = VertexTable::default();
+        // All indices were checked with `vertex_index` when their vertex was added.
+        let mut vertices_by_oid: Vec<u32> = (0..self.len() as u32).collect();
+        {
+            let oids = &self.vertices_to_oid;
+            vertices_by_oid.sort_unstable_by(|&a, &b| oids[a as usize].cmp(&oids[b as usize]));
         }
+        self.vertices_by_oid = vertices_by_oid;
+        Ok(format!(
+            "parent-edges compacted from {} to {}, reclaiming {}",
+            memory::format_bytes(lists_bytes as u64),
+            memory::format_bytes(edges_bytes as u64),
+            memory::format_bytes(lists_bytes.saturating_sub(edges_bytes) as u64)
+        ))
     }
-    fn append(&mut self, oid: Oid) -> usize {
+    fn append(&mut self, oid: Oid) -> Result<usize, Error> {
+        if let Some(idx) = self.vertex_of(&oid) {
+            // A gitlink seen earlier is a commit of the graph after all. The superproject trees
+            // stay its parents, so lookups pass through it to the commits of the superproject.
+            self.vertices_to_kind[idx] = Kind::Commit;
+            return Ok(idx);
+        }
         let idx = self.vertices_to_oid.len();
+        let stored_idx = vertex_index(idx)?;
         self.vertices_to_oid.push(oid.clone());
-        self.oids_to_vertices.insert(oid, idx);
-        self.vertices_to_edges.push(Vec::new());
-        idx
+        self.oids_to_vertices.insert(stored_idx, &self.vertices_to_oid);
+        self.vertices_to_kind.push(Kind::Commit);
+        self.vertices_to_parents.push(Vec::new());
+        Ok(idx)
     }
-    fn insert_parent_get_new_child_id(&mut self, parent: usize, child: Oid) -> Option<usize> {
-        match self.oids_to_vertices.entry(child) {
-            Entry::Occupied(entry) => {
-                unsafe { self.vertices_to_edges.get_unchecked_mut(*entry.get()) }.push(parent);
-                None
+    fn insert_parent_get_new_child_id(
+        &mut self,
+        parent: usize,
+        child: Oid,
+        kind: Kind,
+    ) -> Result<Option<usize>, Error> {
+        match self.oids_to_vertices.get(&child, &self.vertices_to_oid) {
+            Some(child_idx) => {
+                // Entries of a tree are inserted together, so a repeated parent is the last one.
+                let parents =
+                    unsafe { self.vertices_to_parents.get_unchecked_mut(child_idx as usize) };
+                if parents.last() == Some(&(parent as u32)) {
+                    if kind != Kind::Tree {
+                        self.num_duplicate_edges += 1;
+                    }
+                } else {
+                    parents.push(parent as u32);
+                }
+                Ok(None)
             }
-            Entry::Vacant(entry) => {
+            None => {
                 let child_idx = self.vertices_to_oid.len();
-                self.vertices_to_oid.push(entry.key().clone());
-                entry.insert(child_idx);
-                self.vertices_to_edges.push(vec![parent]);
-                Some(child_idx)
+                let stored_idx = vertex_index(child_idx)?;
+                self.vertices_to_oid.push(child);
+                self.vertices_to_kind.push(kind);
+                self.oids_to_vertices.insert(stored_idx, &self.vertices_to_oid);
+                self.vertices_to_parents.push(vec![parent as u32]);
+                Ok(Some(child_idx))
             }
         }
     }
-    pub fn len(&self) -> usize {
-        self.vertices_to_oid.len()
+    pub fn contains(&self, oid: &Oid) -> bool {
+        self.vertex_of(oid).is_some()
     }
-    // TODO: dedup
-    pub fn lookup_idx(&self, blob: &Oid, stack: &mut Stack, out: &mut Vec<usize>) {
-        out.clear();
-        match self.oids_to_vertices.get(blob) {
-            None => {}
-            Some(idx) => {
-                let indices_to_traverse = &mut stack.indices;
-                indices_to_traverse.clear();
-                indices_to_traverse.extend(unsafe { self.vertices_to_edges.get_unchecked(*idx) });
-                while let Some(idx) = indices_to_traverse.pop() {
-                    let parent_indices = unsafe { self.vertices_to_edges.get_unchecked(idx) };
-                    if parent_indices.is_empty() {
-                        out.push(idx);
-                    } else {
-                        indices_to_traverse.extend(parent_indices)
-                    }
-                }
-            }
+    /// True if `oid` is indexed as commit, not just known as the commit of a gitlink.
+    fn contains_commit(&self, oid: &Oid) -> bool {
+        self.kind_of_oid(oid) == Some(Kind::Commit)
+    }
+    #[inline]
+    fn vertex_of(&self, oid: &Oid) -> Option<usize> {
+        if !self.oids_to_vertices.is_empty() {
+            return self.oids_to_vertices
+                .get(oid, &self.vertices_to_oid)
+                .map(|idx| idx as usize);
         }
+        self.position_of(oid)
+            .ok()
+            .map(|pos| self.vertices_by_oid[pos] as usize)
     }
-    pub fn lookup(&self, blob: &Oid, stack: &mut Stack, out: &mut Vec<Oid>) {
-        out.clear();
-        match self.oids_to_vertices.get(blob) {
-            None => {}
-            Some(idx) => {
-                let indices_to_traverse = &mut stack.indices;
-                indices_to_traverse.clear();
-                indices_to_traverse.extend(unsafe { self.vertices_to_edges.get_unchecked(*idx) });
-                while let Some(idx) = indices_to_traverse.pop() {
-                    let parent_indices = unsafe { self.vertices_to_edges.get_unchecked(idx) };
-                    if parent_indices.is_empty() {
-                        out.push(unsafe { *self.vertices_to_oid.get_unchecked(idx) });
-                    } else {
-                        indices_to_traverse.extend(parent_indices)
-                    }
-                }
-            }
+    /// The position of `oid` in `vertices_by_oid`, or where it would have to be inserted.
+    fn position_of(&self, oid: &Oid) -> Result<usize, usize> {
+        self.vertices_by_oid.binary_search_by(|&idx| {
+            unsafe { self.vertices_to_oid.get_unchecked(idx as usize) }.cmp(oid)
+        })
+    }
+    /// The memory used by object ids, along with what storing them only once saves compared to
+    /// also keying a map with them.
+    fn object_id_memory(&self) -> String {
+        let stored = self.len() * (mem::size_of::<Oid>() + mem::size_of::<u32>());
+        let saved = self.len() * mem::size_of::<Oid>();
+        format!(
+            "object ids use {}, saving at least {}",
+            memory::format_bytes(stored as u64),
+            memory::format_bytes(saved as u64)
+        )
+    }
+    /// True if `commit` was indexed with --include-dangling without being reachable from any ref.
+    pub fn is_dangling(&self, commit: &Oid) -> bool {
+        !self.dangling_commits.is_empty()
+            && self.vertex_of(commit)
+                .map_or(false, |idx| self.dangling_commits.contains(&idx))
+    }
+    /// The label like 'stash@{2}' or 'stash@{2}^2' of `commit` if it belongs to a stash entry.
+    pub fn stash_label(&self, commit: &Oid) -> Option<&str> {
+        if self.stash_labels.is_empty() {
+            return None;
         }
+        self.vertex_of(commit)
+            .and_then(|idx| self.stash_labels.get(&idx))
+            .map(String::as_str)
     }
-}
-
-pub fn build(opts: &Options) -> Result<ReverseGraph, Error> {
-    let repo = Repository::open(&opts.repository)?;
-
-    let mut walk = repo.revwalk()?;
-    walk.set_sorting(git2::Sort::TOPOLOGICAL);
-    setup_walk(&repo, &mut walk, opts.head_only)?;
-
-    let progress = ProgressBar::new_spinner();
-    let mut graph = ReverseGraph::default();
-    let (mut num_commits, mut edges_total) = (0, 0);
-
-    for commit_oid in walk.filter_map(Result::ok) {
-        num_commits += 1;
-        if let Ok(object) = repo.find_object(commit_oid, Some(ObjectType::Commit)) {
-            let commit = object.into_commit().expect("to have commit");
-            let tree = commit.tree().expect("commit to have tree");
-            let commit_idx = graph.append(commit_oid);
-            if let Some(tree_idx) = graph.insert_parent_get_new_child_id(commit_idx, tree.id()) {
-                edges_total += recurse_tree(&repo, tree, tree_idx, &mut graph);
-            }
+    /// The index of the repository `commit` was found in, 0 for REPOSITORY and 1 for the first
+    /// one given with --repository, or None if it isn't known.
+    pub fn repository_of(&self, commit: &Oid) -> Option<usize> {
+        self.vertex_of(commit)
+            .map(|idx| self.commits_to_repository.get(&idx).cloned().unwrap_or(0))
+    }
+    /// The name of the repository at `repository` as returned by `repository_of`, if commits
+    /// were indexed from multiple repositories.
+    pub fn repository_label(&self, repository: usize) -> Option<&str> {
+        self.repository_labels.get(repository).map(String::as_str)
+    }
+    /// The labels of all repositories, along with the commits found in other repositories than
+    /// REPOSITORY and the index of their repository in ascending order, to store them along
+    /// with the graph.
+    pub fn commit_repositories(&self) -> (Vec<String>, Vec<(usize, usize)>) {
+        let mut commits: Vec<_> = self.commits_to_repository
+            .iter()
+            .map(|(&idx, &repository)| (idx, repository))
+            .collect();
+        commits.sort();
+        (self.repository_labels.clone(), commits)
+    }
+    pub fn set_commit_repositories(
+        &mut self,
+        labels: Vec<String>,
+        commits: Vec<(usize, usize)>,
+    ) -> Result<(), Error> {
+        if let Some(&(idx, _)) = commits
+            .iter()
+            .find(|&&(idx, _)| idx >= self.len() || self.vertices_to_kind[idx] != Kind::Commit)
+        {
+            bail!("Vertex {} of another repository is not a commit of the graph", idx)
         }
-        if num_commits % COMMIT_PROGRESS_RATE == 0 {
-            progress.set_message(&format!(
-                "{} Commits done; reverse-tree with {} entries and a total of {} parent-edges",
-                num_commits,
-                graph.len(),
-                edges_total
-            ));
-            progress.tick();
+        if let Some(&(_, repository)) = commits
+            .iter()
+            .find(|&&(_, repository)| repository >= labels.len())
+        {
+            bail!("Repository {} of a commit has no label", repository)
         }
+        self.repository_labels = labels;
+        self.commits_to_repository = commits.into_iter().collect();
+        Ok(())
     }
-    if let Some((passes, edges_removed)) = graph.optimize_topology() {
-        eprintln!("Removed {} edges in {} passes", edges_removed, passes);
-        edges_total -= edges_removed;
+    /// The path of the submodule `blob` was first found in, or None if it wasn't in a submodule.
+    pub fn submodule_of(&self, blob: &Oid) -> Option<&str> {
+        self.vertex_of(blob)
+            .and_then(|idx| self.blobs_to_submodule.get(&idx))
+            .map(|&submodule| self.submodule_paths[submodule].as_str())
     }
-    graph.compact();
-    progress.finish_and_clear();
-
-    eprintln!(
-        "READY: Build reverse-tree from {} commits with graph with {} vertices and {} parent-edges",
-        num_commits,
-        graph.len(),
-        edges_total
-    );
-    Ok(graph)
-}
-
-fn recurse_tree(repo: &Repository, tree: Tree, tree_idx: usize, state: &mut ReverseGraph) -> usize {
-    use ObjectType::*;
-    let mut refs = 0;
-    for item in tree.iter() {
-        match item.kind() {
-            Some(Tree) => {
-                if let Some(item_idx) = state.insert_parent_get_new_child_id(tree_idx, item.id()) {
-                    refs += recurse_tree(
-                        repo,
-                        item.to_object(repo)
-                            .expect("valid object")
-                            .into_tree()
-                            .expect("tree"),
-                        item_idx,
-                        state,
-                    )
-                }
-            }
-            Some(Blob) => {
-                refs += 1;
-                state.insert_parent_get_new_child_id(tree_idx, item.id());
-            }
-            _ => continue,
+    /// The paths of submodules and the blobs found in them in ascending order, to store them
+    /// along with the graph.
+    pub fn submodule_blobs(&self) -> (Vec<String>, Vec<(usize, usize)>) {
+        let mut blobs: Vec<_> = self.blobs_to_submodule
+            .iter()
+            .map(|(&idx, &submodule)| (idx, submodule))
+            .collect();
+        blobs.sort();
+        (self.submodule_paths.clone(), blobs)
+    }
+    pub fn set_submodule_blobs(
+        &mut self,
+        paths: Vec<String>,
+        blobs: Vec<(usize, usize)>,
+    ) -> Result<(), Error> {
+        if let Some(&(idx, submodule)) = blobs.iter().find(|&&(idx, submodule)| {
+            idx >= self.len() || self.vertices_to_kind[idx] != Kind::Blob || submodule >= paths.len()
+        }) {
+            bail!("Vertex {} of submodule {} is not a blob of the graph", idx, submodule)
         }
+        self.submodule_paths = paths;
+        self.blobs_to_submodule = blobs.into_iter().collect();
+        Ok(())
     }
-    refs
-}
-
-fn setup_walk(repo: &Repository, walk: &mut Revwalk, head_only: bool) -> Result<(), Error> {
-    if head_only {
-        walk.push_head()?;
-    } else {
-        let mut refs_pushed = 0;
-        for remote_head in repo.branches(Some(git2::BranchType::Remote))?
-            .filter_map(|b| b.map(|(b, _bt)| b).ok().and_then(|b| b.get().target()))
+    /// The pathspecs blobs were restricted to with --path, or none if all blobs were indexed.
+    pub fn pathspecs(&self) -> &[String] {
+        &self.pathspecs
+    }
+    pub fn set_pathspecs(&mut self, pathspecs: Vec<String>) {
+        self.pathspecs = pathspecs;
+    }
+    /// True if `blob` wasn't indexed as it is larger than --max-blob-size.
+    pub fn is_oversized(&self, blob: &Oid) -> bool {
+        self.oversized_blobs.contains(blob)
+    }
+    /// The blobs skipped for their size in ascending order, to store them along with the graph.
+    pub fn oversized_blobs(&self) -> Vec<Sha1> {
+        let mut blobs: Vec<_> = self.oversized_blobs.iter().cloned().collect();
+        blobs.sort();
+        blobs.into_iter().map(Sha1::from).collect()
+    }
+    pub fn set_oversized_blobs(&mut self, blobs: Vec<Sha1>) {
+        self.oversized_blobs = blobs.into_iter().map(Oid::from).collect();
+    }
+    pub fn is_staged(&self, blob: &Oid) -> bool {
+        self.staged_blobs.contains(blob)
+    }
+    /// The blobs staged in the index in ascending order, to store them along with the graph.
+    pub fn staged_blobs(&self) -> Vec<Sha1> {
+        let mut blobs: Vec<_> = self.staged_blobs.iter().cloned().collect();
+        blobs.sort();
+        blobs.into_iter().map(Sha1::from).collect()
+    }
+    pub fn set_staged_blobs(&mut self, blobs: Vec<Sha1>) {
+        self.staged_blobs = blobs.into_iter().map(Oid::from).collect();
+    }
+    /// The labels of stash commits by their index in ascending order, to store them along with
+    /// the graph.
+    pub fn stash_labels(&self) -> Vec<(usize, String)> {
+        let mut labels: Vec<_> = self.stash_labels
+            .iter()
+            .map(|(&idx, label)| (idx, label.clone()))
+            .collect();
+        labels.sort();
+        labels
+    }
+    pub fn set_stash_labels(&mut self, labels: Vec<(usize, String)>) -> Result<(), Error> {
+        if let Some(&(idx, _)) = labels
+            .iter()
+            .find(|&&(idx, _)| idx >= self.len() || self.vertices_to_kind[idx] != Kind::Commit)
         {
-            walk.push(remote_head)?;
-            refs_pushed += 1;
+            bail!("Stash commit {} is not a commit of the graph", idx)
         }
-        if refs_pushed == 0 {
-            eprintln!("Didn't find a single remote - using head instead to avoid empty traversal");
-            walk.push_head()?;
+        self.stash_labels = labels.into_iter().collect();
+        Ok(())
+    }
+    pub fn build_counters(&self) -> BuildCounters {
+        BuildCounters {
+            num_skipped_entries: self.num_skipped_entries,
+            num_missing_trees: self.num_missing_trees,
+            num_missing_commits: self.num_missing_commits,
+            num_truncated_trees: self.num_truncated_trees,
+            num_entries_beyond_limit: self.num_entries_beyond_limit,
+            max_tree_entries: self.max_tree_entries,
+            commits_capped_at: self.commits_capped_at,
+            num_missing_submodule_commits: self.num_missing_submodule_commits,
         }
     }
-    Ok(())
+    pub fn set_build_counters(&mut self, counters: BuildCounters) {
+        self.num_skipped_entries = counters.num_skipped_entries;
+        self.num_missing_trees = counters.num_missing_trees;
+        self.num_missing_commits = counters.num_missing_commits;
+        self.num_truncated_trees = counters.num_truncated_trees;
+        self.num_entries_beyond_limit = counters.num_entries_beyond_limit;
+        self.max_tree_entries = counters.max_tree_entries;
+        self.commits_capped_at = counters.commits_capped_at;
+        self.num_missing_submodule_commits = counters.num_missing_submodule_commits;
+    }
+    /// The indices of dangling commits in ascending order, to store them along with the graph.
+    pub fn dangling_commits(&self) -> Vec<usize> {
+        let mut dangling: Vec<_> = self.dangling_commits.iter().cloned().collect();
+        dangling.sort();
+        dangling
+    }
+    pub fn set_dangling_commits(&mut self, dangling: Vec<usize>) -> Result<(), Error> {
+        if let Some(&idx) = dangling
+            .iter()
+            .find(|&&idx| idx >= self.len() || self.vertices_to_kind[idx] != Kind::Commit)
+        {
+            bail!("Dangling commit {} is not a commit of the graph", idx)
+        }
+        self.dangling_commits = dangling.into_iter().collect();
+        Ok(())
+    }
+    /// The only object whose hex-sha starts with `prefix`, or None if there is no such object.
+    /// It's an error if the prefix is ambiguous.
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<Option<Oid>, Error> {
+        let prefix = prefix.to_lowercase();
+        let start = Oid::from_str(&prefix)?;
+        let pos = match self.position_of(&start) {
+            Ok(pos) | Err(pos) => pos,
+        };
+        let mut matches = self.vertices_by_oid[pos..]
+            .iter()
+            .map(|&idx| self.vertices_to_oid[idx as usize])
+            .take_while(|oid| oid.to_string().starts_with(&prefix));
+        match (matches.next(), matches.next()) {
+            (Some(_), Some(_)) => bail!("Object id prefix '{}' is ambiguous", prefix),
+            (first, _) => Ok(first),
+        }
+    }
+    /// Append the blobs sharing a tree with `blob` to `out`, unless they are in `out` already,
+    /// until it holds `max` blobs. Requires the graph to be built with forward edges.
+    pub fn siblings(&self, blob: &Oid, max: usize, out: &mut Vec<Oid>) -> Result<(), Error> {
+        let children = match self.vertices_to_children {
+            Some(ref children) => children,
+            None => bail!(
+                "Siblings can only be found in graphs built with --with-forward-edges. \
+                 Caches built without it need to be rebuilt."
+            ),
+        };
+        let idx = match self.vertex_of(blob) {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+        let mut seen: HashSet<Oid> = out.iter().cloned().collect();
+        for &tree in self.parents_of(idx) {
+            let tree = tree as usize;
+            if self.vertices_to_kind[tree] != Kind::Tree {
+                continue;
+            }
+            for &sibling in &children[tree] {
+                let sibling = sibling as usize;
+                if out.len() >= max {
+                    return Ok(());
+                }
+                let oid = self.vertices_to_oid[sibling];
+                if sibling != idx && self.vertices_to_kind[sibling] == Kind::Blob
+                    && seen.insert(oid)
+                {
+                    out.push(oid);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn num_commits(&self) -> usize {
+        self.vertices_to_kind
+            .iter()
+            .filter(|&&kind| kind == Kind::Commit)
+            .count()
+    }
+    /// What the graph lacks due to problems or limits while building it.
+    pub fn caveats(&self) -> Vec<String> {
+        let mut caveats = Vec::new();
+        if self.num_skipped_entries > 0 {
+            caveats.push(format!(
+                "skipped {} tree entries of unknown kind",
+                self.num_skipped_entries
+            ));
+        }
+        if self.num_missing_trees > 0 {
+            caveats.push(format!("{} trees could not be read", self.num_missing_trees));
+        }
+        if self.num_missing_commits > 0 {
+            caveats.push(format!("{} commits could not be read", self.num_missing_commits));
+        }
+        if let Some(max_commits) = self.commits_capped_at {
+            caveats.push(format!(
+                "capped at the {} most recent commits, older ones are missing",
+                max_commits
+            ));
+        }
+        if self.num_truncated_trees > 0 {
+            caveats.push(format!(
+                "skipped {} entries of {} trees with more than {} entries",
+                self.num_entries_beyond_limit,
+                self.num_truncated_trees,
+                self.max_tree_entries.unwrap_or(0)
+            ));
+        }
+        if !self.pathspecs.is_empty() {
+            caveats.push(format!(
+                "only blobs at paths matching {:?} are indexed",
+                self.pathspecs
+            ));
+        }
+        if !self.oversized_blobs.is_empty() {
+            caveats.push(format!(
+                "skipped {} blobs larger than --max-blob-size",
+                self.oversized_blobs.len()
+            ));
+        }
+        if self.num_missing_submodule_commits > 0 {
+            caveats.push(format!(
+                "skipped {} gitlinks whose commit is in no initialized submodule",
+                self.num_missing_submodule_commits
+            ));
+        }
+        caveats
+    }
+    /// The kind of the object `oid` if it is known.
+    pub fn kind_of_oid(&self, oid: &Oid) -> Option<Kind> {
+        self.vertex_of(oid).map(|idx| self.vertices_to_kind[idx])
+    }
+    /// The amount of direct parents, i.e. trees or commits, of the object `oid` if it is known.
+    pub fn num_parents(&self, oid: &Oid) -> Option<usize> {
+        self.vertex_of(oid).map(|idx| self.edge_count(idx))
+    }
+    pub fn len(&self) -> usize {
+        self.vertices_to_oid.len()
+    }
+    /// All commits reachable from the vertex at `from`, using the given scratch space.
+    fn commits_reachable_from(
+        &self,
+        from: usize,
+        indices: &mut Vec<usize>,
+        visited: &mut FixedBitSet,
+        visited_indices: &mut Vec<usize>,
+    ) -> Vec<usize> {
+        for idx in visited_indices.drain(..) {
+            visited.set(idx, false);
+        }
+        if visited.len() < self.len() {
+            visited.grow(self.len());
+        }
+        let mut commits = Vec::new();
+        indices.clear();
+        indices.push(from);
+        while let Some(idx) = indices.pop() {
+            let parent_indices = self.parents_of(idx);
+            if parent_indices.is_empty() || self.vertices_to_kind[idx] == Kind::Commit {
+                commits.push(idx);
+            }
+            for &parent in parent_indices {
+                let parent = parent as usize;
+                if !visited.contains(parent) {
+                    visited.insert(parent);
+                    visited_indices.push(parent);
+                    indices.push(parent);
+                }
+            }
+        }
+        commits
+    }
+    /// Call `f` with the index of every commit containing `blob`, as soon as it is discovered.
+    /// Each vertex is visited only once, hence the traversal terminates even on broken graphs.
+    /// As safety valve, it fails on parents which can't exist in a well-formed graph: the
+    /// vertex it started from, a blob or a vertex out of range, as reached through a cycle.
+    pub fn lookup_streaming_idx(
+        &self,
+        blob: &Oid,
+        stack: &mut Stack,
+        mut f: impl FnMut(usize),
+    ) -> Result<(), Error> {
+        let start = match self.vertex_of(blob) {
+            None => return Ok(()),
+            Some(idx) => idx,
+        };
+        let Stack {
+            indices: ref mut indices_to_traverse,
+            ref mut visited,
+            ref mut visited_indices,
+            ref mut subtree_memo,
+        } = *stack;
+        for idx in visited_indices.drain(..) {
+            visited.set(idx, false);
+        }
+        if visited.len() < self.len() {
+            visited.grow(self.len());
+        }
+        indices_to_traverse.clear();
+        let mut last_visited = [start; LAST_VISITED_ON_CYCLE];
+        for &parent in self.parents_of(start) {
+            let parent = parent as usize;
+            if !visited.contains(parent) {
+                self.check_parent(blob, start, parent, &last_visited)?;
+                visited.insert(parent);
+                visited_indices.push(parent);
+                indices_to_traverse.push(parent);
+            }
+        }
+
+        let mut num_visited = 0;
+        while let Some(idx) = indices_to_traverse.pop() {
+            last_visited[num_visited % LAST_VISITED_ON_CYCLE] = idx;
+            num_visited += 1;
+            let parent_indices = self.parents_of(idx);
+            if parent_indices.is_empty() || self.vertices_to_kind[idx] == Kind::Commit {
+                f(idx);
+            }
+            if parent_indices.is_empty() {
+                continue;
+            }
+            if let Some(ref mut memo) = *subtree_memo {
+                if self.vertices_to_kind[idx] == Kind::Tree {
+                    if let Some(commits) = memo.commits_of(self, idx) {
+                        for &commit in commits {
+                            if !visited.contains(commit) {
+                                visited.insert(commit);
+                                visited_indices.push(commit);
+                                f(commit);
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+            for &parent in parent_indices {
+                let parent = parent as usize;
+                if !visited.contains(parent) {
+                    self.check_parent(blob, start, parent, &last_visited)?;
+                    visited.insert(parent);
+                    visited_indices.push(parent);
+                    indices_to_traverse.push(parent);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Fail with an internal invariant violation naming `blob` and the `last_visited` vertices
+    /// if `parent` can't be reached in a well-formed graph when starting at `start`.
+    fn check_parent(
+        &self,
+        blob: &Oid,
+        start: usize,
+        parent: usize,
+        last_visited: &[usize],
+    ) -> Result<(), Error> {
+        if parent != start && parent < self.len() && self.vertices_to_kind[parent] != Kind::Blob {
+            return Ok(());
+        }
+        bail!(
+            "Internal invariant violated: the lookup of blob {} reached {} as parent, which is impossible unless the graph has a cycle or is corrupt. Last visited were {}",
+            blob,
+            if parent < self.len() {
+                self.oid_of(parent).to_string()
+            } else {
+                format!("vertex {} of {}", parent, self.len())
+            },
+            last_visited
+                .iter()
+                .map(|&idx| self.oid_of(idx).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+    /// Call `f` with the id of every commit containing `blob`, as soon as it is discovered.
+    pub fn lookup_streaming(
+        &self,
+        blob: &Oid,
+        stack: &mut Stack,
+        mut f: impl FnMut(Oid),
+    ) -> Result<(), Error> {
+        self.lookup_streaming_idx(blob, stack, |idx| {
+            f(unsafe { *self.vertices_to_oid.get_unchecked(idx) })
+        })
+    }
+    pub fn lookup_idx(
+        &self,
+        blob: &Oid,
+        stack: &mut Stack,
+        out: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        out.clear();
+        self.lookup_streaming_idx(blob, stack, |idx| out.push(idx))
+    }
+    pub fn lookup(&self, blob: &Oid, stack: &mut Stack, out: &mut Vec<Oid>) -> Result<(), Error> {
+        out.clear();
+        self.lookup_streaming(blob, stack, |oid| out.push(oid))
+    }
+    /// Fail if the commits of `blob` found by `lookup_idx`, which may use the subtree cache of
+    /// `stack`, differ from the ones found by `lookup_plain`.
+    pub fn check_lookup_consistency(&self, blob: &Oid, stack: &mut Stack) -> Result<(), Error> {
+        let mut indices = Vec::new();
+        self.lookup_idx(blob, stack, &mut indices)?;
+        indices.sort();
+        let plain = self.lookup_plain(blob);
+        if indices != plain {
+            bail!(
+                "Lookups of blob {} disagree: {} commits found by the lookup, {} by a plain traversal",
+                blob,
+                indices.len(),
+                plain.len()
+            )
+        }
+        Ok(())
+    }
+    /// The sorted commits and vertices without parents reachable from `blob`, found by a traversal with its
+    /// own state and without any cache, to check `lookup_idx` against.
+    fn lookup_plain(&self, blob: &Oid) -> Vec<usize> {
+        let mut roots = Vec::new();
+        let idx = match self.vertex_of(blob) {
+            None => return roots,
+            Some(idx) => idx,
+        };
+        let mut seen = HashSet::new();
+        let mut to_visit: Vec<usize> = self.parents_of(idx).iter().map(|&p| p as usize).collect();
+        while let Some(idx) = to_visit.pop() {
+            if !seen.insert(idx) {
+                continue;
+            }
+            let parents = self.parents_of(idx);
+            if parents.is_empty() || self.vertices_to_kind[idx] == Kind::Commit {
+                roots.push(idx);
+            }
+            to_visit.extend(parents.iter().map(|&p| p as usize));
+        }
+        roots.sort();
+        roots
+    }
+    /// Make `blob` a parent of its own parents, closing a cycle through it, for testing only.
+    pub fn inject_cycle(&mut self, blob: &Oid) -> Result<(), Error> {
+        let idx = self.vertex_of(blob)
+            .ok_or_else(|| format_err!("Blob {} is not part of the graph", blob))?;
+        let mut lists: Vec<Vec<u32>> =
+            (0..self.edges.len()).map(|v| self.edges.of(v).to_vec()).collect();
+        for &parent in self.edges.of(idx) {
+            lists[parent as usize].push(vertex_index(idx)?);
+        }
+        self.edges = Edges::from_lists(lists, 1)?;
+        Ok(())
+    }
+}
+
+pub fn build(opts: &BuildOptions) -> Result<ReverseGraph, Error> {
+    let hang_at = match opts.test_hang_at {
+        Some(ref hex) => Some(Oid::from_str(hex)?),
+        None => None,
+    };
+    let watchdog = Watchdog::spawn(opts.hang_timeout, opts.hang_abort);
+    let heartbeat = watchdog.register("build", "commit");
+    if let Some(ref command) = opts.objects_from_batch {
+        if opts.since.is_some() || opts.until.is_some() {
+            bail!("--since and --until can't be used with --objects-from-batch")
+        }
+        if opts.max_blob_size.is_some() {
+            bail!("--max-blob-size can't be used with --objects-from-batch, which reads blobs entirely")
+        }
+        if opts.include_index {
+            bail!("--include-index can't be used with --objects-from-batch")
+        }
+        return build_from_batch(
+            command,
+            &read_tips(opts)?,
+            watchdog,
+            &heartbeat,
+            opts,
+        );
+    }
+    let repo = open_repository(&opts.repository)?;
+    check_alternates(&repo)?;
+    check_shallow(&repo, opts.allow_shallow)?;
+    let partial_clone = is_partial_clone(&repo);
+    if partial_clone {
+        eprintln!(
+            "The repository is a partial clone, objects which aren't available locally are {}",
+            if opts.fetch_missing {
+                "fetched on demand"
+            } else {
+                "skipped, use --fetch-missing to fetch them"
+            }
+        );
+    }
+    if opts.fsck_lite {
+        check_object_database(&repo, partial_clone)?;
+    }
+
+    let mut walk = Traversal::new(&repo, opts)?;
+    let mut refs_summary = setup_walk(&repo, &mut walk, opts)?;
+    let window = TimeWindow::from_options(opts)?;
+    let replacements = replace_refs(&repo, opts)?;
+    // Replacement commits are traversed for the history they graft, but indexed as the
+    // commits they replace.
+    let replacement_commits: HashSet<Oid> = replacements
+        .values()
+        .filter(|&&oid| repo.find_commit(oid).is_ok())
+        .cloned()
+        .collect();
+    for &commit in &replacement_commits {
+        walk.push(commit)?;
+    }
+    // The parents a replacement drops must not be indexed through the original commit either.
+    let grafted = if replacements.is_empty() {
+        None
+    } else {
+        Some(reachable_with_replacements(&repo, &walk, &replacements, &replacement_commits))
+    };
+    if !replacements.is_empty() {
+        refs_summary.push_str(&format!(", applied {} replace refs", replacements.len()));
+    }
+
+    let progress = ProgressBar::new_spinner();
+    let mut graph = ReverseGraph::default();
+    graph.pathspecs = pathspec::normalize(&opts.paths);
+    let (mut num_commits, mut edges_total) = (0, 0);
+
+    let submodules = if opts.recurse_submodules {
+        open_submodules(&repo, &mut graph)?
+    } else {
+        Vec::new()
+    };
+    let commit_graph = if opts.no_commit_graph {
+        None
+    } else {
+        CommitGraph::open(&repo.path().join("objects"))?
+    };
+    let mut num_trees_from_commit_graph = 0;
+    let commit_oids: Box<dyn Iterator<Item = Oid> + '_> =
+        match commit_graph_order(commit_graph.as_ref(), &walk, &replacements, opts)? {
+            Some(commits) => {
+                eprintln!("Enumerating {} commits using the commit-graph", commits.len());
+                Box::new(commits.into_iter())
+            }
+            None => Box::new(walk.revwalk.filter_map(Result::ok)),
+        };
+    let mut commit_oids = commit_oids
+        .filter(|oid| !replacement_commits.contains(oid))
+        .filter(|oid| grafted.as_ref().map_or(true, |g| g.contains(oid)))
+        .filter(|&oid| window.as_ref().map_or(true, |w| w.contains_commit(&repo, oid)));
+    let max_commits = opts.max_commits.unwrap_or(usize::max_value());
+    for commit_oid in commit_oids.by_ref().take(max_commits) {
+        num_commits += 1;
+        heartbeat.beat(commit_oid);
+        if hang_at == Some(commit_oid) {
+            thread::sleep(Duration::from_secs(watchdog::TEST_HANG_SECONDS));
+        }
+        let source_oid = replaced(&replacements, commit_oid);
+        let tree_id = match commit_graph.as_ref().and_then(|g| g.tree_of(&source_oid)) {
+            Some(tree_id) => {
+                num_trees_from_commit_graph += 1;
+                Some(tree_id)
+            }
+            None => match repo.find_commit(source_oid) {
+                Ok(commit) => Some(commit.tree_id()),
+                Err(_) => {
+                    graph.num_missing_commits += 1;
+                    None
+                }
+            },
+        };
+        if let Some(tree_id) = tree_id.map(|tree_id| replaced(&replacements, tree_id)) {
+            let mut walk =
+                TreeWalk::new(&progress, &heartbeat, commit_oid, opts).with_submodules(&submodules);
+            edges_total += index_commit(&repo, commit_oid, tree_id, &mut graph, &mut walk)?.1;
+        }
+        if num_commits % COMMIT_PROGRESS_RATE == 0 {
+            progress.set_message(&format!(
+                "{} Commits done; reverse-tree with {} entries and a total of {} parent-edges",
+                num_commits,
+                graph.len(),
+                edges_total
+            ));
+            progress.tick();
+        }
+    }
+    if opts.max_commits.is_some() && commit_oids.next().is_some() {
+        graph.commits_capped_at = opts.max_commits;
+    }
+    graph.repository_labels = repository_labels(opts);
+    for (repository_idx, path) in opts.other_repositories.iter().enumerate() {
+        let (repo_commits, repo_edges) = index_other_repository(
+            path,
+            repository_idx + 1,
+            &mut graph,
+            &progress,
+            &heartbeat,
+            window.as_ref(),
+            opts,
+        )?;
+        num_commits += repo_commits;
+        edges_total += repo_edges;
+    }
+    if let Some(ref window) = window {
+        if num_commits == 0 {
+            bail!("None of the traversed commits was committed {}", window)
+        }
+    }
+    if opts.include_stash {
+        // The most recent entry comes first, so commits shared by entries get its label.
+        for (commit, label) in stash_commits(&repo)? {
+            if let Some(idx) = graph.vertex_of(&commit) {
+                if graph.vertices_to_kind[idx] == Kind::Commit {
+                    graph.stash_labels.entry(idx).or_insert(label);
+                }
+            }
+        }
+    }
+    if opts.include_dangling {
+        let mut dangling = dangling_commits(&repo, &graph, window.as_ref())?;
+        dangling.retain(|oid| !replacement_commits.contains(oid));
+        for &commit_oid in &dangling {
+            if let Ok(commit) = repo.find_commit(commit_oid) {
+                let mut walk =
+                    TreeWalk::new(&progress, &heartbeat, commit_oid, opts).with_submodules(&submodules);
+                let (commit_idx, num_edges) =
+                    index_commit(&repo, commit_oid, commit.tree_id(), &mut graph, &mut walk)?;
+                graph.dangling_commits.insert(commit_idx);
+                edges_total += num_edges;
+                num_commits += 1;
+            }
+        }
+        refs_summary.push_str(&format!(", indexed {} dangling commits", graph.dangling_commits.len()));
+    }
+    if opts.include_index {
+        let num_staged = index_staged_blobs(&repo, &mut graph, opts)?;
+        refs_summary.push_str(&format!(", found {} staged blobs", num_staged));
+    }
+    if graph.num_fetched_trees > 0 {
+        refs_summary.push_str(&format!(", fetched {} missing trees", graph.num_fetched_trees));
+    }
+    if let Some(commit_graph) = commit_graph {
+        eprintln!(
+            "Used commit-graph with {} commits to obtain the trees of {} of {} commits",
+            commit_graph.num_commits(),
+            num_trees_from_commit_graph,
+            num_commits
+        );
+    }
+    drop(watchdog);
+    finish(graph, &progress, num_commits, edges_total, &refs_summary, opts)
+}
+
+/// Remember the blobs staged in the index of `repo` in `graph`, honoring --path and
+/// --max-blob-size. Bare repositories have no index. Returns the amount of staged blobs.
+fn index_staged_blobs(
+    repo: &Repository,
+    graph: &mut ReverseGraph,
+    opts: &BuildOptions,
+) -> Result<usize, Error> {
+    if repo.is_bare() {
+        return Ok(0);
+    }
+    let index = repo.index()?;
+    let odb = repo.odb()?;
+    for entry in index.iter() {
+        match entry.mode & 0o170000 {
+            0o100000 | 0o120000 => {}
+            _ => continue,
+        }
+        if !graph.pathspecs.is_empty()
+            && !pathspec::blob(&graph.pathspecs, &String::from_utf8_lossy(&entry.path))
+        {
+            continue;
+        }
+        if let Some(max) = opts.max_blob_size {
+            if is_oversized(&odb, entry.id, max, graph) {
+                continue;
+            }
+        }
+        graph.staged_blobs.insert(entry.id);
+    }
+    Ok(graph.staged_blobs.len())
+}
+
+/// A name for REPOSITORY and each repository given with --repository if there are any, which
+/// is its directory name without '.git'. Repositories of the same name are told apart by as many
+/// of their parent directories as needed, like 'a/repo' and 'b/repo'.
+fn repository_labels(opts: &BuildOptions) -> Vec<String> {
+    if opts.other_repositories.is_empty() {
+        return Vec::new();
+    }
+    let names: Vec<_> = iter::once(&opts.repository)
+        .chain(&opts.other_repositories)
+        .map(|path| repository_names(path))
+        .collect();
+    let mut depths = vec![1; names.len()];
+    let label = |names: &[String], depth: usize| {
+        let mut names = names[..depth.min(names.len())].to_vec();
+        names.reverse();
+        names.join("/")
+    };
+    loop {
+        let labels: Vec<_> = names.iter().zip(&depths).map(|(n, &d)| label(n, d)).collect();
+        let mut deepened = false;
+        for (idx, depth) in depths.iter_mut().enumerate() {
+            let is_ambiguous = labels.iter().filter(|&l| *l == labels[idx]).count() > 1;
+            if is_ambiguous && *depth < names[idx].len() {
+                *depth += 1;
+                deepened = true;
+            }
+        }
+        if !deepened {
+            // Only the same repository given twice ends up here with clashing labels.
+            return labels
+                .iter()
+                .enumerate()
+                .map(|(idx, l)| {
+                    if labels[..idx].contains(l) {
+                        format!("{}~{}", l, idx)
+                    } else {
+                        l.clone()
+                    }
+                })
+                .collect();
+        }
+    }
+}
+
+/// The directory name of the repository at `path` without '.git', followed by the names of all
+/// its parent directories.
+fn repository_names(path: &Path) -> Vec<String> {
+    let path = if path.ends_with(".git") {
+        path.parent().unwrap_or(path)
+    } else {
+        path
+    };
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    let mut names: Vec<_> = path.iter()
+        .rev()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| name != "/")
+        .collect();
+    if let Some(name) = names.first_mut() {
+        *name = name.trim_right_matches(".git").to_owned();
+    }
+    names
+}
+
+/// Index the commits of the repository at `path` not yet in `graph`, remembering they were found
+/// in the repository at `repository_idx`. Returns the amount of commits and edges added.
+fn index_other_repository(
+    path: &Path,
+    repository_idx: usize,
+    graph: &mut ReverseGraph,
+    progress: &ProgressBar,
+    heartbeat: &Heartbeat,
+    window: Option<&TimeWindow>,
+    opts: &BuildOptions,
+) -> Result<(usize, usize), Error> {
+    let repo = open_repository(path)
+        .with_context(|_| format!("Could not open repository at '{}'", path.display()))?;
+    check_alternates(&repo)?;
+    check_shallow(&repo, opts.allow_shallow)?;
+    let mut walk = Traversal::new(&repo, opts)?;
+    setup_walk(&repo, &mut walk, opts)?;
+
+    let (mut num_commits, mut edges_total) = (0, 0);
+    let max_commits = opts.max_commits.unwrap_or(usize::max_value());
+    for commit_oid in walk.revwalk.filter_map(Result::ok)
+        .filter(|&oid| window.map_or(true, |w| w.contains_commit(&repo, oid)))
+        .take(max_commits)
+    {
+        // Shared history was indexed already.
+        if graph.contains_commit(&commit_oid) {
+            continue;
+        }
+        if let Ok(commit) = repo.find_commit(commit_oid) {
+            let mut tree_walk = TreeWalk::new(progress, heartbeat, commit_oid, opts);
+            let (commit_idx, num_edges) =
+                index_commit(&repo, commit_oid, commit.tree_id(), graph, &mut tree_walk)?;
+            graph.commits_to_repository.insert(commit_idx, repository_idx);
+            edges_total += num_edges;
+            num_commits += 1;
+        }
+    }
+    eprintln!(
+        "Indexed {} commits of '{}' which are not in REPOSITORY",
+        num_commits,
+        path.display()
+    );
+    Ok((num_commits, edges_total))
+}
+
+/// The objects replaced with 'git replace', mapped to their replacements, unless disabled with
+/// --no-replace-objects or GIT_NO_REPLACE_OBJECTS like in git.
+fn replace_refs(repo: &Repository, opts: &BuildOptions) -> Result<HashMap<Oid, Oid>, Error> {
+    let mut replacements = HashMap::new();
+    if opts.no_replace_objects || env::var_os(NO_REPLACE_OBJECTS_ENV).is_some() {
+        return Ok(replacements);
+    }
+    for reference in repo.references_glob(&format!("{}*", REPLACE_REFS_PREFIX))?
+        .filter_map(Result::ok)
+    {
+        let original = reference
+            .name()
+            .and_then(|name| Oid::from_str(&name[REPLACE_REFS_PREFIX.len()..]).ok());
+        if let (Some(original), Some(replacement)) = (original, reference.target()) {
+            replacements.insert(original, replacement);
+        }
+    }
+    Ok(replacements)
+}
+
+/// All commits reachable from the tips of `walk` when following the parents of replacements
+/// instead of those of the commits they replace. Replacement commits themselves are only followed.
+fn reachable_with_replacements(
+    repo: &Repository,
+    walk: &Traversal,
+    replacements: &HashMap<Oid, Oid>,
+    replacement_commits: &HashSet<Oid>,
+) -> HashSet<Oid> {
+    let mut reachable = HashSet::new();
+    let mut commits: Vec<_> = walk.pushed
+        .iter()
+        .filter(|oid| !replacement_commits.contains(oid))
+        .cloned()
+        .collect();
+    while let Some(oid) = commits.pop() {
+        if !reachable.insert(oid) {
+            continue;
+        }
+        if let Ok(commit) = repo.find_commit(replaced(replacements, oid)) {
+            let num_parents = if walk.first_parent { 1 } else { commit.parent_count() };
+            commits.extend(commit.parent_ids().take(num_parents));
+        }
+    }
+    reachable
+}
+
+/// The object `oid` is replaced with, or `oid` itself.
+fn replaced(replacements: &HashMap<Oid, Oid>, oid: Oid) -> Oid {
+    replacements.get(&oid).cloned().unwrap_or(oid)
+}
+
+/// Add `commit` and its tree `tree_id` to `graph`, returning the index of the commit and the
+/// amount of edges to blobs added.
+fn index_commit(
+    repo: &Repository,
+    commit: Oid,
+    tree_id: Oid,
+    graph: &mut ReverseGraph,
+    walk: &mut TreeWalk,
+) -> Result<(usize, usize), Error> {
+    let commit_idx = graph.append(commit)?;
+    let tree_idx = if graph.pathspecs.is_empty() {
+        graph.insert_parent_get_new_child_id(commit_idx, tree_id, Kind::Tree)?
+    } else {
+        // The root tree matches only partially, so its entries are attached to the commit.
+        walk.restricted = true;
+        Some(commit_idx)
+    };
+    // Known trees are not even read, which matters for very wide ones.
+    let num_edges = match tree_idx {
+        Some(tree_idx) => match find_tree(repo, tree_id, graph, walk) {
+            Some(tree) => recurse_tree(repo, tree, tree_idx, graph, walk)?,
+            None => 0,
+        },
+        None => 0,
+    };
+    Ok((commit_idx, num_edges))
+}
+
+/// All commits in the object database of `repo` which are not in `graph`, and were committed
+/// within `window` if there is one. Only their ids are kept, so memory is bound by the amount
+/// of such commits, not the amount of objects.
+fn dangling_commits(
+    repo: &Repository,
+    graph: &ReverseGraph,
+    window: Option<&TimeWindow>,
+) -> Result<Vec<Oid>, Error> {
+    let odb = repo.odb()?;
+    let mut dangling = HashSet::new();
+    odb.foreach(|oid| {
+        if !graph.contains_commit(oid) {
+            if let Ok((_, ObjectType::Commit)) = odb.read_header(*oid) {
+                dangling.insert(*oid);
+            }
+        }
+        true
+    })?;
+    let mut dangling: Vec<_> = dangling
+        .into_iter()
+        .filter(|&oid| window.map_or(true, |w| w.contains_commit(repo, oid)))
+        .collect();
+    dangling.sort();
+    Ok(dangling)
+}
+
+fn finish(
+    mut graph: ReverseGraph,
+    progress: &ProgressBar,
+    num_commits: usize,
+    mut edges_total: usize,
+    refs_summary: &str,
+    opts: &BuildOptions,
+) -> Result<ReverseGraph, Error> {
+    if opts.with_forward_edges {
+        graph.add_forward_edges();
+    } else if let Some((passes, edges_removed)) = graph.optimize_topology() {
+        eprintln!("Removed {} edges in {} passes", edges_removed, passes);
+        edges_total -= edges_removed;
+    }
+    let compacted = graph.compact(opts.threads.unwrap_or_else(num_cpus::get_physical))?;
+    let duplicates = if graph.num_duplicate_edges > 0 {
+        let raw = edges_total;
+        edges_total = edges_total.saturating_sub(graph.num_duplicate_edges);
+        format!(", {} parent-edges before removing {} duplicates", raw, graph.num_duplicate_edges)
+    } else {
+        String::new()
+    };
+    graph.max_tree_entries = opts.max_tree_entries;
+    progress.finish_and_clear();
+    if let Some(max_commits) = graph.commits_capped_at {
+        eprintln!(
+            "WARNING: The graph is capped at the {} most recent commits, blobs only in older commits won't be found",
+            max_commits
+        );
+    }
+    if !graph.vertices_to_kind.contains(&Kind::Commit) || !graph.vertices_to_kind.contains(&Kind::Blob) {
+        eprintln!(
+            "WARNING: The graph has no commits reachable from any blob, hence all lookups will be empty. \
+             Please check the selection of refs, for instance with --head-only or --tips."
+        );
+    }
+
+    eprintln!(
+        "READY: Build reverse-tree from {} commits with graph with {} vertices and {} parent-edges{}{}{}{}",
+        num_commits,
+        graph.len(),
+        edges_total,
+        duplicates,
+        refs_summary,
+        graph
+            .caveats()
+            .iter()
+            .map(|caveat| format!(", {}", caveat))
+            .collect::<String>(),
+        match (opts.report_memory, memory::peak_rss()) {
+            (true, Some(peak)) => format!(
+                ", peak memory {}, {}, {}",
+                memory::format_bytes(peak),
+                graph.object_id_memory(),
+                compacted
+            ),
+            (true, None) => format!(
+                ", peak memory unknown, {}, {}",
+                graph.object_id_memory(),
+                compacted
+            ),
+            (false, _) => String::new(),
+        }
+    );
+    Ok(graph)
+}
+
+/// The commit times selected with --since and --until, in seconds since the epoch.
+struct TimeWindow {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl TimeWindow {
+    fn from_options(opts: &BuildOptions) -> Result<Option<TimeWindow>, Error> {
+        if opts.since.is_none() && opts.until.is_none() {
+            return Ok(None);
+        }
+        let now = date::now();
+        let parse = |spec: &Option<String>| -> Result<Option<i64>, Error> {
+            match *spec {
+                Some(ref spec) => Ok(Some(date::parse(spec, now)?)),
+                None => Ok(None),
+            }
+        };
+        Ok(Some(TimeWindow {
+            since: parse(&opts.since)?,
+            until: parse(&opts.until)?,
+        }))
+    }
+    /// True if the commit time of `commit` is within the window. Unreadable commits are not.
+    fn contains_commit(&self, repo: &Repository, commit: Oid) -> bool {
+        repo.find_commit(commit)
+            .map(|commit| {
+                let time = commit.time().seconds();
+                self.since.map_or(true, |since| time >= since)
+                    && self.until.map_or(true, |until| time <= until)
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.since, self.until) {
+            (Some(since), Some(until)) => write!(f, "between @{} and @{}", since, until),
+            (Some(since), None) => write!(f, "since @{}", since),
+            (None, Some(until)) => write!(f, "until @{}", until),
+            (None, None) => write!(f, "at any time"),
+        }
+    }
+}
+
+fn read_tips(opts: &BuildOptions) -> Result<Vec<Oid>, Error> {
+    let mut tips = opts.tips.clone();
+    if let Some(ref path) = opts.tips_from {
+        let file = File::open(path)
+            .with_context(|_| format!("Could not open tips file '{}'", path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                tips.push(line.trim().to_owned());
+            }
+        }
+    }
+    if tips.is_empty() {
+        bail!("Building from an object stream requires --tips or --tips-from")
+    }
+    tips.iter()
+        .map(|hex| {
+            Oid::from_str(hex).with_context(|_| format!("Invalid tip '{}'", hex))
+                .map_err(Into::into)
+        })
+        .collect()
+}
+
+fn build_from_batch(
+    command: &str,
+    tips: &[Oid],
+    watchdog: Watchdog,
+    heartbeat: &Heartbeat,
+    opts: &BuildOptions,
+) -> Result<ReverseGraph, Error> {
+    let mut objects = ObjectStream::spawn(command)?;
+    let progress = ProgressBar::new_spinner();
+    let mut graph = ReverseGraph::default();
+    graph.pathspecs = pathspec::normalize(&opts.paths);
+    let (mut num_commits, mut edges_total) = (0, 0);
+
+    let mut seen = BTreeSet::new();
+    let mut commits_to_visit = tips.to_owned();
+    while let Some(oid) = commits_to_visit.pop() {
+        if !seen.insert(oid) {
+            continue;
+        }
+        if Some(num_commits) == opts.max_commits {
+            graph.commits_capped_at = opts.max_commits;
+            break;
+        }
+        let commit = match objects.read(&oid)? {
+            (ObjectType::Commit, data) => batch::parse_commit(&oid, data)?,
+            (ObjectType::Tag, data) => {
+                commits_to_visit.push(batch::parse_tag_target(&oid, data)?);
+                continue;
+            }
+            (kind, _) => bail!("Expected {} to be a commit, but it is a {}", oid, kind),
+        };
+        commits_to_visit.extend(commit.parents);
+
+        num_commits += 1;
+        heartbeat.beat(oid);
+        let commit_idx = graph.append(oid)?;
+        let mut walk = TreeWalk::new(&progress, heartbeat, oid, opts);
+        let tree_idx = if graph.pathspecs.is_empty() {
+            graph.insert_parent_get_new_child_id(commit_idx, commit.tree, Kind::Tree)?
+        } else {
+            // The root tree matches only partially, so its entries are attached to the commit.
+            walk.restricted = true;
+            Some(commit_idx)
+        };
+        if let Some(tree_idx) = tree_idx {
+            edges_total +=
+                recurse_batch_tree(&mut objects, commit.tree, tree_idx, &mut graph, &mut walk)?;
+        }
+        if num_commits % COMMIT_PROGRESS_RATE == 0 {
+            progress.set_message(&format!(
+                "{} Commits done; reverse-tree with {} entries and a total of {} parent-edges",
+                num_commits,
+                graph.len(),
+                edges_total
+            ));
+            progress.tick();
+        }
+    }
+    objects.finish()?;
+    drop(watchdog);
+    finish(graph, &progress, num_commits, edges_total, "", opts)
+}
+
+/// Keeps progress and the watchdog informed while recursing into the trees of a commit, and
+/// limits the amount of entries of each tree.
+struct TreeWalk<'a> {
+    progress: &'a ProgressBar,
+    heartbeat: &'a Heartbeat,
+    commit: Oid,
+    max_entries: Option<usize>,
+    num_entries: usize,
+    max_blob_size: Option<u64>,
+    gitlinks: bool,
+    fetch_missing: bool,
+    /// Whether gitlinks should be recursed into, the submodules to look for their commits in,
+    /// and the one currently walked.
+    recurse_submodules: bool,
+    submodules: &'a [(String, Repository)],
+    submodule: Option<usize>,
+    /// The path of the current tree, and whether it matches the pathspecs only partially so
+    /// its entries have to be matched one by one.
+    path: String,
+    restricted: bool,
+}
+
+impl<'a> TreeWalk<'a> {
+    fn new(
+        progress: &'a ProgressBar,
+        heartbeat: &'a Heartbeat,
+        commit: Oid,
+        opts: &BuildOptions,
+    ) -> TreeWalk<'a> {
+        TreeWalk {
+            progress,
+            heartbeat,
+            commit,
+            max_entries: opts.max_tree_entries,
+            num_entries: 0,
+            max_blob_size: opts.max_blob_size,
+            gitlinks: !opts.no_gitlinks,
+            fetch_missing: opts.fetch_missing,
+            recurse_submodules: opts.recurse_submodules,
+            submodules: &[],
+            submodule: None,
+            path: String::new(),
+            restricted: false,
+        }
+    }
+    fn with_submodules(mut self, submodules: &'a [(String, Repository)]) -> TreeWalk<'a> {
+        self.submodules = submodules;
+        self
+    }
+    /// Call for each entry of `tree`, so that wide trees don't look like hangs.
+    fn entry(&mut self, tree: Oid) {
+        self.num_entries += 1;
+        if self.num_entries % TREE_ENTRY_PROGRESS_RATE == 0 {
+            self.heartbeat.beat(self.commit);
+            self.progress.set_message(&format!(
+                "{} entries of commit {} done, currently in tree {}",
+                self.num_entries, self.commit, tree
+            ));
+            self.progress.tick();
+        }
+    }
+    /// How the entry `name` of kind `kind` in the current tree matches `pathspecs`, which is
+    /// entirely if the current tree does.
+    fn select(&self, pathspecs: &[String], name: &str, kind: ObjectType) -> Match {
+        if !self.restricted {
+            return Match::All;
+        }
+        let path = pathspec::join(&self.path, name);
+        match kind {
+            ObjectType::Blob if pathspec::blob(pathspecs, &path) => Match::All,
+            ObjectType::Blob => Match::None,
+            _ => pathspec::tree(pathspecs, &path),
+        }
+    }
+    /// Enter the subtree `name` matching as `selected`, returning what to `leave()` it with.
+    fn enter(&mut self, name: &str, selected: Match) -> (usize, bool) {
+        let outer = (self.path.len(), self.restricted);
+        if self.restricted {
+            self.path = pathspec::join(&self.path, name);
+        }
+        self.restricted = selected == Match::Partial;
+        outer
+    }
+    fn leave(&mut self, (path_len, restricted): (usize, bool)) {
+        self.path.truncate(path_len);
+        self.restricted = restricted;
+    }
+    /// The amount of entries to index of a tree with `len` entries. The others are skipped.
+    fn limit(&self, len: usize, state: &mut ReverseGraph) -> usize {
+        match self.max_entries {
+            Some(max) if len > max => {
+                state.num_truncated_trees += 1;
+                state.num_entries_beyond_limit += len - max;
+                max
+            }
+            _ => len,
+        }
+    }
+}
+
+fn recurse_batch_tree(
+    objects: &mut ObjectStream,
+    tree: Oid,
+    tree_idx: usize,
+    state: &mut ReverseGraph,
+    walk: &mut TreeWalk,
+) -> Result<usize, Error> {
+    use ObjectType::*;
+    watchdog::tree(tree);
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    match objects.try_read(&tree)? {
+        Some((Tree, data)) => {
+            batch::parse_tree(&tree, data, &mut entries)?;
+            // Names are only needed to match entries against the pathspecs.
+            if walk.restricted {
+                names.extend(
+                    entries
+                        .iter()
+                        .map(|&(_, _, ref name)| String::from_utf8_lossy(&data[name.clone()]).into_owned()),
+                );
+            }
+        }
+        Some((kind, _)) => bail!("Expected {} to be a tree, but it is a {}", tree, kind),
+        None => {
+            state.num_missing_trees += 1;
+            return Ok(0);
+        }
+    }
+    let limit = walk.limit(entries.len(), state);
+    let mut refs = 0;
+    for (eid, (kind, id, _)) in entries.into_iter().take(limit).enumerate() {
+        walk.entry(tree);
+        let name = names.get(eid).map_or("", String::as_str);
+        let selected = walk.select(&state.pathspecs, name, kind);
+        if selected == Match::None {
+            continue;
+        }
+        match kind {
+            Tree => {
+                let item_idx = match selected {
+                    Match::Partial => Some(tree_idx),
+                    _ => state.insert_parent_get_new_child_id(tree_idx, id, Kind::Tree)?,
+                };
+                if let Some(item_idx) = item_idx {
+                    let outer = walk.enter(name, selected);
+                    let subtree_refs = recurse_batch_tree(objects, id, item_idx, state, walk);
+                    walk.leave(outer);
+                    refs += subtree_refs?;
+                }
+            }
+            Blob => {
+                refs += 1;
+                state.insert_parent_get_new_child_id(tree_idx, id, Kind::Blob)?;
+            }
+            Commit if walk.gitlinks && selected == Match::All => {
+                refs += insert_gitlink(tree_idx, id, state)?
+            }
+            Commit => continue,
+            _ => state.num_skipped_entries += 1,
+        }
+    }
+    Ok(refs)
+}
+
+fn recurse_tree(
+    repo: &Repository,
+    tree: Tree,
+    tree_idx: usize,
+    state: &mut ReverseGraph,
+    walk: &mut TreeWalk,
+) -> Result<usize, Error> {
+    use ObjectType::*;
+    watchdog::tree(tree.id());
+    let limit = walk.limit(tree.len(), state);
+    // Only the headers of blobs are read to learn their size, not their content.
+    let odb = match walk.max_blob_size {
+        Some(_) => repo.odb().ok(),
+        None => None,
+    };
+    let mut refs = 0;
+    for item in tree.iter().take(limit) {
+        walk.entry(tree.id());
+        let name = String::from_utf8_lossy(item.name_bytes());
+        let selected = walk.select(&state.pathspecs, &name, item.kind().unwrap_or(Any));
+        if selected == Match::None {
+            continue;
+        }
+        match item.kind() {
+            Some(Tree) => {
+                // Partially matching trees aren't indexed, their matching entries are attached
+                // to the closest indexed tree or commit instead.
+                let item_idx = match selected {
+                    Match::Partial => Some(tree_idx),
+                    _ => state.insert_parent_get_new_child_id(tree_idx, item.id(), Kind::Tree)?,
+                };
+                if let Some(item_idx) = item_idx {
+                    if let Some(subtree) = find_tree(repo, item.id(), state, walk) {
+                        let outer = walk.enter(&name, selected);
+                        let subtree_refs = recurse_tree(repo, subtree, item_idx, state, walk);
+                        walk.leave(outer);
+                        refs += subtree_refs?;
+                    }
+                }
+            }
+            Some(Blob) => {
+                if let (Some(max), Some(odb)) = (walk.max_blob_size, odb.as_ref()) {
+                    if is_oversized(odb, item.id(), max, state) {
+                        continue;
+                    }
+                }
+                refs += 1;
+                let blob_idx =
+                    state.insert_parent_get_new_child_id(tree_idx, item.id(), Kind::Blob)?;
+                if let (Some(blob_idx), Some(submodule)) = (blob_idx, walk.submodule) {
+                    state.blobs_to_submodule.insert(blob_idx, submodule);
+                }
+            }
+            Some(Commit) => {
+                if walk.gitlinks && selected == Match::All {
+                    refs += insert_gitlink(tree_idx, item.id(), state)?;
+                }
+                if !walk.recurse_submodules {
+                    continue;
+                }
+                // Nested submodules aren't opened, so their commits can't be found either.
+                if walk.submodules.is_empty() || walk.submodule.is_some() {
+                    state.num_missing_submodule_commits += 1;
+                } else {
+                    let outer = walk.enter(&name, selected);
+                    let submodule_refs = recurse_submodule(item.id(), tree_idx, selected, state, walk);
+                    walk.leave(outer);
+                    refs += submodule_refs?;
+                }
+            }
+            _ => state.num_skipped_entries += 1,
+        }
+    }
+    Ok(refs)
+}
+
+/// The tree `id` of `repo`, fetched with git if it is missing and --fetch-missing is set.
+/// Trees which remain missing are counted in `state`.
+fn find_tree<'r>(
+    repo: &'r Repository,
+    id: Oid,
+    state: &mut ReverseGraph,
+    walk: &TreeWalk,
+) -> Option<Tree<'r>> {
+    if let Ok(tree) = repo.find_tree(id) {
+        return Some(tree);
+    }
+    if walk.fetch_missing && fetch_object(repo, id) {
+        if let Ok(tree) = repo.find_tree(id) {
+            state.num_fetched_trees += 1;
+            return Some(tree);
+        }
+    }
+    state.num_missing_trees += 1;
+    None
+}
+
+/// Have git fetch the missing object `id` from the promisor remote of the partial clone `repo`,
+/// as libgit2 can't. Returns true if git could read it afterwards.
+fn fetch_object(repo: &Repository, id: Oid) -> bool {
+    Command::new("git")
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(&["cat-file", "-e"])
+        .arg(id.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// True if `repo` is a partial clone, whose promisor remote has objects not available locally.
+pub fn is_partial_clone(repo: &Repository) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    config.get_string("extensions.partialclone").is_ok()
+        || config
+            .entries(Some(r"remote\..*\.promisor"))
+            .map(|entries| {
+                (&entries)
+                    .filter_map(Result::ok)
+                    .any(|entry| entry.value().map_or(false, |v| v == "true"))
+            })
+            .unwrap_or(false)
+}
+
+/// Record the gitlink `commit` as child of `tree_idx` to make it a lookup key. A commit of the
+/// graph itself keeps its kind, and lookups pass through it to the commits of the superproject.
+/// Returns the added edges.
+fn insert_gitlink(
+    tree_idx: usize,
+    commit: Oid,
+    state: &mut ReverseGraph,
+) -> Result<usize, Error> {
+    Ok(match state.kind_of_oid(&commit) {
+        None | Some(Kind::Gitlink) | Some(Kind::Commit) => {
+            state.insert_parent_get_new_child_id(tree_idx, commit, Kind::Gitlink)?;
+            1
+        }
+        Some(_) => 0,
+    })
+}
+
+/// True if `blob` is larger than `max` bytes, remembering it in `state` if so. Blobs known to
+/// `state` aren't looked up again.
+fn is_oversized(odb: &Odb, blob: Oid, max: u64, state: &mut ReverseGraph) -> bool {
+    if state.oversized_blobs.contains(&blob) {
+        return true;
+    }
+    if state.contains(&blob) {
+        return false;
+    }
+    match odb.read_header(blob) {
+        Ok((size, _)) if size as u64 > max => {
+            state.oversized_blobs.insert(blob);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Index the tree of the gitlink `commit` as child of `tree_idx`, reading it from the first
+/// submodule which has the commit.
+fn recurse_submodule(
+    commit: Oid,
+    tree_idx: usize,
+    selected: Match,
+    state: &mut ReverseGraph,
+    walk: &mut TreeWalk,
+) -> Result<usize, Error> {
+    let submodules = walk.submodules;
+    let found = submodules
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, &(_, ref repo))| repo.find_commit(commit).ok().map(|c| (idx, c.tree_id())))
+        .next();
+    let (submodule, tree_id) = match found {
+        Some(found) => found,
+        None => {
+            state.num_missing_submodule_commits += 1;
+            return Ok(0);
+        }
+    };
+    let subtree_idx = match selected {
+        Match::Partial => tree_idx,
+        _ => match state.insert_parent_get_new_child_id(tree_idx, tree_id, Kind::Tree)? {
+            Some(idx) => idx,
+            None => return Ok(0),
+        },
+    };
+    let repo = &submodules[submodule].1;
+    match find_tree(repo, tree_id, state, walk) {
+        Some(tree) => {
+            let outer = mem::replace(&mut walk.submodule, Some(submodule));
+            let refs = recurse_tree(repo, tree, subtree_idx, state, walk);
+            walk.submodule = outer;
+            refs
+        }
+        None => Ok(0),
+    }
+}
+
+/// Open the submodules of `repo` to index the commits of gitlinks, remembering their paths in
+/// `graph`. Submodules which aren't initialized are skipped with a warning.
+fn open_submodules(
+    repo: &Repository,
+    graph: &mut ReverseGraph,
+) -> Result<Vec<(String, Repository)>, Error> {
+    let mut submodules = Vec::new();
+    let mut num_skipped = 0;
+    for submodule in repo.submodules()? {
+        let path = submodule.path().to_string_lossy().into_owned();
+        match submodule.open() {
+            Ok(submodule_repo) => {
+                graph.submodule_paths.push(path.clone());
+                submodules.push((path, submodule_repo));
+            }
+            Err(_) => {
+                eprintln!("WARNING: Skipping submodule at '{}' as it isn't initialized", path);
+                num_skipped += 1;
+            }
+        }
+    }
+    eprintln!(
+        "Recursing into {} submodules, skipped {} which aren't initialized",
+        submodules.len(),
+        num_skipped
+    );
+    Ok(submodules)
+}
+
+/// The commits selected by `opts` whose tree contains `blob`, found by reading all of their
+/// trees instead of building a graph. It is slow, and meant to validate lookups in graphs.
+pub fn commits_containing(blob: &Oid, opts: &BuildOptions) -> Result<Vec<Oid>, Error> {
+    if opts.objects_from_batch.is_some() {
+        bail!("Commits can only be found without a graph by reading REPOSITORY, not with --objects-from-batch")
+    }
+    if !opts.paths.is_empty() {
+        bail!("Commits can only be found without a graph if all paths are indexed, not with --path")
+    }
+    let repo = open_repository(&opts.repository)?;
+    let mut walk = Traversal::new(&repo, opts)?;
+    setup_walk(&repo, &mut walk, opts)?;
+    let window = TimeWindow::from_options(opts)?;
+
+    let mut known_trees = HashMap::new();
+    let mut commits = Vec::new();
+    let max_commits = opts.max_commits.unwrap_or(usize::max_value());
+    for commit_oid in walk.revwalk.filter_map(Result::ok)
+        .filter(|&oid| window.as_ref().map_or(true, |w| w.contains_commit(&repo, oid)))
+        .take(max_commits)
+    {
+        let tree_id = match repo.find_commit(commit_oid) {
+            Ok(commit) => commit.tree_id(),
+            Err(_) => continue,
+        };
+        if tree_contains(&repo, tree_id, blob, &mut known_trees) {
+            commits.push(commit_oid);
+        }
+    }
+    Ok(commits)
+}
+
+fn tree_contains(repo: &Repository, tree_id: Oid, blob: &Oid, known: &mut HashMap<Oid, bool>) -> bool {
+    if let Some(&contains) = known.get(&tree_id) {
+        return contains;
+    }
+    let contains = match repo.find_tree(tree_id) {
+        Ok(tree) => tree.iter().any(|item| match item.kind() {
+            Some(ObjectType::Blob) => item.id() == *blob,
+            Some(ObjectType::Tree) => tree_contains(repo, item.id(), blob, known),
+            _ => false,
+        }),
+        Err(_) => false,
+    };
+    known.insert(tree_id, contains);
+    contains
+}
+
+fn corrupt(repo: &Repository, what: &str, err: &git2::Error) -> Error {
+    format_err!(
+        "The object database of '{}' seems to be corrupt: could not read {}: {}",
+        repo.path().display(),
+        what,
+        err.message()
+    )
+}
+
+/// Append `dirs` to the object directories in GIT_ALTERNATE_OBJECT_DIRECTORIES, which makes them
+/// visible to repositories opened with `open_repository()` as well as to spawned git processes.
+pub fn add_alternate_object_directories(dirs: &[PathBuf]) -> Result<(), Error> {
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    let cwd = env::current_dir()?;
+    let mut all = environment_alternates();
+    all.extend(dirs.iter().map(|dir| cwd.join(dir)));
+    env::set_var(ALTERNATES_ENV, env::join_paths(all)?);
+    Ok(())
+}
+
+fn environment_alternates() -> Vec<PathBuf> {
+    env::var_os(ALTERNATES_ENV)
+        .map(|dirs| env::split_paths(&dirs).filter(|d| !d.as_os_str().is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// Set GIT_WORK_TREE to `work_tree`, which makes it the worktree of repositories opened with
+/// `open_repository()` as well as of spawned git processes. Useful if the git directory
+/// isn't within the worktree.
+pub fn set_work_tree(work_tree: &Path) -> Result<(), Error> {
+    if !work_tree.is_dir() {
+        bail!("The worktree at '{}' is not a directory", work_tree.display())
+    }
+    env::set_var(WORK_TREE_ENV, env::current_dir()?.join(work_tree));
+    Ok(())
+}
+
+/// Open the repository at `path` or the closest of its parent directories, using the git dir,
+/// the object directories and the worktree configured in the environment if there are any, just
+/// like git does. GIT_DIR is used only if `path` is the default one.
+pub fn open_repository(path: &Path) -> Result<Repository, Error> {
+    let git_dir = env::var_os(GIT_DIR_ENV);
+    let start = match git_dir {
+        Some(ref git_dir) if path == Path::new(DEFAULT_REPOSITORY) => Path::new(git_dir),
+        _ => path,
+    };
+    let flags = if git_dir.is_none() && env::var_os(OBJECT_DIRECTORY_ENV).is_none()
+        && env::var_os(ALTERNATES_ENV).is_none()
+    {
+        git2::RepositoryOpenFlags::empty()
+    } else {
+        git2::RepositoryOpenFlags::FROM_ENV
+    };
+    let repo = Repository::open_ext(start, flags, &[] as &[&OsStr]).map_err(|err| {
+        if err.code() == git2::ErrorCode::NotFound {
+            format_err!(
+                "Could not find a git repository at '{}' or any of its parent directories",
+                env::current_dir()
+                    .map(|cwd| cwd.join(start))
+                    .unwrap_or_else(|_| start.to_owned())
+                    .display()
+            )
+        } else {
+            err.into()
+        }
+    })?;
+    if let Some(work_tree) = env::var_os(WORK_TREE_ENV) {
+        repo.set_workdir(Path::new(&work_tree), false)?;
+    }
+    Ok(repo)
+}
+
+/// All object directories of `repo`, starting with its own, followed by the ones configured in
+/// 'objects/info/alternates' and in the environment.
+pub fn object_directories(repo: &Repository) -> Vec<PathBuf> {
+    let objects = env::var_os(OBJECT_DIRECTORY_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| repo.path().join("objects"));
+    let mut dirs = Vec::new();
+    if let Ok(alternates) = fs::read_to_string(objects.join("info").join("alternates")) {
+        dirs.extend(
+            alternates
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(|l| objects.join(l)),
+        );
+    }
+    dirs.insert(0, objects);
+    dirs.extend(environment_alternates());
+    dirs
+}
+
+/// Any object stored in the object directory at `dir`, loose or packed.
+fn sample_object(dir: &Path) -> Result<Option<Oid>, Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            for object in fs::read_dir(entry.path())? {
+                let hex = format!("{}{}", name, object?.file_name().to_string_lossy());
+                if let Ok(oid) = Oid::from_str(&hex) {
+                    if hex.len() == 40 {
+                        return Ok(Some(oid));
+                    }
+                }
+            }
+        }
+    }
+    let pack_dir = dir.join("pack");
+    if !pack_dir.is_dir() {
+        return Ok(None);
+    }
+    for entry in fs::read_dir(pack_dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "idx") {
+            continue;
+        }
+        // Version 2 indices start with a magic number, the version and a fanout table,
+        // followed by the sorted object ids.
+        let data = fs::read(&path)?;
+        let first = PACK_INDEX_HEADER_LEN + PACK_INDEX_FANOUT_LEN;
+        if data.len() >= first + 20 && data[..4] == PACK_INDEX_MAGIC {
+            return Ok(Some(Oid::from_bytes(&data[first..first + 20])?));
+        }
+    }
+    Ok(None)
+}
+
+/// Fail if `repo` is shallow, as blobs beyond its boundary can't be found, unless `allow` is set,
+/// in which case the boundary commits are listed instead.
+fn check_shallow(repo: &Repository, allow: bool) -> Result<(), Error> {
+    if !repo.is_shallow() {
+        return Ok(());
+    }
+    if !allow {
+        bail!(
+            "The repository at '{}' is shallow, so blobs only in commits beyond its boundary won't be found. \
+             Run 'git fetch --unshallow' first, or pass --allow-shallow to build an incomplete graph anyway.",
+            repo.path().display()
+        )
+    }
+    let boundary = fs::read_to_string(repo.path().join("shallow")).unwrap_or_default();
+    eprintln!(
+        "WARNING: The repository is shallow, blobs only in commits beyond these boundary commits won't be found:"
+    );
+    for commit in boundary.lines().filter(|l| !l.trim().is_empty()) {
+        eprintln!("  {}", commit.trim());
+    }
+    Ok(())
+}
+
+/// Read one object through each object directory of `repo` other than its own, failing with
+/// the first directory which can't be read.
+fn check_alternates(repo: &Repository) -> Result<(), Error> {
+    let odb = repo.odb()?;
+    for dir in object_directories(repo).into_iter().skip(1) {
+        let sample = sample_object(&dir).with_context(|_| {
+            format!("Alternate object directory '{}' is not readable", dir.display())
+        })?;
+        if let Some(oid) = sample {
+            odb.read(oid).with_context(|_| {
+                format!(
+                    "Could not read object {} through alternate object directory '{}'",
+                    oid,
+                    dir.display()
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Read HEAD, all references and a sample of commits along with their trees and entries,
+/// failing with the first object that can't be read.
+fn check_object_database(repo: &Repository, partial_clone: bool) -> Result<(), Error> {
+    eprintln!("Checking object database...");
+    let head = repo.revparse_single("HEAD")
+        .and_then(|h| h.peel(ObjectType::Commit))
+        .map_err(|e| corrupt(repo, "HEAD", &e))?;
+
+    let mut num_refs = 0;
+    for reference in repo.references().map_err(|e| corrupt(repo, "references", &e))? {
+        let reference = reference.map_err(|e| corrupt(repo, "a reference", &e))?;
+        if let Some(target) = reference.target() {
+            repo.find_object(target, None).map_err(|e| {
+                corrupt(
+                    repo,
+                    &format!("{} of '{}'", target, reference.name().unwrap_or("<non-utf8>")),
+                    &e,
+                )
+            })?;
+        }
+        num_refs += 1;
+    }
+
+    let odb = repo.odb()?;
+    let mut walk = repo.revwalk()?;
+    walk.push(head.id())?;
+    let mut num_objects = 0;
+    for commit_oid in walk.take(FSCK_SAMPLE_COMMITS) {
+        let commit_oid = commit_oid.map_err(|e| corrupt(repo, "the commit history", &e))?;
+        let tree = repo.find_commit(commit_oid)
+            .and_then(|c| c.tree())
+            .map_err(|e| corrupt(repo, &format!("commit {} or its tree", commit_oid), &e))?;
+        for entry in tree.iter() {
+            match odb.read_header(entry.id()) {
+                Ok(_) => num_objects += 1,
+                // Objects of partial clones are promised by a remote, their absence is expected.
+                Err(ref e) if partial_clone && e.code() == git2::ErrorCode::NotFound => continue,
+                Err(e) => return Err(corrupt(repo, &format!("tree entry {}", entry.id()), &e)),
+            }
+        }
+        num_objects += 2;
+    }
+    eprintln!(
+        "Object database looks healthy after reading {} references and {} objects",
+        num_refs, num_objects
+    );
+    Ok(())
+}
+
+/// In this case, traversing from head is all there is to do, and not worth a warning.
+fn is_single_branch_without_remotes(repo: &Repository) -> Result<bool, Error> {
+    Ok(repo.remotes()?.is_empty() && repo.branches(Some(git2::BranchType::Local))?.count() <= 1)
+}
+
+/// Match `text` against `pattern`, in which '*' matches any amount of characters and '?' a
+/// single one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<_>, Vec<_>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    backtrack = Some((bp, bt + 1));
+                    p = bp + 1;
+                    t = bt + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The options selecting the commits to traverse, to be compared when reusing caches.
+pub fn traversal_fingerprint(opts: &BuildOptions) -> String {
+    let mut parts = vec![
+        format!("head-only={}", opts.head_only),
+        format!("remote-patterns={:?}", opts.remote_patterns),
+        format!("exclude-remotes={:?}", opts.exclude_remotes),
+        format!("tips={:?}", opts.tips),
+    ];
+    let ref_flags = [
+        (opts.local_branches, "local-branches"),
+        (opts.include_tags, "include-tags"),
+        (opts.include_worktrees, "include-worktrees"),
+        (opts.include_stash, "include-stash"),
+        (opts.include_index, "include-index"),
+    ];
+    parts.extend(ref_flags.iter().filter(|&&(set, _)| set).map(|&(_, key)| format!("{}=true", key)));
+    if !opts.refs.is_empty() {
+        parts.push(format!("refs={:?}", opts.refs));
+    }
+    if opts.all_refs {
+        parts.push("all=true".into());
+    }
+    if let Some(ref spec) = opts.rev_range {
+        parts.push(format!("rev-range={:?}", spec));
+    }
+    if !opts.exclude_revs.is_empty() {
+        parts.push(format!("not={:?}", opts.exclude_revs));
+    }
+    if !opts.exclude_refs.is_empty() {
+        parts.push(format!("exclude-refs={:?}", opts.exclude_refs));
+    }
+    if let Some(ref since) = opts.since {
+        parts.push(format!("since={:?}", since));
+    }
+    if let Some(ref until) = opts.until {
+        parts.push(format!("until={:?}", until));
+    }
+    let walk_flags = [
+        (opts.first_parent, "first-parent"),
+        (opts.include_dangling, "include-dangling"),
+        (opts.no_replace_objects, "no-replace-objects"),
+    ];
+    parts.extend(walk_flags.iter().filter(|&&(set, _)| set).map(|&(_, key)| format!("{}=true", key)));
+    if !opts.other_repositories.is_empty() {
+        parts.push(format!("repositories={:?}", opts.other_repositories));
+    }
+    if opts.recurse_submodules {
+        parts.push("recurse-submodules=true".into());
+    }
+    if opts.no_gitlinks {
+        parts.push("no-gitlinks=true".into());
+    }
+    if !opts.paths.is_empty() {
+        parts.push(format!("paths={:?}", pathspec::normalize(&opts.paths)));
+    }
+    if let Some(max) = opts.max_tree_entries {
+        parts.push(format!("max-tree-entries={}", max));
+    }
+    if let Some(max) = opts.max_blob_size {
+        parts.push(format!("max-blob-size={}", max));
+    }
+    if let Some(max) = opts.max_commits {
+        parts.push(format!("max-commits={}", max));
+    }
+    parts.join(" ")
+}
+
+/// A revwalk which remembers the commits pushed into and hidden from it, to be able to
+/// enumerate the same commits using the commit-graph instead.
+struct Traversal<'repo> {
+    repo: &'repo Repository,
+    revwalk: Revwalk<'repo>,
+    pushed: Vec<Oid>,
+    hidden: Vec<Oid>,
+    first_parent: bool,
+}
+
+impl<'repo> Traversal<'repo> {
+    fn new(repo: &'repo Repository, opts: &BuildOptions) -> Result<Traversal<'repo>, Error> {
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(walk_sorting(opts));
+        Ok(Traversal {
+            repo,
+            revwalk,
+            pushed: Vec::new(),
+            hidden: Vec::new(),
+            first_parent: false,
+        })
+    }
+    fn push(&mut self, commit: Oid) -> Result<(), git2::Error> {
+        self.pushed.push(commit);
+        self.revwalk.push(commit)
+    }
+    fn push_head(&mut self) -> Result<(), git2::Error> {
+        let head = self.repo.head()?.peel(ObjectType::Commit)?.id();
+        self.push(head)
+    }
+    fn hide(&mut self, commit: Oid) -> Result<(), git2::Error> {
+        self.hidden.push(commit);
+        self.revwalk.hide(commit)
+    }
+    fn simplify_first_parent(&mut self) {
+        self.first_parent = true;
+        self.revwalk.simplify_first_parent();
+    }
+}
+
+/// The commits of `walk` in the order of the revwalk, obtained from `commit_graph` without
+/// reading commit objects, or None if the revwalk has to be used. Which one was taken and why
+/// is printed.
+fn commit_graph_order(
+    commit_graph: Option<&CommitGraph>,
+    walk: &Traversal,
+    replacements: &HashMap<Oid, Oid>,
+    opts: &BuildOptions,
+) -> Result<Option<Vec<Oid>>, Error> {
+    let reason = match commit_graph {
+        _ if opts.no_commit_graph => "--no-commit-graph is set",
+        None => "there is no commit-graph",
+        // Grafted history isn't in the commit-graph, which is why git ignores it as well.
+        Some(_) if !replacements.is_empty() => "replace refs are in effect",
+        Some(commit_graph) => {
+            let by_time = opts.max_commits.is_some();
+            match commit_graph.traverse(&walk.pushed, &walk.hidden, walk.first_parent, by_time)? {
+                Some(commits) => return Ok(Some(commits)),
+                None => "the commit-graph is stale",
+            }
+        }
+    };
+    eprintln!("Enumerating commits with a revwalk as {}", reason);
+    Ok(None)
+}
+
+/// Topological order, from newest to oldest if only the most recent commits are to be indexed.
+fn walk_sorting(opts: &BuildOptions) -> git2::Sort {
+    if opts.max_commits.is_some() {
+        git2::Sort::TOPOLOGICAL | git2::Sort::TIME
+    } else {
+        git2::Sort::TOPOLOGICAL
+    }
+}
+
+/// Push the tips selected by `opts` into `walk`, returning a summary of the pushed refs for
+/// the READY line.
+fn setup_walk(repo: &Repository, walk: &mut Traversal, opts: &BuildOptions) -> Result<String, Error> {
+    let mut pushed = HashSet::new();
+    let mut summary = String::new();
+    let mut filter = RefFilter::new(&opts.exclude_refs);
+    if opts.rev_range.is_some() && (opts.head_only || opts.all_refs || !opts.refs.is_empty()) {
+        bail!("--rev-range can't be combined with --head-only, --all or --refs")
+    }
+    if let Some(ref spec) = opts.rev_range {
+        push_rev_range(repo, walk, spec)?;
+    } else if opts.all_refs {
+        if opts.head_only || !opts.refs.is_empty() {
+            bail!("--all can't be combined with --head-only or --refs")
+        }
+        let mut num_refs = 0;
+        let head = repo.head().ok();
+        for reference in head.into_iter().chain(repo.references()?.filter_map(Result::ok)) {
+            if !filter.keep(reference.name().unwrap_or("")) {
+                continue;
+            }
+            if let Some(commit) = peel_to_commit(repo, &reference, "ref")? {
+                num_refs += 1;
+                if pushed.insert(commit) {
+                    walk.push(commit)?;
+                }
+            }
+        }
+        filter.check_remaining()?;
+        summary.push_str(&format!(", pushed {} refs", num_refs));
+    } else if opts.head_only {
+        if !opts.refs.is_empty() {
+            bail!("--head-only and --refs are mutually exclusive")
+        }
+        walk.push_head()?;
+    } else if !opts.refs.is_empty() {
+        for pattern in &opts.refs {
+            let mut num_matches = 0;
+            for reference in repo.references_glob(pattern)?.filter_map(Result::ok) {
+                num_matches += 1;
+                if !filter.keep(reference.name().unwrap_or("")) {
+                    continue;
+                }
+                if let Some(commit) = peel_to_commit(repo, &reference, "ref")? {
+                    if pushed.insert(commit) {
+                        walk.push(commit)?;
+                    }
+                }
+            }
+            if num_matches == 0 {
+                eprintln!("WARNING: Ref pattern '{}' matched no ref", pattern);
+            }
+        }
+        if pushed.is_empty() {
+            filter.check_remaining()?;
+            bail!(
+                "None of the refs matching {:?} points to a commit, there is nothing to traverse",
+                opts.refs
+            )
+        }
+    } else {
+        let selecting = !opts.remote_patterns.is_empty() || !opts.exclude_remotes.is_empty();
+        let mut remotes = BTreeMap::new();
+        let mut pattern_matches = vec![0; opts.remote_patterns.len()];
+        for branch in repo.branches(Some(git2::BranchType::Remote))?
+            .filter_map(|b| b.map(|(b, _bt)| b).ok())
+        {
+            let remote_head = match branch.get().target() {
+                Some(target) => target,
+                None => continue,
+            };
+            let name = branch.name().ok().and_then(|n| n).unwrap_or("").to_owned();
+            let remote = name.split('/').next().unwrap_or("").to_owned();
+            let mut selected = opts.remote_patterns.is_empty();
+            for (pattern, num_matches) in opts.remote_patterns.iter().zip(pattern_matches.iter_mut()) {
+                if glob_matches(pattern, &name) {
+                    *num_matches += 1;
+                    selected = true;
+                }
+            }
+            let excluded = opts.exclude_remotes.contains(&remote);
+            let counts = remotes.entry(remote).or_insert((0, 0));
+            if !selected || excluded || !filter.keep(branch.get().name().unwrap_or("")) {
+                counts.1 += 1;
+                continue;
+            }
+            counts.0 += 1;
+            if pushed.insert(remote_head) {
+                walk.push(remote_head)?;
+            }
+        }
+        if opts.local_branches {
+            for branch in repo.branches(Some(git2::BranchType::Local))?
+                .filter_map(|b| b.map(|(b, _bt)| b).ok())
+            {
+                if !filter.keep(branch.get().name().unwrap_or("")) {
+                    continue;
+                }
+                if let Some(target) = branch.get().target() {
+                    if pushed.insert(target) {
+                        walk.push(target)?;
+                    }
+                }
+            }
+        }
+        if selecting {
+            for (remote, (num_selected, num_excluded)) in remotes {
+                eprintln!(
+                    "Remote '{}': selected {} branches, excluded {}",
+                    remote, num_selected, num_excluded
+                );
+            }
+            for (pattern, _) in opts.remote_patterns
+                .iter()
+                .zip(pattern_matches)
+                .filter(|&(_, num_matches)| num_matches == 0)
+            {
+                eprintln!("Remote pattern '{}' matched no branch", pattern);
+            }
+        }
+        if pushed.is_empty() {
+            filter.check_remaining()?;
+            if selecting || !is_single_branch_without_remotes(repo)? {
+                eprintln!(
+                    "Didn't find a single remote - using head instead to avoid empty traversal"
+                );
+            }
+            walk.push_head()?;
+        }
+    }
+    if opts.include_tags {
+        let num_tags = push_tags(repo, walk, &mut pushed, &mut filter)?;
+        summary.push_str(&format!(", pushed {} tags", num_tags));
+    }
+    if opts.include_stash {
+        let stash = stash_commits(repo)?;
+        for &(commit, _) in &stash {
+            if pushed.insert(commit) {
+                walk.push(commit)?;
+            }
+        }
+        let num_entries = stash.iter().filter(|&&(_, ref label)| !label.contains('^')).count();
+        summary.push_str(&format!(", pushed {} stash entries", num_entries));
+    }
+    if opts.include_worktrees {
+        let num_heads = push_worktree_heads(repo, walk, &mut pushed)?;
+        summary.push_str(&format!(", pushed {} worktree HEADs", num_heads));
+    }
+    summary.push_str(&filter.summary());
+    for rev in &opts.exclude_revs {
+        let commit = repo.revparse_single(rev)
+            .and_then(|object| object.peel(ObjectType::Commit))
+            .with_context(|_| format!("Could not resolve '{}' given with --not to a commit", rev))?;
+        walk.hide(commit.id())?;
+    }
+    if opts.first_parent {
+        walk.simplify_first_parent();
+        summary.push_str(", following first parents only");
+    }
+    Ok(summary)
+}
+
+/// The commits of all stash entries, the most recent first, each followed by the commits of the
+/// index and the untracked files it recorded. They are labeled like 'stash@{2}', 'stash@{2}^2'
+/// and 'stash@{2}^3'. Without a stash there are none.
+fn stash_commits(repo: &Repository) -> Result<Vec<(Oid, String)>, Error> {
+    let mut commits = Vec::new();
+    if repo.find_reference(STASH_REF).is_err() {
+        return Ok(commits);
+    }
+    let reflog = repo.reflog(STASH_REF)?;
+    for sid in 0..reflog.len() {
+        let stash = match reflog.get(sid).map(|entry| repo.find_commit(entry.id_new())) {
+            Some(Ok(stash)) => stash,
+            _ => continue,
+        };
+        let label = format!("stash@{{{}}}", sid);
+        commits.push((stash.id(), label.clone()));
+        for (pid, parent) in stash.parent_ids().enumerate().skip(1) {
+            commits.push((parent, format!("{}^{}", label, pid + 1)));
+        }
+    }
+    Ok(commits)
+}
+
+/// Push the HEADs of all worktrees of `repo` into `walk`, which includes the main worktree if
+/// `repo` is a linked one. Locked and prunable worktrees are skipped with a warning.
+fn push_worktree_heads(
+    repo: &Repository,
+    walk: &mut Traversal,
+    pushed: &mut HashSet<Oid>,
+) -> Result<usize, Error> {
+    // Linked worktrees have their own git dir below the common one, named in 'commondir'.
+    let common_dir = match fs::read_to_string(repo.path().join("commondir")) {
+        Ok(dir) => repo.path().join(dir.trim()),
+        Err(_) => repo.path().to_owned(),
+    };
+    let mut git_dirs = Vec::new();
+    if repo.is_worktree() {
+        git_dirs.push(common_dir.clone());
+    }
+    if let Ok(entries) = fs::read_dir(common_dir.join("worktrees")) {
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).map(|e| e.path()).collect();
+        entries.sort();
+        for git_dir in entries {
+            let name = git_dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            if git_dir.join("locked").is_file() {
+                eprintln!("WARNING: Skipping worktree '{}' as it is locked", name);
+                continue;
+            }
+            let work_tree = fs::read_to_string(git_dir.join("gitdir")).unwrap_or_default();
+            if work_tree.trim().is_empty() || !Path::new(work_tree.trim()).exists() {
+                eprintln!(
+                    "WARNING: Skipping worktree '{}' as it is prunable, its directory is gone",
+                    name
+                );
+                continue;
+            }
+            git_dirs.push(git_dir);
+        }
+    }
+    let mut num_heads = 0;
+    for git_dir in git_dirs {
+        let head = Repository::open(&git_dir)
+            .and_then(|worktree| {
+                let head = worktree.head()?;
+                head.peel(ObjectType::Commit).map(|commit| commit.id())
+            })
+            .with_context(|_| {
+                format!("Could not resolve HEAD of worktree at '{}'", git_dir.display())
+            })?;
+        num_heads += 1;
+        if pushed.insert(head) {
+            walk.push(head)?;
+        }
+    }
+    Ok(num_heads)
+}
+
+/// Drops refs whose full name matches any of the --exclude-refs globs, counting the refs it
+/// keeps and remembering those it drops.
+struct RefFilter<'a> {
+    patterns: &'a [String],
+    num_included: usize,
+    excluded: Vec<String>,
+}
+
+impl<'a> RefFilter<'a> {
+    fn new(patterns: &'a [String]) -> RefFilter<'a> {
+        RefFilter {
+            patterns,
+            num_included: 0,
+            excluded: Vec::new(),
+        }
+    }
+    /// True if the ref `name` is to be traversed.
+    fn keep(&mut self, name: &str) -> bool {
+        if self.patterns.iter().any(|pattern| glob_matches(pattern, name)) {
+            self.excluded.push(name.to_owned());
+            false
+        } else {
+            self.num_included += 1;
+            true
+        }
+    }
+    /// Fail if refs were excluded and none is left, as there would be nothing to traverse.
+    fn check_remaining(&self) -> Result<(), Error> {
+        const MAX_LISTED: usize = 10;
+        if self.num_included > 0 || self.excluded.is_empty() {
+            return Ok(());
+        }
+        let mut listed = self.excluded[..self.excluded.len().min(MAX_LISTED)].join("\n  ");
+        if self.excluded.len() > MAX_LISTED {
+            listed.push_str(&format!("\n  ... and {} more", self.excluded.len() - MAX_LISTED));
+        }
+        bail!(
+            "--exclude-refs {:?} excluded all {} refs, there is nothing to traverse. Excluded were:\n  {}",
+            self.patterns,
+            self.excluded.len(),
+            listed
+        )
+    }
+    fn summary(&self) -> String {
+        if self.patterns.is_empty() {
+            String::new()
+        } else {
+            format!(
+                ", included {} refs, excluded {}",
+                self.num_included,
+                self.excluded.len()
+            )
+        }
+    }
+}
+
+/// Push the commits of `spec` into `walk`, which is either a single revision or a range
+/// like 'A..B', whose start is hidden.
+fn push_rev_range(repo: &Repository, walk: &mut Traversal, spec: &str) -> Result<(), Error> {
+    let revspec = repo.revparse(spec)
+        .with_context(|_| format!("Could not parse revision range '{}'", spec))?;
+    if revspec.mode().contains(git2::RevparseMode::MERGE_BASE) {
+        bail!("Symmetric differences like 'A...B' are not supported by --rev-range, use 'A..B' instead")
+    }
+    let commit_of = |object: &git2::Object| -> Result<Oid, Error> {
+        Ok(object.peel(ObjectType::Commit)
+            .with_context(|_| format!("'{}' does not point to a commit", spec))?
+            .id())
+    };
+    match (revspec.from(), revspec.to()) {
+        (Some(from), Some(to)) if revspec.mode().contains(git2::RevparseMode::RANGE) => {
+            walk.push(commit_of(to)?)?;
+            walk.hide(commit_of(from)?)?;
+        }
+        (Some(single), _) => walk.push(commit_of(single)?)?,
+        _ => bail!("Revision range '{}' selects no commit", spec),
+    }
+    Ok(())
+}
+
+/// Push the commits of all tags into `walk` unless they are `pushed` already, peeling annotated
+/// tags, and return the amount of tags pointing to commits.
+fn push_tags(
+    repo: &Repository,
+    walk: &mut Traversal,
+    pushed: &mut HashSet<Oid>,
+    filter: &mut RefFilter,
+) -> Result<usize, Error> {
+    let mut num_tags = 0;
+    for reference in repo.references_glob("refs/tags/*")?.filter_map(Result::ok) {
+        if !filter.keep(reference.name().unwrap_or("")) {
+            continue;
+        }
+        if let Some(commit) = peel_to_commit(repo, &reference, "tag")? {
+            num_tags += 1;
+            if pushed.insert(commit) {
+                walk.push(commit)?;
+            }
+        }
+    }
+    Ok(num_tags)
+}
+
+/// The commit `reference` points to, resolving symbolic refs and peeling annotated tags, or
+/// None with a warning naming it as `what` if it points to something else.
+fn peel_to_commit(repo: &Repository, reference: &Reference, what: &str) -> Result<Option<Oid>, Error> {
+    let target = match reference.resolve().ok().and_then(|r| r.target()) {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+    let mut object = repo.find_object(target, None)?;
+    while object.kind() == Some(ObjectType::Tag) {
+        let target = object.as_tag().expect("kind checked").target()?;
+        object = target;
+    }
+    if object.kind() != Some(ObjectType::Commit) {
+        eprintln!(
+            "WARNING: Skipping {} '{}' as it points to a {} instead of a commit",
+            what,
+            reference.name().unwrap_or("<invalid utf-8>"),
+            object.kind().map_or("unknown object", |kind| kind.str())
+        );
+        return Ok(None);
+    }
+    Ok(Some(object.id()))
 }
@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate failure;
 extern crate failure_tools;
 extern crate git2;
@@ -14,24 +15,75 @@ extern crate bincode;
 extern crate crossbeam_channel;
 extern crate lz4;
 extern crate serde;
+extern crate ignore;
 
 use failure_tools::ok_or_exit;
-use std::path::PathBuf;
+use std::{path::PathBuf, process};
 use git2::ObjectType;
 use structopt::StructOpt;
+use fixedbitset::FixedBitSet;
 
 mod lut;
 mod cli;
 mod find;
+mod gitignore;
+mod batch;
+mod watchdog;
+mod commit_graph;
+mod memory;
+mod replay;
+mod notes;
+mod binary;
+mod hash_cache;
+mod coverage;
+mod date;
+mod pathspec;
 
 fn main() {
     let opts = Options::from_args();
-    ok_or_exit(cli::run(opts));
+    match cli::run(opts) {
+        Err(err) => match err.downcast::<cli::Exit>() {
+            Ok(exit) => {
+                eprintln!("{}", exit);
+                process::exit(exit.code)
+            }
+            Err(err) => ok_or_exit(Err(err)),
+        },
+        Ok(()) => {}
+    }
 }
 
-#[derive(Default)]
 pub struct Stack {
     indices: Vec<usize>,
+    visited: FixedBitSet,
+    visited_indices: Vec<usize>,
+    subtree_memo: Option<lut::SubtreeMemo>,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack {
+            indices: Vec::new(),
+            visited: FixedBitSet::with_capacity(0),
+            visited_indices: Vec::new(),
+            subtree_memo: None,
+        }
+    }
+}
+
+impl Stack {
+    /// A stack remembering the commits of frequently traversed trees in `megabytes` of memory,
+    /// or nothing if it is 0.
+    fn with_subtree_memo(megabytes: usize) -> Self {
+        Stack {
+            subtree_memo: if megabytes == 0 {
+                None
+            } else {
+                Some(lut::SubtreeMemo::new(megabytes * 1024 * 1024))
+            },
+            ..Stack::default()
+        }
+    }
 }
 
 /// A basic example
@@ -42,21 +94,513 @@ pub struct Options {
     #[structopt(short = "t", long = "threads")]
     threads: Option<usize>,
 
+    /// If set, each commit of the lookup results or the ranked candidates with a note is
+    /// followed by a 'note <commit> <note>' record. Notes are read from 'refs/notes/commits'
+    /// or the given ref, and require REPOSITORY.
+    #[structopt(long = "show-notes")]
+    show_notes: Option<Option<String>>,
+
+    /// The maximum amount of characters of notes shown with --show-notes.
+    #[structopt(long = "note-max-chars", default_value = "200")]
+    note_max_chars: usize,
+
+    /// A command run with 'sh' for each file of the tree-to-integrate, receiving the file on stdin
+    /// and its path as first argument. Its output is hashed instead of the file, which allows
+    /// matching repositories whose content was stored through a git clean filter.
+    #[structopt(long = "hash-filter")]
+    hash_filter: Option<String>,
+
+    /// A file remembering the object ids of the files of the tree-to-integrate along with their
+    /// size and modification time, so that only changed files are hashed. It is written while
+    /// hashing, so an interrupted run can be resumed. It is created if it doesn't exist.
+    #[structopt(long = "hash-cache", parse(from_os_str))]
+    hash_cache: Option<PathBuf>,
+
+    /// An additional object directory to read objects from, like the ones listed in
+    /// 'objects/info/alternates'. Can be given multiple times and is appended to the ones in
+    /// GIT_ALTERNATE_OBJECT_DIRECTORIES. Each alternate is checked to be readable before building.
+    #[structopt(long = "alternate-objects", parse(from_os_str),
+                raw(number_of_values = "1"))]
+    alternate_objects: Vec<PathBuf>,
+
+    /// The worktree of REPOSITORY, if REPOSITORY is a git directory outside of it, like with
+    /// GIT_DIR and GIT_WORK_TREE. It is used to exclude ignored files from a tree-to-integrate
+    /// within it. Defaults to GIT_WORK_TREE.
+    #[structopt(long = "work-tree", parse(from_os_str))]
+    work_tree: Option<PathBuf>,
+
+    /// If set, only a single commit is output: the first one containing all blobs of the
+    /// tree-to-integrate, or otherwise the best ranked one. The exit code is 0 for a commit
+    /// containing all blobs, 4 for one containing only some, and 2 if there is no commit at all.
+    #[structopt(long = "best")]
+    best: bool,
+
+    /// If set, up to the given amount of matched blobs is output as evidence for the best
+    /// candidate, as 'evidence <blob> rarity=<weight> absent-from=<commits> <path>'. Blobs are
+    /// chosen to be rare, from different directories, and absent from the other candidates
+    /// up to --max-candidates. They are verified against the tree of the best candidate.
+    #[structopt(long = "evidence")]
+    evidence: Option<usize>,
+
+    /// If set, the leading commits by their score according to --rank-by are printed to stderr
+    /// every few seconds while looking up the blobs of the tree-to-integrate.
+    #[structopt(long = "progressive")]
+    progressive: bool,
+
+    /// If set, no more blobs of the tree-to-integrate are looked up once the commit with the
+    /// highest score according to --rank-by can't be caught anymore, even if all remaining
+    /// blobs matched another.
+    #[structopt(long = "early-stop")]
+    early_stop: bool,
+
     /// If set, traversal will only happen along the checked-out head.
     /// Otherwise it will take into consideration all remote branches, too
     /// Also useful for bare-repositories
     #[structopt(long = "head-only")]
     head_only: bool,
 
+    /// A glob like 'origin/*' or 'upstream/release-*' matched against the names of remote
+    /// branches. Can be given multiple times. If set, only matching branches are traversed,
+    /// and head is used only if no branch matches.
+    #[structopt(long = "remote-pattern", raw(number_of_values = "1"))]
+    remote_patterns: Vec<String>,
+
+    /// The name of a remote whose branches are not to be traversed. Can be given multiple times.
+    #[structopt(long = "exclude-remote", raw(number_of_values = "1"))]
+    exclude_remotes: Vec<String>,
+
+    /// If set, local branches are traversed as well as remote ones, which finds blobs of commits
+    /// that were never pushed.
+    #[structopt(long = "local-branches")]
+    local_branches: bool,
+
+    /// If set, the commits of all tags are traversed as well, which finds blobs of releases
+    /// never merged into a branch. Tags of trees or blobs are skipped with a warning.
+    #[structopt(long = "include-tags")]
+    include_tags: bool,
+
+    /// If set, the HEADs of all linked worktrees are traversed as well, which finds blobs of
+    /// detached HEADs not reachable from any branch. Locked or prunable worktrees are skipped
+    /// with a warning. Works from the main repository as well as from a linked worktree.
+    #[structopt(long = "include-worktrees")]
+    include_worktrees: bool,
+
+    /// If set, the commits of all stash entries are traversed as well, including those recording
+    /// the index and untracked files. They are marked like ':stash@{2}' or ':stash@{2}^3' in
+    /// lookup output.
+    #[structopt(long = "include-stash")]
+    include_stash: bool,
+
+    /// If set, blobs staged in the index are indexed as well, even if no commit contains them.
+    /// They are reported with ':index' among their commits.
+    #[structopt(long = "include-index")]
+    include_index: bool,
+
+    /// A glob like 'refs/remotes/origin/release/*' matched against full ref names. Can be given
+    /// multiple times. If set, only the matching refs are traversed instead of all remote
+    /// branches, and it is an error if none of them points to a commit.
+    #[structopt(long = "refs", raw(number_of_values = "1"))]
+    refs: Vec<String>,
+
+    /// If set, HEAD and every ref are traversed, including local and remote branches, tags,
+    /// notes and the stash. Refs not pointing to a commit are skipped with a warning.
+    #[structopt(long = "all")]
+    all: bool,
+
+    /// A revision like 'origin/master' or a range like 'v1.0..origin/master', whose commits are
+    /// traversed instead of all remote branches. Only two-dot ranges are supported.
+    #[structopt(long = "rev-range")]
+    rev_range: Option<String>,
+
+    /// A revision whose commits and their ancestors are not traversed. Can be given multiple times.
+    #[structopt(long = "not", raw(number_of_values = "1"))]
+    exclude_revs: Vec<String>,
+
+    /// A glob like 'refs/remotes/*/pr/*' matched against full ref names. Can be given multiple
+    /// times. Matching refs are dropped from those selected by the other options, and it is an
+    /// error if no ref remains.
+    #[structopt(long = "exclude-refs", raw(number_of_values = "1"))]
+    exclude_refs: Vec<String>,
+
+    /// Only commits committed at or after this date are indexed. Accepts ISO 8601 like
+    /// '2017-03-21' or '2017-03-21T10:20:30+01:00', relative dates like '2.years.ago' and
+    /// '@<seconds since the epoch>'.
+    #[structopt(long = "since")]
+    since: Option<String>,
+
+    /// Only commits committed at or before this date are indexed, in the formats of --since.
+    #[structopt(long = "until")]
+    until: Option<String>,
+
+    /// If set, only the first parent of merge commits is followed, which indexes the mainline
+    /// of each traversed ref without the commits of merged side branches.
+    #[structopt(long = "first-parent")]
+    first_parent: bool,
+
+    /// If set, all commits of the object database which aren't reachable from the traversed refs
+    /// are indexed as well, like those only in the reflog after a rebase. They are marked with
+    /// ':dangling' in lookup output, as they may be garbage-collected.
+    #[structopt(long = "include-dangling")]
+    include_dangling: bool,
+
+    /// If set, objects replaced with 'git replace' are indexed as they are, instead of with the
+    /// tree and history of their replacement. Like GIT_NO_REPLACE_OBJECTS.
+    #[structopt(long = "no-replace-objects")]
+    no_replace_objects: bool,
+
+    /// If set, a graph is built from a shallow REPOSITORY, listing its boundary commits.
+    /// Otherwise that is an error, as blobs beyond the boundary can't be found.
+    #[structopt(long = "allow-shallow")]
+    allow_shallow: bool,
+
+    /// Another repository whose commits are indexed into the same graph, like a fork or a backup
+    /// mirror. Can be given multiple times. Commits are then prefixed with the name of the
+    /// repository they were found in, like 'fork:<commit>', with REPOSITORY taking precedence.
+    /// Commits pinned by a gitlink are found along with the commits which pinned them.
+    #[structopt(long = "repository", parse(from_os_str), raw(number_of_values = "1"))]
+    other_repositories: Vec<PathBuf>,
+
+    /// If set, the commits of gitlinks are looked up in the initialized submodules of REPOSITORY
+    /// and their trees are indexed, attributing their blobs to the commits of the superproject.
+    /// Lookups of blobs in submodules are followed by a 'submodule <blob> <path>' record.
+    #[structopt(long = "recurse-submodules")]
+    recurse_submodules: bool,
+
+    /// If set, the commits submodules are pinned at aren't recorded. By default they are
+    /// lookup keys like blobs, finding the commits of REPOSITORY which pinned a submodule at
+    /// them. Saves memory in repositories with many submodules.
+    #[structopt(long = "no-gitlinks")]
+    no_gitlinks: bool,
+
+    /// A path like 'services/payments' or glob like 'docs/*.md' within the repository. Can be
+    /// given multiple times. If set, only blobs at or below matching paths are indexed, which
+    /// reduces memory, and lookups of other blobs find nothing. Caches remember the paths.
+    #[structopt(long = "path", raw(number_of_values = "1"))]
+    paths: Vec<String>,
+
+    /// If set, HEAD, all references and a sample of objects are read before building the graph
+    /// to fail early if the object database is corrupt. Only a fraction of a full fsck.
+    #[structopt(long = "fsck-lite")]
+    fsck_lite: bool,
+
+    /// If set and REPOSITORY is a partial clone, trees which aren't available locally are
+    /// fetched from its promisor remote with git, one at a time. Otherwise they are skipped and
+    /// counted. Blobs are indexed by their id and never need fetching.
+    #[structopt(long = "fetch-missing")]
+    fetch_missing: bool,
+
+    /// If set, the commit-graph file is ignored and commits are enumerated by reading commit
+    /// objects, which is slower but doesn't depend on the commit-graph being up to date.
+    #[structopt(long = "no-commit-graph")]
+    no_commit_graph: bool,
+
+    /// If set, only the given amount of most recent commits in topological order is indexed,
+    /// which bounds the time and memory needed to build the graph. Blobs only contained in
+    /// older commits won't be found. Also available as --max-count, like in git.
+    #[structopt(long = "max-commits", raw(alias = r#""max-count""#))]
+    max_commits: Option<usize>,
+
+    /// If set, only the given amount of entries of each tree is indexed while building the graph,
+    /// and the amount of entries skipped in wider trees is reported. Blobs in the skipped
+    /// entries won't be found.
+    #[structopt(long = "max-tree-entries")]
+    max_tree_entries: Option<usize>,
+
+    /// If set, blobs larger than the given amount of bytes aren't indexed, which keeps giant
+    /// binaries out of the graph. Only object headers are read to learn their size. Lookups of
+    /// skipped blobs are followed by a 'not-indexed <blob> size-filter' record.
+    #[structopt(long = "max-blob-size")]
+    max_blob_size: Option<u64>,
+
+    /// If set, the peak memory usage of the process is reported once the graph is built.
+    #[structopt(long = "report-memory")]
+    report_memory: bool,
+
+    /// The amount of seconds to wait for another process to save the cache before giving up.
+    /// 0 waits forever.
+    #[structopt(long = "cache-lock-timeout", default_value = "3600")]
+    cache_lock_timeout: u64,
+
+    /// The amount of seconds without any progress after which the state of all workers is
+    /// dumped to stderr, while building the graph or looking up blobs. 0 disables the watchdog.
+    #[structopt(long = "hang-timeout", default_value = "600")]
+    hang_timeout: u64,
+
+    /// If set, the process exits with code 3 once --hang-timeout was exceeded, after dumping
+    /// the state of all workers.
+    #[structopt(long = "hang-abort")]
+    hang_abort: bool,
+
+    /// Sleep for minutes once the given commit is visited while building the graph.
+    #[structopt(long = "test-hang-at", raw(hidden = "true"))]
+    test_hang_at: Option<String>,
+
+    /// A shell command behaving like 'git cat-file --batch' to obtain all objects from, instead
+    /// of opening REPOSITORY. Useful for object stores libgit2 can't read directly.
+    /// Requires --tips or --tips-from to know where to start the traversal.
+    #[structopt(long = "objects-from-batch")]
+    objects_from_batch: Option<String>,
+
+    /// A commit to start the traversal at when using --objects-from-batch.
+    /// Can be given multiple times.
+    #[structopt(long = "tips", raw(number_of_values = "1"))]
+    tips: Vec<String>,
+
+    /// A file with one commit hex-sha per line to start the traversal at when
+    /// using --objects-from-batch.
+    #[structopt(long = "tips-from", parse(from_os_str))]
+    tips_from: Option<PathBuf>,
+
     /// The path at which to look for a graph cache. If a file exists at the given path,
     /// it will be loaded as graph cache.
     /// Otherwise a graph cache will be written out before proceeding as normal.
     /// Refresh the cache by deleting the file.
-    #[structopt(name = "CACHE", long = "cache-path", parse(from_os_str))]
-    cache_path: Option<PathBuf>,
+    /// If given multiple times, all caches must exist and blobs received on stdin are looked up
+    /// in the first cache that knows them.
+    /// If it is a directory, the newest readable cache in it is used, or a new timestamped one
+    /// is written into it.
+    #[structopt(name = "CACHE", long = "cache-path", parse(from_os_str),
+                raw(number_of_values = "1"))]
+    cache_path: Vec<PathBuf>,
+
+    /// If set and --cache-path is a directory, a new cache is written into it even if there
+    /// already is a readable one.
+    #[structopt(long = "refresh-cache")]
+    refresh_cache: bool,
+
+    /// The amount of newest caches to keep if --cache-path is a directory. Older ones are deleted.
+    #[structopt(long = "keep-caches", default_value = "3")]
+    keep_caches: usize,
+
+    /// The text shown when a cache can't be read because it was written by a newer version,
+    /// e.g. where to obtain it. Defaults to the 'reconstruct.updateHint' git configuration
+    /// of REPOSITORY.
+    #[structopt(long = "update-hint")]
+    update_hint: Option<String>,
+
+    /// The oldest version which is allowed to read a newly written cache, like '1.2.0'.
+    #[structopt(long = "min-reader-version")]
+    min_reader_version: Option<lut::Version>,
+
+    /// Before each lookup, check that it finds the same commits as a plain traversal without
+    /// caches would.
+    #[structopt(long = "test-check-lookups", raw(hidden = "true"))]
+    test_check_lookups: bool,
+
+    /// Pretend a newly written cache was written by the given version.
+    #[structopt(long = "test-producer-version", raw(hidden = "true"))]
+    test_producer_version: Option<lut::Version>,
+
+    /// Pretend a newly written cache is of the given format.
+    #[structopt(long = "test-cache-format", raw(hidden = "true"))]
+    test_cache_format: Option<u32>,
+
+    /// Close a cycle through the given blob once the graph is built or loaded, by making it a
+    /// parent of its own parents.
+    #[structopt(long = "test-inject-cycle", raw(hidden = "true"))]
+    test_inject_cycle: Option<String>,
+
+    /// If set with multiple --cache-path, the commits of all caches knowing a blob are output,
+    /// instead of only the ones of the first cache.
+    #[structopt(long = "union-caches")]
+    union_caches: bool,
+
+    /// If set, hex-shas received on stdin may be surrounded by quotes, brackets and
+    /// trailing punctuation, like the ones found in JSON array dumps.
+    /// Otherwise every line must be a plain hex-sha.
+    #[structopt(long = "lenient")]
+    lenient: bool,
+
+    /// The format of records on stdin. 'oids' expects one hex-sha per record, 'raw-diff' the
+    /// output of 'git diff --raw' or 'git log --raw', taking the new blob of each entry and
+    /// writing its path followed by a tab before the commits. Deleted files are skipped.
+    /// Abbreviated hex-shas are resolved against the graph in both formats.
+    #[structopt(long = "input-format", default_value = "oids")]
+    input_format: cli::InputFormat,
+
+    /// How records on stdin are compressed, one of 'none', 'lz4' or 'auto'. 'auto' detects
+    /// lz4 frames by their magic bytes, and reads other input as is.
+    #[structopt(long = "input-compression", default_value = "none")]
+    input_compression: cli::InputCompression,
+
+    /// With --input-format=raw-diff, look up the old blob of each entry instead of the new one,
+    /// skipping added files.
+    #[structopt(long = "use-old-oid")]
+    use_old_oid: bool,
+
+    /// If set, input records are expected to be terminated by NUL, like the output of
+    /// 'git log --raw -z', while output records remain terminated by newline unless -z is set.
+    #[structopt(long = "input-z")]
+    input_z: bool,
+
+    /// What to find for each blob received on stdin. 'lookup' finds the commits containing it,
+    /// 'siblings' the other blobs in the trees directly containing it, which requires
+    /// --with-forward-edges. Output is formatted like the one of lookups.
+    #[structopt(long = "mode", default_value = "lookup")]
+    mode: cli::Mode,
+
+    /// The maximum amount of siblings output per blob with --mode=siblings.
+    #[structopt(long = "max-siblings", default_value = "1000")]
+    max_siblings: usize,
+
+    /// If set, the children of each tree are stored along with the graph and its cache, which is
+    /// needed by --mode=siblings. It about doubles the memory used for edges.
+    #[structopt(long = "with-forward-edges")]
+    with_forward_edges: bool,
+
+    /// If set, the tool fails on the first object received on stdin which is known to the graph
+    /// as another kind, like a commit fed into a lookup of blobs. One of 'blob', 'tree', 'commit'
+    /// or 'gitlink'.
+    #[structopt(long = "only-kind")]
+    only_kind: Option<lut::Kind>,
+
+    /// If set, every vertex of the graph is written to stdout as '<index> <oid> <kind> <edge-count>'
+    /// instead of looking up blobs. Use '--dump-oids=<kind>' to only list 'commit', 'tree',
+    /// 'blob' or 'gitlink' vertices.
+    #[structopt(long = "dump-oids")]
+    dump_oids: Option<Option<lut::Kind>>,
+
+    /// Instead of reading blobs from stdin, look up the given blob in the graph and compare its
+    /// commits to those found by reading every tree of REPOSITORY, like
+    /// 'git log --all --find-object' would. Differences are listed and fail the run.
+    /// It is slow, and meant to validate graphs.
+    #[structopt(long = "self-check")]
+    self_check: Option<String>,
+
+    /// If set, the commits of each blob are sorted from oldest to newest, and each is followed
+    /// by ':<commit time>' in seconds since the epoch. Requires REPOSITORY to read commit times.
+    #[structopt(long = "commit-time-output")]
+    commit_time_output: bool,
+
+    /// If set, the commits of each blob are remembered, so that blobs repeated in the input
+    /// are only looked up once. Output is the same, at the cost of memory.
+    #[structopt(long = "dedupe-input")]
+    dedupe_input: bool,
+
+    /// If set, the input and a digest of the commits found for each blob are written into a
+    /// compressed replay file at the given path, to be checked later with --check-replay.
+    #[structopt(long = "record-replay", parse(from_os_str))]
+    record_replay: Option<PathBuf>,
+
+    /// Instead of reading blobs from stdin, re-run all queries of the given replay file and
+    /// report those whose commits differ from the recorded ones.
+    #[structopt(long = "check-replay", parse(from_os_str))]
+    check_replay: Option<PathBuf>,
+
+    /// If set, all input is read and checked for malformed object ids before the graph is
+    /// built or loaded, and a report is printed to stderr. Input is buffered in memory.
+    #[structopt(long = "validate-input")]
+    validate_input: bool,
+
+    /// The fraction of malformed records between 0.0 and 1.0 above which --validate-input fails.
+    #[structopt(long = "max-invalid-fraction", default_value = "1.0")]
+    max_invalid_fraction: f64,
+
+    /// The amount of megabytes to use per thread for remembering the commits reachable from
+    /// trees traversed by multiple lookups, which speeds up looking up blobs from the same
+    /// directories. 0 disables it.
+    #[structopt(long = "subtree-cache-mb", default_value = "0")]
+    subtree_cache_mb: usize,
+
+    /// If set, commits are written to stdout as soon as they are discovered instead of
+    /// collecting all of them first, which bounds memory for blobs used by many commits.
+    #[structopt(long = "stream")]
+    stream: bool,
+
+    /// The format of lookup results on stdout. 'text' writes hex-shas, 'binary' writes a header
+    /// followed by one record per blob with its id, the path it was requested for if any, the
+    /// amount of commits and their ids, all as raw bytes, which can be turned into text with
+    /// --decode.
+    #[structopt(long = "format", default_value = "text")]
+    format: cli::OutputFormat,
+
+    /// Instead of looking up blobs, write the results stored in the given file by
+    /// --format=binary to stdout as text.
+    #[structopt(long = "decode", parse(from_os_str))]
+    decode: Option<PathBuf>,
+
+    /// If set, the commits of each blob are written to '<dir>/<blob>.txt' instead of stdout.
+    /// The directory is created if needed, and existing files are overwritten.
+    #[structopt(long = "output-dir", parse(from_os_str))]
+    output_dir: Option<PathBuf>,
+
+    /// If set, the time each blob lookup takes is measured and the given amount of
+    /// slowest lookups is reported along with their commit counts once all input was processed.
+    /// In --stream mode, the time includes writing the commits.
+    #[structopt(long = "time-lookups")]
+    time_lookups: Option<usize>,
+
+    /// What to do if a blob is not contained in any commit. 'blank' outputs an empty record,
+    /// 'report' additionally writes 'no commits for <blob>' to stderr, and 'fail' also exits
+    /// with code 2 once all input was processed.
+    #[structopt(long = "on-empty", default_value = "blank")]
+    on_empty: cli::OnEmpty,
+
+    /// If set, input records on stdin are expected to be terminated by NUL instead of newline.
+    /// Output records are terminated by NUL and each field is followed by NUL, so a record
+    /// ends with an empty field.
+    /// Lookup mode: one record per input blob, one field per commit.
+    /// Find mode: one record per candidate with its commit, matched blobs, coverage and rarity,
+    /// with --best also whether it is exact, followed by 'note' and 'evidence' records whose
+    /// fields are those of their space-separated form, ending with the path of the evidence blob.
+    #[structopt(short = "z")]
+    nul_terminated: bool,
+
+    /// How to rank candidate commits for the tree-to-integrate. 'coverage' is the fraction of
+    /// its blobs contained in a commit, 'rarity' weighs each blob by the inverse of the amount
+    /// of trees containing it, and 'combined' is the mean of both.
+    #[structopt(long = "rank-by", default_value = "combined")]
+    rank_by: find::RankBy,
+
+    /// The maximum amount of ranked candidate commits to output for the tree-to-integrate.
+    #[structopt(long = "max-candidates", default_value = "10")]
+    max_candidates: usize,
+
+    /// If set, only commits whose tree has exactly the blobs of the tree-to-integrate, and
+    /// no others, are reported. The best of the other candidates are reported as near misses
+    /// along with the difference in blob count. Requires REPOSITORY to count blobs per commit.
+    #[structopt(long = "strict-tree-match")]
+    strict_tree_match: bool,
+
+    /// The directory of the repository the tree-to-integrate corresponds to, if it is not
+    /// the repository's root. Used by --strict-tree-match to compare against this subtree.
+    #[structopt(long = "relative-to", parse(from_os_str))]
+    relative_to: Option<PathBuf>,
+
+    /// If set and --relative-to is not given, the repository directories in which the best
+    /// candidates keep the blobs of the tree-to-integrate are reported, and the best fitting
+    /// one is used as if it was passed with --relative-to.
+    #[structopt(long = "infer-relative")]
+    infer_relative: bool,
+
+    /// If set, entries of the tree-to-integrate which would be ignored by git are not hashed.
+    /// Rules are read from the given repository, or from REPOSITORY if no value is given,
+    /// using its top-level .gitignore file, info/exclude and core.excludesFile.
+    /// Pass the value as '--use-gitignore=<path>' to avoid it consuming a positional argument.
+    #[structopt(long = "use-gitignore", parse(from_os_str))]
+    use_gitignore: Option<Option<PathBuf>>,
+
+    /// If set, .gitignore files within the tree-to-integrate are honored when hashing it.
+    /// Rules in nested files take precedence over the ones of their parent directories.
+    #[structopt(long = "use-tree-gitignore")]
+    use_tree_gitignore: bool,
+
+    /// A file with '<oid> <path>' records of the blobs of the tree-to-integrate, or '-' to read
+    /// them from stdin. Records are terminated by newline, or NUL with -z. This avoids hashing
+    /// if the blob ids are known already.
+    #[structopt(long = "tree-manifest", parse(from_os_str))]
+    tree_manifest: Option<PathBuf>,
+
+    /// A repository and a tree-ish within it, like a commit, tag or tree, whose tree
+    /// should be used as tree-to-integrate.
+    /// Its blobs are read directly from the repository, which avoids hashing entirely.
+    #[structopt(long = "tree-from-repo",
+                raw(number_of_values = "2", value_names = r#"&["path", "tree-ish"]"#))]
+    tree_from_repo: Vec<String>,
 
-    /// the repository to index for queries
-    #[structopt(name = "REPOSITORY", parse(from_os_str))]
+    /// the repository to index for queries, or one of its subdirectories. Defaults to the
+    /// current directory, or GIT_DIR if set
+    #[structopt(name = "REPOSITORY", parse(from_os_str), default_value = ".")]
     repository: PathBuf,
 
     /// The directory tree for which to figure out the merge commit.
@@ -64,6 +608,8 @@ pub struct Options {
     /// receiving hex-shas of blobs, one per line, on stdin and outputting
     /// all commits knowing that blob on stdout, separated by space, terminated
     /// by newline.
+    /// If '-', a listing like the one of 'git ls-tree -r <tree>' is read from stdin instead,
+    /// which avoids hashing entirely.
     #[structopt(name = "tree-to-integrate", parse(from_os_str))]
     tree: Option<PathBuf>,
 }
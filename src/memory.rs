@@ -0,0 +1,30 @@
+//! Memory statistics of the current process, where the platform provides them.
+
+/// The peak resident set size of this process in bytes.
+pub fn peak_rss() -> Option<u64> {
+    status_field("VmHWM:")
+}
+
+/// The current resident set size of this process in bytes.
+pub fn current_rss() -> Option<u64> {
+    status_field("VmRSS:")
+}
+
+#[cfg(target_os = "linux")]
+fn status_field(name: &str) -> Option<u64> {
+    ::std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find(|l| l.starts_with(name))
+        .and_then(|l| l[name.len()..].trim().trim_right_matches("kB").trim().parse().ok())
+        .map(|kb: u64| kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn status_field(_name: &str) -> Option<u64> {
+    None
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    format!("{:.1}MiB", bytes as f64 / (1024.0 * 1024.0))
+}
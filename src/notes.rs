@@ -0,0 +1,74 @@
+use git2::{Oid, Repository};
+use lut;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub const DEFAULT_NOTES_REF: &str = "refs/notes/commits";
+const HEX_OID_LEN: usize = 40;
+
+/// Reads the notes of commits from a notes ref, remembering them as the same commits
+/// tend to be part of many results.
+pub struct Notes {
+    repo: Option<Repository>,
+    notes_ref: String,
+    max_chars: usize,
+    cache: HashMap<Oid, Option<String>>,
+}
+
+impl Notes {
+    /// Without a repository at `repository`, a warning is printed once and no notes are found.
+    pub fn open(repository: &Path, notes_ref: Option<String>, max_chars: usize) -> Notes {
+        let repo = match lut::open_repository(repository) {
+            Ok(repo) => Some(repo),
+            Err(err) => {
+                eprintln!("WARNING: Notes can't be shown without a repository: {}", err);
+                None
+            }
+        };
+        Notes {
+            repo,
+            notes_ref: notes_ref.unwrap_or_else(|| DEFAULT_NOTES_REF.into()),
+            max_chars,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// The note of `commit` on a single line, truncated to the configured amount of characters.
+    pub fn get(&mut self, commit: Oid) -> Option<&str> {
+        let (repo, notes_ref, max_chars) = (&self.repo, &self.notes_ref, self.max_chars);
+        self.cache
+            .entry(commit)
+            .or_insert_with(|| {
+                let note = repo.as_ref()?.find_note(Some(notes_ref), commit).ok()?;
+                let message = note.message()?.split_whitespace().collect::<Vec<_>>().join(" ");
+                Some(if message.chars().count() > max_chars {
+                    message.chars().take(max_chars).chain("...".chars()).collect()
+                } else {
+                    message
+                })
+            })
+            .as_ref()
+            .map(|s| s.as_str())
+    }
+
+    /// A 'note <commit> <note>' record for each commit in `record` which has a note.
+    /// Commits may be prefixed with '<repository>:' and followed by ':<time>' or ':dangling', and
+    /// ':stash@{N}' if they belong to a stash entry.
+    pub fn records(&mut self, record: &str, terminator: char) -> String {
+        let mut out = String::new();
+        for commit in record
+            .split(|c| c == ' ' || c == '\0' || c == '\n')
+            .filter(|c| !c.is_empty())
+            .filter_map(|c| {
+                c.split(':')
+                    .find(|field| field.len() == HEX_OID_LEN)
+                    .and_then(|hex| Oid::from_str(hex).ok())
+            })
+        {
+            if let Some(note) = self.get(commit) {
+                out.push_str(&format!("note {} {}{}", commit, note, terminator));
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,59 @@
+//! Matching of the paths given with --path against the paths of tree entries.
+
+use lut::glob_matches;
+
+/// How the entries at and below a path match the pathspecs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// Nothing at or below the path matches.
+    None,
+    /// Some entries below the path may match, so each of them has to be checked.
+    Partial,
+    /// The path and everything below it matches.
+    All,
+}
+
+/// The pathspecs as given on the command-line, without leading './' and trailing '/'.
+pub fn normalize(specs: &[String]) -> Vec<String> {
+    specs
+        .iter()
+        .map(|spec| {
+            let spec = spec.trim_left_matches("./").trim_right_matches('/');
+            spec.to_owned()
+        })
+        .collect()
+}
+
+/// How the tree at `path` matches any of `specs`.
+pub fn tree(specs: &[String], path: &str) -> Match {
+    if specs.iter().any(|spec| glob_matches(spec, path)) {
+        Match::All
+    } else if specs.iter().any(|spec| may_contain_matches(spec, path)) {
+        Match::Partial
+    } else {
+        Match::None
+    }
+}
+
+/// True if the blob at `path`, which is in a partially matching tree, matches any of `specs`.
+pub fn blob(specs: &[String], path: &str) -> bool {
+    specs.iter().any(|spec| glob_matches(spec, path))
+}
+
+/// `name` appended to the directory `dir`, which is empty for the root tree.
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// True if paths below the directory `dir` can match `spec`, judged by the part of `spec`
+/// before its first wildcard.
+fn may_contain_matches(spec: &str, dir: &str) -> bool {
+    let literal_len = spec.find(|c| c == '*' || c == '?').unwrap_or(spec.len());
+    let (literal, has_wildcard) = (&spec[..literal_len], literal_len < spec.len());
+    let dir = format!("{}/", dir);
+    literal.starts_with(&dir) || (has_wildcard && dir.starts_with(literal))
+}
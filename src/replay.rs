@@ -0,0 +1,182 @@
+use bincode::{self, deserialize_from, serialize_into};
+use failure::{Error, ResultExt};
+use git2::Oid;
+use lut::ReverseGraph;
+use lz4;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+/// Result sets with up to this many commits are stored in full, larger ones only as digest.
+const FULL_RESULT_MAX_COMMITS: usize = 16;
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Header {
+        options: String,
+        graph: u64,
+    },
+    Query {
+        input: String,
+        blob: String,
+        num_commits: usize,
+        digest: u64,
+        commits: Option<Vec<String>>,
+    },
+}
+
+fn fnv1a(state: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(state, |h, &b| (h ^ u64::from(b)).wrapping_mul(FNV_PRIME))
+}
+
+/// A digest over all vertices and the amount of their edges, stable across platforms and versions.
+pub fn graph_digest(graphs: &[ReverseGraph]) -> u64 {
+    graphs.iter().fold(FNV_OFFSET_BASIS, |h, graph| {
+        (0..graph.len()).fold(h, |h, idx| {
+            fnv1a(
+                fnv1a(h, graph.oid_of(idx).as_bytes()),
+                graph.edge_count(idx).to_string().as_bytes(),
+            )
+        })
+    })
+}
+
+/// `commits` must be sorted.
+fn result_digest(commits: &[String]) -> u64 {
+    commits
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |h, c| fnv1a(fnv1a(h, c.as_bytes()), b"\n"))
+}
+
+/// Writes the queries of a run into a compressed replay file.
+pub struct Recorder {
+    out: lz4::Encoder<BufWriter<File>>,
+    commits: Vec<String>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, options: String, graph: u64) -> Result<Recorder, Error> {
+        let file = File::create(path)
+            .with_context(|_| format!("Could not create replay file at '{}'", path.display()))?;
+        let mut out = lz4::EncoderBuilder::new().build(BufWriter::new(file))?;
+        serialize_into(&mut out, &Record::Header { options, graph })?;
+        Ok(Recorder {
+            out,
+            commits: Vec::new(),
+        })
+    }
+
+    /// Record the query for `input`, resolved to `blob`, which produced the given output record.
+    pub fn record(&mut self, input: &str, blob: &Oid, output: &str) -> Result<(), Error> {
+        self.commits.clear();
+        self.commits.extend(
+            output
+                .split(|c| c == ' ' || c == '\0' || c == '\n')
+                .filter(|c| !c.is_empty())
+                .map(ToOwned::to_owned),
+        );
+        self.commits.sort();
+        serialize_into(
+            &mut self.out,
+            &Record::Query {
+                input: input.to_owned(),
+                blob: blob.to_string(),
+                num_commits: self.commits.len(),
+                digest: result_digest(&self.commits),
+                commits: if self.commits.len() <= FULL_RESULT_MAX_COMMITS {
+                    Some(self.commits.clone())
+                } else {
+                    None
+                },
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        let (_, result) = self.out.finish();
+        result.map_err(Into::into)
+    }
+}
+
+/// Re-run all queries recorded at `path` using `lookup`, which resolves an input line to a blob
+/// and its commits. Differences are reported on stderr, and their amount is returned.
+pub fn check(
+    path: &Path,
+    options: &str,
+    graph: u64,
+    mut lookup: impl FnMut(&str) -> Result<(Oid, Vec<String>), Error>,
+) -> Result<usize, Error> {
+    let file = File::open(path)
+        .with_context(|_| format!("Could not open replay file at '{}'", path.display()))?;
+    let mut input = lz4::Decoder::new(BufReader::new(file))?;
+    let (mut num_queries, mut num_differing) = (0, 0);
+    loop {
+        let record = match deserialize_from(&mut input) {
+            Ok(record) => record,
+            Err(err) => {
+                if let bincode::ErrorKind::Io(ref io_err) = *err {
+                    if io_err.kind() == io::ErrorKind::UnexpectedEof {
+                        break;
+                    }
+                }
+                return Err(err.into());
+            }
+        };
+        match record {
+            Record::Header {
+                options: ref recorded_options,
+                graph: recorded_graph,
+            } => {
+                if recorded_options != options {
+                    eprintln!(
+                        "NOTE: Options differ - recorded '{}', now '{}'",
+                        recorded_options, options
+                    );
+                }
+                if recorded_graph != graph {
+                    eprintln!("NOTE: The graph differs from the one used when recording");
+                }
+            }
+            Record::Query {
+                input: line,
+                blob,
+                num_commits,
+                digest,
+                commits,
+            } => {
+                num_queries += 1;
+                let (oid, mut now) = lookup(&line)?;
+                now.sort();
+                if oid.to_string() == blob && result_digest(&now) == digest {
+                    continue;
+                }
+                num_differing += 1;
+                eprintln!(
+                    "query {} for {} differs: {} commits recorded, {} now",
+                    num_queries,
+                    blob,
+                    num_commits,
+                    now.len()
+                );
+                if let Some(recorded) = commits {
+                    for commit in recorded.iter().filter(|c| !now.contains(c)) {
+                        eprintln!("  - {}", commit);
+                    }
+                    for commit in now.iter().filter(|c| !recorded.contains(c)) {
+                        eprintln!("  + {}", commit);
+                    }
+                }
+            }
+        }
+    }
+    eprintln!(
+        "Checked {} queries, {} of which differ",
+        num_queries, num_differing
+    );
+    Ok(num_differing)
+}
@@ -0,0 +1,182 @@
+use git2::Oid;
+use memory;
+use std::cell::RefCell;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const HANG_EXIT_CODE: i32 = 3;
+/// How long the hidden test hook sleeps on its commit - long enough to trigger any test timeout.
+pub const TEST_HANG_SECONDS: u64 = 180;
+const POLL_INTERVAL_MS: u64 = 250;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Heartbeat>>> = RefCell::new(None);
+}
+
+/// Signs of life of a single worker thread, as observed by the `Watchdog`.
+pub struct Heartbeat {
+    /// False if the watchdog is disabled, which makes beats no-ops.
+    enabled: bool,
+    name: String,
+    kind: &'static str,
+    beats: AtomicUsize,
+    last_beat: Mutex<Option<(Oid, Instant)>>,
+    last_tree: Mutex<Option<Oid>>,
+}
+
+impl Heartbeat {
+    /// Signal progress on `oid`, which is of the kind this heartbeat was registered with.
+    pub fn beat(&self, oid: Oid) {
+        if !self.enabled {
+            return;
+        }
+        *self.last_beat.lock().expect("no poisoned lock") = Some((oid, Instant::now()));
+        self.beats.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Remember `oid` as the tree currently recursed into by this thread, if it is watched.
+/// This doesn't count as progress.
+pub fn tree(oid: Oid) {
+    CURRENT.with(|current| {
+        if let Some(ref heartbeat) = *current.borrow() {
+            *heartbeat.last_tree.lock().expect("no poisoned lock") = Some(oid);
+        }
+    })
+}
+
+/// Watches the heartbeats of worker threads and reports them if none of them made any progress
+/// for the configured amount of time, optionally aborting the process.
+pub struct Watchdog {
+    heartbeats: Arc<Mutex<Vec<Arc<Heartbeat>>>>,
+    stop: Arc<AtomicBool>,
+    enabled: bool,
+}
+
+impl Watchdog {
+    /// A `timeout` of 0 seconds disables the watchdog, making all heartbeats no-ops.
+    pub fn spawn(timeout: u64, abort: bool) -> Watchdog {
+        let heartbeats = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        if timeout > 0 {
+            let heartbeats = Arc::clone(&heartbeats);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || watch(&heartbeats, &stop, Duration::from_secs(timeout), abort));
+        }
+        Watchdog {
+            heartbeats,
+            stop,
+            enabled: timeout > 0,
+        }
+    }
+
+    /// Create a heartbeat for the calling thread, which makes it available to `tree()` unless
+    /// the watchdog is disabled.
+    pub fn register(&self, name: &str, kind: &'static str) -> Arc<Heartbeat> {
+        let heartbeat = Arc::new(Heartbeat {
+            enabled: self.enabled,
+            name: name.to_owned(),
+            kind,
+            beats: AtomicUsize::new(0),
+            last_beat: Mutex::new(None),
+            last_tree: Mutex::new(None),
+        });
+        self.heartbeats
+            .lock()
+            .expect("no poisoned lock")
+            .push(Arc::clone(&heartbeat));
+        let current_heartbeat = if self.enabled {
+            Some(Arc::clone(&heartbeat))
+        } else {
+            None
+        };
+        CURRENT.with(|current| *current.borrow_mut() = current_heartbeat);
+        heartbeat
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn watch(heartbeats: &Mutex<Vec<Arc<Heartbeat>>>, stop: &AtomicBool, timeout: Duration, abort: bool) {
+    let poll_interval = Duration::from_millis(POLL_INTERVAL_MS).min(timeout);
+    let mut last_total = 0;
+    let mut last_change = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        thread::sleep(poll_interval);
+        let heartbeats = heartbeats.lock().expect("no poisoned lock");
+        let total = heartbeats
+            .iter()
+            .map(|h| h.beats.load(Ordering::Relaxed))
+            .sum();
+        if total != last_total {
+            last_total = total;
+            last_change = Instant::now();
+            continue;
+        }
+        if last_change.elapsed() < timeout || stop.load(Ordering::Relaxed) {
+            continue;
+        }
+        eprintln!(
+            "WARNING: No progress for {}s, the process might be hung",
+            last_change.elapsed().as_secs()
+        );
+        dump(&heartbeats);
+        if abort {
+            eprintln!("Aborting as requested by --hang-abort");
+            process::exit(HANG_EXIT_CODE);
+        }
+        last_change = Instant::now();
+    }
+}
+
+fn dump(heartbeats: &[Arc<Heartbeat>]) {
+    for heartbeat in heartbeats {
+        let last_tree = *heartbeat.last_tree.lock().expect("no poisoned lock");
+        match *heartbeat.last_beat.lock().expect("no poisoned lock") {
+            Some((oid, at)) => eprintln!(
+                "thread '{}': last {} {} {}s ago, {} in total, last tree {}",
+                heartbeat.name,
+                heartbeat.kind,
+                oid,
+                at.elapsed().as_secs(),
+                heartbeat.beats.load(Ordering::Relaxed),
+                last_tree.map_or_else(|| "none".into(), |t| t.to_string())
+            ),
+            None => eprintln!("thread '{}': no {} seen yet", heartbeat.name, heartbeat.kind),
+        }
+    }
+    if let (Some(current), Some(peak)) = (memory::current_rss(), memory::peak_rss()) {
+        eprintln!(
+            "memory: {} resident, {} at peak",
+            memory::format_bytes(current),
+            memory::format_bytes(peak)
+        );
+    }
+    kernel_stacks();
+}
+
+/// Print the kernel stacks of all our threads, which is only permitted with sufficient privileges.
+#[cfg(target_os = "linux")]
+fn kernel_stacks() {
+    use std::fs;
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(_) => return,
+    };
+    for task in tasks.filter_map(Result::ok) {
+        if let Ok(stack) = fs::read_to_string(task.path().join("stack")) {
+            eprintln!("kernel stack of task {}:", task.file_name().to_string_lossy());
+            eprint!("{}", stack);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_stacks() {}
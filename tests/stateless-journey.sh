@@ -11,6 +11,7 @@ fixture="$root/fixtures"
 snapshot="$root/snapshots"
 
 SUCCESSFULLY=0
+WITH_FAILURE=1
 commit=dc595f7f016a0cff8b176a4c1e67483986f14816
 
 title "Frontend mode - find merge commit"
@@ -37,6 +38,222 @@ title "Frontend mode - find merge commit"
         )
       )
     )
+    (with "ignore rules"
+      (sandbox '
+        git init -q ignoring &&
+        echo "*.swp" > ignoring/.gitignore &&
+        echo "*.log" > ignoring/.git/info/exclude &&
+        echo "*.tmp" > global-excludes &&
+        git -C ignoring config core.excludesFile "$PWD/global-excludes" &&
+        cp -R "$fixture/tree" tree &&
+        touch tree/a.swp tree/b.log tree/c.tmp tree/etc/keep.swp &&
+        echo "!keep.swp" > tree/etc/.gitignore
+      '
+        (when "using the rules of a repository"
+          it "ignores entries matched by .gitignore, info/exclude and core.excludesFile" && {
+            expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --use-gitignore=ignoring '$fixture/repo' tree 2>&1 | grep -q 'Ignored 4 entries'"
+          }
+        )
+        (when "additionally using the rules within the tree"
+          it "lets nested .gitignore files override their parents" && {
+            expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --use-gitignore=ignoring --use-tree-gitignore '$fixture/repo' tree 2>&1 | grep -q 'Ignored 3 entries'"
+          }
+        )
+      )
+    )
+    (with "a tree read from another repository"
+      it "succeeds without hashing" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --tree-from-repo '$fixture/repo' HEAD '$fixture/repo' 2>&1 | grep -q 'blobs from git tree'"
+      }
+    )
+    (with "a listing of the tree read from stdin"
+      it "succeeds without hashing" && {
+        expect_run_sh ${SUCCESSFULLY} "git --git-dir '$fixture/repo' ls-tree -r HEAD | '$exe' --head-only '$fixture/repo' - 2>&1 | grep -q 'blobs from listing'"
+      }
+    )
+    (with "a manifest of the blobs of the tree"
+      (sandbox '
+        git --git-dir "$fixture/repo" ls-tree -r HEAD | grep " blob " | sed "s/^[0-9]* blob \([0-9a-f]*\)\t/\1 /" > manifest
+      '
+        it "finds HEAD with full coverage without hashing" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --tree-manifest manifest --max-candidates 1 '$fixture/repo' 2>/dev/null | grep -q \"^\$(git --git-dir '$fixture/repo' rev-parse HEAD) .* coverage=100.0%\""
+        }
+        it "reads the manifest from stdin with NUL-terminated records" && {
+          expect_run_sh ${SUCCESSFULLY} "tr '\n' '\0' < manifest | '$exe' -z --head-only --tree-manifest - --max-candidates 1 '$fixture/repo' 2>/dev/null | grep -q \"^\$(git --git-dir '$fixture/repo' rev-parse HEAD) .* coverage=100.0%\""
+        }
+        it "fails on malformed records, reporting them" && {
+          expect_run_sh ${WITH_FAILURE} "set -o pipefail; (cat manifest; echo 'not-a-sha some/path') > malformed && '$exe' --head-only --tree-manifest malformed '$fixture/repo' 2>&1 | grep -q 'malformed manifest record .*not-a-sha'"
+        }
+      )
+    )
+    (with "a hash cache (--hash-cache)"
+      (sandbox '
+        cp -R "$fixture/tree" tree &&
+        "$exe" --head-only "$fixture/repo" tree > expected 2>/dev/null
+      '
+        it "finds the same candidates while filling the hash cache" && {
+          expect_run_sh ${SUCCESSFULLY} "diff expected <('$exe' --head-only --hash-cache hashes '$fixture/repo' tree 2>/dev/null)"
+        }
+        it "hashes no unchanged file again" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --hash-cache hashes '$fixture/repo' tree 2>&1 >/dev/null | grep -q 'hashed 0 files'"
+        }
+        it "resumes from the hash cache of an interrupted run with the same results" && {
+          expect_run_sh ${SUCCESSFULLY} "head -c 80 hashes > partial && diff expected <('$exe' --head-only --hash-cache partial '$fixture/repo' tree 2>/dev/null)"
+        }
+        it "hashes changed files again" && {
+          expect_run_sh ${SUCCESSFULLY} "echo changed >> \"\$(find tree -type f | sort | head -1)\" && '$exe' --head-only --hash-cache hashes '$fixture/repo' tree 2>&1 >/dev/null | grep -q 'hashed 1 files'"
+        }
+      )
+    )
+    (with "a tree sharing many common blobs with the wrong commit and few rare ones with the right one"
+      (sandbox '
+        git init -q ranked && cd ranked &&
+        for c in 1 2 3; do echo "common $c" > common-$c; done &&
+        for n in 1 2 3 4; do
+          echo "other $n" > other && git add . && git -c user.name=a -c user.email=b commit -qm "wrong $n"
+        done &&
+        git rm -q common-* other && echo "rare 1" > rare-1 && echo "rare 2" > rare-2 &&
+        git add . && git -c user.name=a -c user.email=b commit -qm right && cd .. &&
+        mkdir tree && cp ranked/rare-* tree/ && for c in 1 2 3; do echo "common $c" > tree/common-$c; done
+      '
+        it "ranks the right commit first by rarity" && {
+          expect_run_sh ${SUCCESSFULLY} "test \"\$('$exe' --head-only --rank-by rarity --max-candidates 1 ranked tree 2>/dev/null | cut -d ' ' -f 1)\" = \$(git -C ranked rev-parse HEAD)"
+        }
+        it "outputs only the best commit as partial match with --best" && {
+          expect_run_sh 4 "set -o pipefail; '$exe' --head-only --best ranked tree 2>/dev/null | grep -q ' partial '"
+        }
+        it "does not rank the right commit first by coverage" && {
+          expect_run_sh ${SUCCESSFULLY} "test \"\$('$exe' --head-only --rank-by coverage --max-candidates 1 ranked tree 2>/dev/null | cut -d ' ' -f 1)\" != \$(git -C ranked rev-parse HEAD)"
+        }
+      )
+    )
+    (with "a tree whose files were stored through a clean filter"
+      (sandbox '
+        git init -q filtered && echo CONTENT > filtered/file && git -C filtered add . &&
+        git -C filtered -c user.name=a -c user.email=b commit -qm initial &&
+        mkdir tree && echo content > tree/file
+      '
+        it "finds the commit when hashing the filtered content with --hash-filter" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --hash-filter 'tr a-z A-Z' filtered tree 2>/dev/null | grep -q \"\$(git -C filtered rev-parse HEAD) 1/1\""
+        }
+        it "does not find it without the filter" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only filtered tree 2>/dev/null | grep -c \$(git -C filtered rev-parse HEAD) | grep -qx 0"
+        }
+      )
+    )
+    (with "two near-identical candidate commits"
+      (sandbox '
+        git init -q near && cd near && mkdir one two && echo a > one/a && echo b > two/b &&
+        echo c > two/c && git add . && git -c user.name=a -c user.email=b commit -qm before &&
+        echo changed > two/c && git add . && git -c user.name=a -c user.email=b commit -qm after && cd .. &&
+        mkdir tree && cp -R near/one near/two tree/
+      '
+        it "selects the blob distinguishing the best candidate from the other as evidence" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --evidence 1 near tree 2>/dev/null | grep -q \"^evidence \$(git -C near rev-parse HEAD:two/c) .* absent-from=\$(git -C near rev-parse HEAD~1) two/c\\$\""
+        }
+      )
+    )
+    (with "the worktree of the repository itself as tree"
+      (sandbox '
+        git init -q worktree && cd worktree && echo content > file && echo "*.log" > .gitignore &&
+        git add . && git -c user.name=a -c user.email=b commit -qm initial && echo output > build.log
+      '
+        it "excludes .git and ignored files and finds HEAD with all blobs" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only worktree worktree 2>/dev/null | grep -q \"\$(git -C worktree rev-parse HEAD) 2/2\""
+        }
+        it "warns about it" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only worktree worktree 2>&1 >/dev/null | grep -q 'inside the worktree'"
+        }
+      )
+    )
+    (with "a git directory separate from its worktree"
+      (sandbox '
+        git init -q --bare split.git && mkdir wt && echo content > wt/file && echo "*.log" > wt/.gitignore &&
+        git --git-dir split.git --work-tree wt add . &&
+        git --git-dir split.git --work-tree wt -c user.name=a -c user.email=b commit -qm initial &&
+        echo output > wt/build.log
+      '
+        it "excludes ignored files of the worktree given with --work-tree" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --work-tree wt split.git wt 2>/dev/null | grep -q \"\$(git --git-dir split.git rev-parse HEAD) 2/2\""
+        }
+        it "honors GIT_WORK_TREE" && {
+          expect_run_sh ${SUCCESSFULLY} "GIT_WORK_TREE=\$PWD/wt '$exe' --head-only split.git wt 2>&1 >/dev/null | grep -q 'inside the worktree'"
+        }
+        it "fails if the worktree is no directory" && {
+          expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --head-only --work-tree missing split.git wt 2>&1 | grep -q 'is not a directory'"
+        }
+      )
+    )
+    (with "a tree containing a path with an embedded newline"
+      (sandbox '
+        git init -q newline && cd newline && echo content > "$(printf "new\nline")" && git add . &&
+        git -c user.name=a -c user.email=b commit -qm initial && cd .. &&
+        mkdir tree && cp newline/* tree/
+      '
+        it "keeps the path of evidence intact with -z" && {
+          expect_run_sh ${SUCCESSFULLY} "test \"\$('$exe' --head-only --evidence 1 -z newline tree 2>/dev/null | tr '\\0' '\\n' | grep -A 5 '^evidence\$' | sed -n 5,6p)\" = \"\$(printf 'new\\nline')\""
+        }
+        it "mangles it without -z" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --evidence 1 newline tree 2>/dev/null | grep -qx 'line'"
+        }
+      )
+    )
+    (with "a tree whose blobs are mostly contained in a single commit"
+      (sandbox '
+        git init -q leading && cd leading && echo base > base && git add . &&
+        git -c user.name=a -c user.email=b commit -qm base &&
+        for n in $(seq 40); do echo "file $n" > file-$n; done &&
+        git add . && git -c user.name=a -c user.email=b commit -qm leader && cd .. &&
+        mkdir tree && cp leading/* tree/
+      '
+        it "stops early once the leader can't be caught by its combined score" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --threads 1 --head-only --early-stop leading tree 2>err >/dev/null && grep -q 'Stopped early' err && grep -q \"combined score .* - the leader can't be caught\" err"
+        }
+        it "finds the same winner as a full run with the default ranking" && {
+          expect_run_sh ${SUCCESSFULLY} "test \"\$('$exe' --threads 1 --head-only --early-stop --max-candidates 1 leading tree 2>/dev/null | cut -d ' ' -f 1)\" = \"\$('$exe' --head-only --max-candidates 1 leading tree 2>/dev/null | cut -d ' ' -f 1)\""
+        }
+        it "finds the same winner as a full run" && {
+          expect_run_sh ${SUCCESSFULLY} "test \"\$('$exe' --threads 1 --head-only --early-stop --rank-by coverage --max-candidates 1 leading tree 2>/dev/null | cut -d ' ' -f 1)\" = \"\$('$exe' --head-only --rank-by coverage --max-candidates 1 leading tree 2>/dev/null | cut -d ' ' -f 1)\""
+        }
+      )
+    )
+    (with "a tree equal to the one of HEAD"
+      it "outputs HEAD as exact match with --best" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --best --tree-from-repo '$fixture/repo' HEAD '$fixture/repo' 2>/dev/null | grep -q 'exact'"
+      }
+      it "finds HEAD as exact match with --strict-tree-match" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --strict-tree-match --tree-from-repo '$fixture/repo' HEAD '$fixture/repo' 2>/dev/null | grep -q \$(git --git-dir '$fixture/repo' rev-parse HEAD)"
+      }
+    )
+    (with "a tree equal to a subdirectory of HEAD"
+      (sandbox '
+        mkdir tree && git --git-dir "$fixture/repo" archive HEAD:etc | tar -x -C tree
+      '
+        it "infers the subdirectory and finds HEAD as exact match" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --strict-tree-match --infer-relative '$fixture/repo' tree 2>&1 | grep -q \"Best fitting prefix: 'etc'\""
+        }
+        it "finds HEAD as exact match with --relative-to" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --strict-tree-match --relative-to etc '$fixture/repo' tree 2>/dev/null | grep -q \$(git --git-dir '$fixture/repo' rev-parse HEAD)"
+        }
+      )
+    )
+    (with "a subdirectory of HEAD vendored into another repository"
+      (sandbox '
+        git init -q vendoring && cd vendoring && mkdir -p third_party/etc && echo own > own &&
+        git --git-dir "$fixture/repo" archive HEAD:etc | tar -x -C third_party/etc && git add . &&
+        git -c user.name=a -c user.email=b commit -qm vendored && cd ..
+      '
+        it "infers the subdirectory from the vendored tree" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --strict-tree-match --infer-relative --tree-from-repo vendoring HEAD:third_party/etc '$fixture/repo' 2>&1 | grep -q \"Best fitting prefix: 'etc'\""
+        }
+        it "finds HEAD of the originating repository as exact match" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --strict-tree-match --relative-to etc --tree-from-repo vendoring HEAD:third_party/etc '$fixture/repo' 2>/dev/null | grep -q \$(git --git-dir '$fixture/repo' rev-parse HEAD)"
+        }
+        it "finds nothing for the tree of the vendoring commit" && {
+          expect_run_sh ${SUCCESSFULLY} "! ('$exe' --head-only --strict-tree-match --tree-from-repo vendoring HEAD '$fixture/repo' 2>/dev/null | grep -q \$(git --git-dir '$fixture/repo' rev-parse HEAD))"
+        }
+      )
+    )
     (with "no cache specified"
       it "succeeds" && {
         WITH_SNAPSHOT="$snapshot/generate-merge-commit-info-success" \
@@ -52,10 +269,1138 @@ title "Frontend mode - find merge commit"
       | expect_run ${SUCCESSFULLY} "$exe" --head-only "$fixture/repo"
     }
   )
+  (when "streaming results"
+    it "produces the same output as without streaming" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --stream --head-only '$fixture/repo' 2>/dev/null)"
+    }
+  )
+  (when "the repository has a commit-graph file"
+    (sandbox '
+      cp -R "$fixture/repo" repo && mkdir -p repo/refs &&
+      git --git-dir repo commit-graph write --reachable 2>/dev/null || true
+    '
+      if [ -e repo/objects/info/commit-graph ]; then
+        it "uses it to obtain trees" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --dump-oids repo 2>&1 >/dev/null | grep -q 'Used commit-graph'"
+        }
+        it "builds the same graph as without it, with vertices possibly in another order" && {
+          expect_run_sh ${SUCCESSFULLY} "diff <('$exe' --head-only --dump-oids '$fixture/repo' 2>/dev/null | cut -d ' ' -f 2- | sort) <('$exe' --head-only --dump-oids repo 2>/dev/null | cut -d ' ' -f 2- | sort)"
+        }
+        it "uses it to enumerate commits" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --dump-oids repo 2>&1 >/dev/null | grep -q 'Enumerating [0-9]* commits using the commit-graph'"
+        }
+        it "finds the same commits as the revwalk" && {
+          expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only --no-commit-graph repo 2>/dev/null | tr ' ' '\n' | sort) <(echo $commit | '$exe' --head-only repo 2>/dev/null | tr ' ' '\n' | sort)"
+        }
+        it "falls back to the revwalk with --no-commit-graph" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --no-commit-graph --dump-oids repo 2>&1 >/dev/null | grep -q 'revwalk as --no-commit-graph is set'"
+        }
+        it "falls back to the revwalk if it is stale" && {
+          expect_run_sh ${SUCCESSFULLY} "git --git-dir repo update-ref HEAD \$(git --git-dir repo commit-tree -p HEAD -m stale HEAD^{tree}) && '$exe' --head-only --dump-oids repo 2>&1 >/dev/null | grep -q 'revwalk as the commit-graph is stale'"
+        }
+        it "falls back to the revwalk if its fanout table isn't sorted" && {
+          expect_run_sh ${SUCCESSFULLY} "cp -R repo unsorted && printf '\\377\\377\\377\\377' | dd of=unsorted/objects/info/commit-graph bs=1 seek=\$(od -An -tu8 --endian=big -j 12 -N 8 unsorted/objects/info/commit-graph | tr -d ' ') conv=notrunc 2>/dev/null && '$exe' --head-only --dump-oids unsorted 2>&1 >/dev/null | grep -q \"fanout table which isn't sorted, ignoring it\""
+        }
+      fi
+    )
+  )
+  (when "reporting memory usage (--report-memory)"
+    it "includes the peak memory in the summary" && {
+      expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --head-only --report-memory '$fixture/repo' 2>&1 >/dev/null | grep -q '^READY:.*, peak memory'"
+    }
+    it "includes the memory of object ids" && {
+      expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --head-only --report-memory '$fixture/repo' 2>&1 >/dev/null | grep -q '^READY:.*, object ids use [0-9.]*MiB, saving at least [0-9.]*MiB'"
+    }
+  )
+  (when "compacting the graph once it is built"
+    it "reports the memory reclaimed from parent-edges in the READY line with --report-memory" && {
+      expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --head-only --report-memory --threads 2 '$fixture/repo' 2>&1 >/dev/null | grep -q '^READY:.*, parent-edges compacted from [0-9.]*MiB to [0-9.]*MiB, reclaiming [0-9.]*MiB'"
+    }
+    it "doesn't report it otherwise" && {
+      expect_run_sh ${SUCCESSFULLY} "! echo $commit | '$exe' --head-only '$fixture/repo' 2>&1 >/dev/null | grep -q 'compacted'"
+    }
+  )
+  (when "dumping all vertices (--dump-oids)"
+    it "lists one vertex per commit when filtering by kind" && {
+      expect_run_sh ${SUCCESSFULLY} "test \$('$exe' --head-only --dump-oids=commit '$fixture/repo' 2>/dev/null | wc -l) = \$(git --git-dir '$fixture/repo' rev-list --count HEAD)"
+    }
+    it "lists blobs with their kind" && {
+      expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --dump-oids '$fixture/repo' 2>/dev/null | grep -q ' $commit blob '"
+    }
+    it "reports the vertex with the most parent-edges" && {
+      expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --dump-oids '$fixture/repo' 2>&1 >/dev/null | grep -q \"Most referenced vertex: .* \$('$exe' --head-only --dump-oids '$fixture/repo' 2>/dev/null | cut -d' ' -f4 | sort -n | tail -1) parent-edges\""
+    }
+  )
+  (when "reporting what the graph covers"
+    (sandbox '
+      echo | "$exe" --head-only --cache-path c.bincode "$fixture/repo" 2>/dev/null
+    '
+      it "reports a fresh build with the amount of commits it indexed" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only '$fixture/repo' 2>&1 | grep -A2 'COVERAGE: fresh build' | grep -q \"commits: *\$(git --git-dir '$fixture/repo' rev-list --count HEAD)\""
+      }
+      it "reports the cache and the traversal it was built with" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --cache-path c.bincode '$fixture/repo' 2>&1 | grep -A1 \"COVERAGE: cache at 'c.bincode', written less than a minute ago\" | grep -q 'traversal: head-only=true'"
+      }
+    )
+  )
+  (when "declaring the kind of all input objects (--only-kind)"
+    it "looks up objects of the declared kind" && {
+      expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --head-only --only-kind blob '$fixture/repo'"
+    }
+    it "fails on an object of another kind, naming its record" && {
+      expect_run_sh ${WITH_FAILURE} "set -o pipefail; printf '$commit\n%s\n' \$(git --git-dir '$fixture/repo' rev-parse HEAD) | '$exe' --head-only --only-kind blob '$fixture/repo' 2>&1 | grep 'Input record 2 is the commit'"
+    }
+  )
+  (when "loading caches written by other versions"
+    (sandbox '
+      for v in 2.0.0 1.9.0 0.9.0; do
+        echo | "$exe" --head-only --cache-path $v.bincode --test-producer-version $v "$fixture/repo" 2>/dev/null
+      done &&
+      echo | "$exe" --head-only --cache-path old-format.bincode --test-cache-format 1 "$fixture/repo" 2>/dev/null &&
+      git init -q hinted && git -C hinted config reconstruct.updateHint "Ask the data team."
+    '
+      it "refuses a cache of a newer major version, naming the version needed and the hint" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo $commit | '$exe' --cache-path 2.0.0.bincode --update-hint 'Get it from the portal.' '$fixture/repo' 2>&1 | grep 'at least version 2.0.0.*Get it from the portal.'"
+      }
+      it "takes the hint from the git configuration" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo $commit | '$exe' --cache-path 2.0.0.bincode hinted 2>&1 | grep 'Ask the data team.'"
+      }
+      it "asks to rebuild a cache of an older format instead of suggesting an update" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo $commit | '$exe' --cache-path old-format.bincode --update-hint 'Get it from the portal.' '$fixture/repo' 2>&1 | grep 'Cache format 1 is no longer supported.*Delete or rebuild the cache at .old-format.bincode.' | grep -v 'portal'"
+      }
+      it "loads a cache of a newer minor version" && {
+        expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --cache-path 1.9.0.bincode '$fixture/repo'"
+      }
+      it "loads a cache of an older version" && {
+        expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --cache-path 0.9.0.bincode '$fixture/repo'"
+      }
+      it "warns when requiring a newer reader than the format needs" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --cache-path min.bincode --min-reader-version 1.5.0 '$fixture/repo' 2>&1 | grep -q 'WARNING: --min-reader-version 1.5.0'"
+      }
+      it "refuses the cache requiring a newer reader" && {
+        expect_run_sh ${WITH_FAILURE} "echo $commit | '$exe' --cache-path min.bincode '$fixture/repo'"
+      }
+    )
+  )
+  (when "saving the same cache concurrently"
+    (sandbox '
+      touch a.bincode.lock
+      echo | "$exe" --head-only --cache-path a.bincode "$fixture/repo" 2>/dev/null &
+      echo | "$exe" --head-only --cache-path a.bincode "$fixture/repo" 2>/dev/null &
+      sleep 1 && rm a.bincode.lock
+      wait
+    '
+      it "leaves a cache which loads" && {
+        expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --cache-path a.bincode '$fixture/repo' >/dev/null"
+      }
+      it "leaves a cache which finds the same commits as without cache" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --cache-path a.bincode '$fixture/repo' 2>/dev/null)"
+      }
+      it "removes the lockfile and the temporary file" && {
+        expect_run_sh ${SUCCESSFULLY} "test \$(ls | grep -c '\.lock\$\|\.tmp\$') = 0"
+      }
+      it "waits for the lock of another process before saving" && {
+        expect_run_sh ${SUCCESSFULLY} "touch b.bincode.lock; (sleep 1; rm b.bincode.lock) & echo | '$exe' --head-only --cache-path b.bincode '$fixture/repo' 2>&1 | grep -q 'Waiting for another process to save the cache'"
+      }
+      it "removes the lock of a process which is not running anymore" && {
+        expect_run_sh ${SUCCESSFULLY} "sh -c 'echo \$\$' > c.bincode.lock && echo | '$exe' --head-only --cache-path c.bincode '$fixture/repo' 2>&1 | grep -q 'Removing stale cache lock' && test -f c.bincode && test ! -e c.bincode.lock"
+      }
+      it "gives up waiting for a held lock after the timeout, naming the lock file" && {
+        expect_run_sh ${SUCCESSFULLY} "echo \$\$ > d.bincode.lock && ! echo | '$exe' --head-only --cache-lock-timeout 1 --cache-path d.bincode '$fixture/repo' 2>err && grep -q 'Gave up waiting for the cache lock at .d.bincode.lock' err && test ! -e d.bincode"
+      }
+    )
+  )
+  (when "using a directory of caches"
+    (sandbox '
+      mkdir caches && echo | "$exe" --head-only --cache-path caches "$fixture/repo" 2>/dev/null
+    '
+      it "wrote a timestamped cache into it" && {
+        expect_run_sh ${SUCCESSFULLY} "test \$(ls caches | grep -c '^cache-.*\.bincode\$') = 1"
+      }
+      it "uses the newest readable cache, skipping unreadable ones" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --cache-path caches/cache-9999999999999.bincode --test-producer-version 2.0.0 '$fixture/repo' 2>/dev/null && echo $commit | '$exe' --head-only --cache-path caches '$fixture/repo' 2>&1 >/dev/null | grep -q 'Skipping cache at .caches/cache-9999999999999.bincode'"
+      }
+      it "keeps only the newest caches when refreshing" && {
+        expect_run_sh ${SUCCESSFULLY} "for n in 1 2 3; do echo | '$exe' --head-only --refresh-cache --keep-caches 2 --cache-path caches '$fixture/repo' 2>/dev/null; done && test \$(ls caches | wc -l) = 2"
+      }
+      it "finds the same commits as without cache" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --cache-path caches '$fixture/repo' 2>/dev/null)"
+      }
+    )
+  )
+  (when "querying multiple caches"
+    (sandbox '
+      git init -q other && cd other && echo content > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm initial && cd .. &&
+      echo | "$exe" --head-only --cache-path other.bincode other 2>/dev/null &&
+      echo | "$exe" --head-only --cache-path fixture.bincode "$fixture/repo" 2>/dev/null
+    '
+      it "finds blobs only known to a later cache" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --cache-path other.bincode --cache-path fixture.bincode '$fixture/repo' 2>/dev/null)"
+      }
+      it "does not output duplicate commits with --union-caches" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --union-caches --cache-path fixture.bincode --cache-path fixture.bincode '$fixture/repo' 2>/dev/null)"
+      }
+      it "finds the same commits for all blobs when caching subtrees of both caches" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <((git -C other ls-tree -r HEAD; git --git-dir '$fixture/repo' ls-tree -r HEAD) | cut -f 1 | cut -d ' ' -f 3 | '$exe' --union-caches --cache-path other.bincode --cache-path fixture.bincode '$fixture/repo' 2>/dev/null | perl -lane 'print join \" \", sort @F') <((git -C other ls-tree -r HEAD; git --git-dir '$fixture/repo' ls-tree -r HEAD) | cut -f 1 | cut -d ' ' -f 3 | '$exe' --subtree-cache-mb 1 --union-caches --cache-path other.bincode --cache-path fixture.bincode '$fixture/repo' 2>/dev/null | perl -lane 'print join \" \", sort @F')"
+      }
+      it "fails if one of the caches does not exist" && {
+        expect_run_sh ${WITH_FAILURE} "echo $commit | '$exe' --cache-path other.bincode --cache-path missing.bincode '$fixture/repo'"
+      }
+    )
+  )
+  (when "validating input up-front (--validate-input)"
+    (sandbox
+      it "fails before building the graph if too many records are malformed" && {
+        expect_run ${WITH_FAILURE} bash -c "printf '$commit\nnot-a-sha\n' | '$exe' --validate-input --max-invalid-fraction 0.2 --head-only --cache-path cache.bincode '$fixture/repo'"
+      }
+      it "did not write the cache" && {
+        expect_run ${WITH_FAILURE} test -e cache.bincode
+      }
+      it "builds the cache if no malformed records are found" && {
+        expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --validate-input --head-only --cache-path cache.bincode '$fixture/repo'"
+      }
+      it "reports duplicates and proceeds with a warm cache" && {
+        expect_run_sh ${SUCCESSFULLY} "printf '$commit\n$commit\n' | '$exe' --validate-input --head-only --cache-path cache.bincode '$fixture/repo' 2>&1 | grep -q 'Validated 2 records: 2 valid object ids, 0 malformed, 1 duplicates'"
+      }
+    )
+  )
+  (when "the build hangs on a commit"
+    it "dumps the state of the build naming the commit and aborts with --hang-abort" && {
+      expect_run_sh 3 "set -o pipefail; echo $commit | '$exe' --head-only --hang-timeout 1 --hang-abort --test-hang-at \$(git --git-dir '$fixture/repo' rev-parse HEAD) '$fixture/repo' 2>&1 >/dev/null | grep \"thread 'build': last commit \$(git --git-dir '$fixture/repo' rev-parse HEAD)\""
+    }
+  )
+  (when "caching the commits of subtrees (--subtree-cache-mb)"
+    it "finds the same commits for all blobs of HEAD as without cache" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(git --git-dir '$fixture/repo' ls-tree -r HEAD | cut -f 1 | cut -d ' ' -f 3 | '$exe' --head-only '$fixture/repo' 2>/dev/null | perl -lane 'print join \" \", sort @F') <(git --git-dir '$fixture/repo' ls-tree -r HEAD | cut -f 1 | cut -d ' ' -f 3 | '$exe' --subtree-cache-mb 1 --head-only '$fixture/repo' 2>/dev/null | perl -lane 'print join \" \", sort @F')"
+    }
+  )
+  (when "the graph has a cycle"
+    (sandbox '
+      git --git-dir "$fixture/repo" ls-tree -r HEAD | head -1 | cut -f 1 | cut -d " " -f 3 > blob
+    '
+      it "fails the lookup" && {
+        expect_run_sh ${WITH_FAILURE} "timeout 60 '$exe' --head-only --test-inject-cycle \$(cat blob) '$fixture/repo' < blob"
+      }
+      it "names the blob instead of hanging" && {
+        expect_run_sh ${SUCCESSFULLY} "timeout 60 '$exe' --head-only --test-inject-cycle \$(cat blob) '$fixture/repo' < blob 2>&1 >/dev/null | grep -q \"Internal invariant violated: the lookup of blob \$(cat blob)\""
+      }
+    )
+  )
+  (when "checking that lookups agree with a plain traversal"
+    it "finds no disagreement for any blob of HEAD when looking up blobs" && {
+      expect_run_sh ${SUCCESSFULLY} "git --git-dir '$fixture/repo' ls-tree -r HEAD | cut -f 1 | cut -d ' ' -f 3 | '$exe' --test-check-lookups --head-only '$fixture/repo'"
+    }
+    it "finds no disagreement for any blob of the tree-to-integrate" && {
+      expect_run ${SUCCESSFULLY} "$exe" --test-check-lookups --head-only "$fixture/repo" "$fixture/tree"
+    }
+    it "finds no disagreement for any blob of HEAD with the subtree cache" && {
+      expect_run_sh ${SUCCESSFULLY} "git --git-dir '$fixture/repo' ls-tree -r HEAD | cut -f 1 | cut -d ' ' -f 3 | '$exe' --test-check-lookups --subtree-cache-mb 1 --head-only '$fixture/repo'"
+    }
+  )
+  (when "deduplicating repeated blobs (--dedupe-input)"
+    it "produces the same output as without deduplication" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(printf '$commit\n$commit\n' | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(printf '$commit\n$commit\n' | '$exe' --dedupe-input --head-only '$fixture/repo' 2>/dev/null)"
+    }
+    it "answers the repeated blob from the earlier lookup" && {
+      expect_run_sh ${SUCCESSFULLY} "printf '$commit\n$commit\n' | '$exe' --dedupe-input --head-only '$fixture/repo' 2>&1 >/dev/null | grep -q '^DONE:.*, 1 repeated blobs answered from earlier lookups'"
+    }
+    it "looks up repeated blobs again without it" && {
+      expect_run_sh ${SUCCESSFULLY} "! (printf '$commit\n$commit\n' | '$exe' --head-only '$fixture/repo' 2>&1 >/dev/null | grep -q 'answered from earlier lookups')"
+    }
+  )
+  (when "recording and checking replays"
+    (sandbox '
+      git init -q replayed && cd replayed && echo content > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm first && cd .. &&
+      git -C replayed rev-parse HEAD:file | "$exe" --record-replay replay.lz4 replayed 2>/dev/null
+    '
+      it "finds no differences with the same graph" && {
+        expect_run ${SUCCESSFULLY} "$exe" --check-replay replay.lz4 replayed
+      }
+      it "notes that options changing the results differ" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --check-replay replay.lz4 --only-kind blob --path file replayed 2>&1 | grep -q \"Options differ - recorded '.*', now '.*only-kind=blob\""
+      }
+      it "reports queries whose commits differ after the repository changed" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo more > replayed/other && git -C replayed add . && git -C replayed -c user.name=a -c user.email=b commit -qm second && '$exe' --check-replay replay.lz4 replayed 2>&1 | grep -q 'Checked 1 queries, 1 of which differ'"
+      }
+    )
+  )
+  (when "writing results to an output directory"
+    (sandbox
+      it "writes one file per blob with the same content as stdout, even if a blob repeats" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(printf '$commit\n$commit\n' | '$exe' --output-dir out --head-only '$fixture/repo' 2>/dev/null && cat out/$commit.txt)"
+      }
+    )
+  )
+  (when "writing results in binary (--format=binary)"
+    (sandbox '
+      git --git-dir "$fixture/repo" ls-tree -r HEAD | cut -f 1 | cut -d " " -f 3 > blobs &&
+      "$exe" --format binary --head-only "$fixture/repo" < blobs > results.bin 2>/dev/null
+    '
+      it "decodes to the same output as a direct text run" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <('$exe' --head-only '$fixture/repo' < blobs 2>/dev/null) <('$exe' --decode results.bin '$fixture/repo' 2>/dev/null)"
+      }
+      it "decodes to the same output as a direct text run with -z" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(tr '\n' '\0' < blobs | '$exe' -z --head-only '$fixture/repo' 2>/dev/null | tr '\0' '\n') <('$exe' -z --decode results.bin '$fixture/repo' 2>/dev/null | tr '\0' '\n')"
+      }
+      it "fails with the byte offset of a truncated record" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; head -c -5 results.bin > truncated.bin && '$exe' --decode truncated.bin '$fixture/repo' 2>&1 | grep -q 'byte offset'"
+      }
+    )
+  )
+  (when "finding the blobs next to a blob (--mode=siblings)"
+    (sandbox '
+      git init -q neighbors && cd neighbors && mkdir a b && echo shared > a/x && echo y > a/y &&
+      git add . && git -c user.name=a -c user.email=b commit -qm first &&
+      cp a/x b/x && echo z > b/z && git add . && git -c user.name=a -c user.email=b commit -qm second && cd ..
+    '
+      it "outputs the union of the other blobs of all trees containing it" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(git -C neighbors rev-parse HEAD:a/x | '$exe' --head-only --mode siblings --with-forward-edges neighbors 2>/dev/null | tr ' ' '\n' | sort) <(git -C neighbors rev-parse HEAD:a/y HEAD:b/z | sort)"
+      }
+      it "outputs no more than --max-siblings blobs" && {
+        expect_run_sh ${SUCCESSFULLY} "test \$(git -C neighbors rev-parse HEAD:a/x | '$exe' --head-only --mode siblings --max-siblings 1 --with-forward-edges neighbors 2>/dev/null | wc -w) = 1"
+      }
+      it "finds the same siblings in a cache built with forward edges" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --with-forward-edges --cache-path forward.bincode neighbors 2>/dev/null && diff <(git -C neighbors rev-parse HEAD:a/x | '$exe' --head-only --mode siblings --with-forward-edges neighbors 2>/dev/null) <(git -C neighbors rev-parse HEAD:a/x | '$exe' --mode siblings --cache-path forward.bincode neighbors 2>/dev/null)"
+      }
+      it "fails without forward edges" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; git -C neighbors rev-parse HEAD:a/x | '$exe' --head-only --mode siblings neighbors 2>&1 | grep 'with-forward-edges'"
+      }
+    )
+  )
+  (when "using NUL-terminated records (-z)"
+    it "produces the same commits as with newline-terminated records" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null | tr ' ' '\n' | sed '/^\$/d') <(printf '$commit\0' | '$exe' -z --head-only '$fixture/repo' 2>/dev/null | tr '\0' '\n' | sed '/^\$/d')"
+    }
+  )
+  (when "reading the output of 'git log --raw' (--input-format=raw-diff)"
+    (sandbox '
+      git init -q renamed && cd renamed && echo content > a && git add . &&
+      git -c user.name=a -c user.email=b commit -qm initial && git mv a b &&
+      git -c user.name=a -c user.email=b commit -qm rename && cd ..
+    '
+      it "echoes the new path of each entry, including renames" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(git -C renamed log --raw -M --full-index -z | '$exe' --input-format raw-diff --input-z renamed 2>/dev/null | cut -f 1) <(printf 'b\na\n')"
+      }
+      it "resolves abbreviated hex-shas to the same commits" && {
+        expect_run_sh ${SUCCESSFULLY} "diff <(git -C renamed log --raw -M --full-index -z | '$exe' --input-format raw-diff --input-z renamed 2>/dev/null) <(git -C renamed log --raw -M | '$exe' --input-format raw-diff renamed 2>/dev/null)"
+      }
+      it "keeps the paths in binary results (--format=binary)" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C renamed log --raw -M | '$exe' --input-format raw-diff --format binary renamed > renamed.bin 2>/dev/null && diff <(git -C renamed log --raw -M | '$exe' --input-format raw-diff renamed 2>/dev/null) <('$exe' --decode renamed.bin renamed 2>/dev/null)"
+      }
+    )
+    (sandbox '
+      git init -q tabbed && cd tabbed && echo content > "$(printf "with\ttab")" && git add . &&
+      git -c user.name=a -c user.email=b commit -qm initial && cd ..
+    '
+      it "separates paths with NUL instead of a tab when writing NUL-terminated records" && {
+        expect_run_sh ${SUCCESSFULLY} "test \"\$(git -C tabbed log --raw --full-index -z | '$exe' --input-format raw-diff -z tabbed 2>/dev/null | tr '\\0' '\\n' | head -n 2)\" = \"\$(printf 'with\\ttab\\n%s' \$(git -C tabbed rev-parse HEAD))\""
+      }
+    )
+  )
+  (when "annotating commits with their time (--commit-time-output)"
+    (sandbox '
+      git init -q dated && cd dated && echo content > file && git add . &&
+      GIT_COMMITTER_DATE="1000000000 +0000" git -c user.name=a -c user.email=b commit -qm first &&
+      echo other > other && git add . &&
+      GIT_COMMITTER_DATE="1000000100 +0000" git -c user.name=a -c user.email=b commit -qm second && cd ..
+    '
+      it "outputs the commits from oldest to newest with their time" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C dated rev-parse HEAD:file | '$exe' --head-only --commit-time-output dated 2>/dev/null | grep -qx \"\$(git -C dated rev-parse HEAD~1):1000000000 \$(git -C dated rev-parse HEAD):1000000100\""
+      }
+    )
+  )
+  (when "reading compressed input (--input-compression)"
+    it "decodes lz4-compressed input" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | lz4 -c | '$exe' --head-only --input-compression lz4 '$fixture/repo' 2>/dev/null)"
+    }
+    it "detects lz4-compressed input" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | lz4 -c | '$exe' --head-only --input-compression auto '$fixture/repo' 2>/dev/null)"
+    }
+    it "reads uncompressed input in auto mode" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null) <(echo $commit | '$exe' --head-only --input-compression auto '$fixture/repo' 2>/dev/null)"
+    }
+    it "fails on gzip-compressed input with a hint" && {
+      expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo $commit | gzip -c | '$exe' --head-only --input-compression auto '$fixture/repo' 2>&1 | grep 'gzip -dc'"
+    }
+  )
+  (when "a blob is not contained in any commit"
+    unknown_blob=0000000000000000000000000000000000000001
+    (with "--on-empty=fail"
+      it "fails with a distinct exit code" && {
+        echo $unknown_blob \
+        | expect_run 2 "$exe" --on-empty=fail --head-only "$fixture/repo"
+      }
+    )
+    (with "--on-empty=report"
+      it "succeeds" && {
+        echo $unknown_blob \
+        | expect_run ${SUCCESSFULLY} "$exe" --on-empty=report --head-only "$fixture/repo"
+      }
+    )
+  )
+  (when "timing lookups"
+    it "reports the slowest lookups" && {
+      expect_run_sh ${SUCCESSFULLY} "echo $commit | '$exe' --time-lookups 3 --head-only '$fixture/repo' 2>&1 >/dev/null | grep -q 'Slowest 1 lookups'"
+    }
+  )
+  (when "hex-shas are quoted and followed by punctuation"
+    (with "--lenient"
+      it "succeeds" && {
+        echo "\"$commit\"," \
+        | expect_run ${SUCCESSFULLY} "$exe" --lenient --head-only "$fixture/repo"
+      }
+    )
+    (with "strict parsing"
+      it "fails" && {
+        echo "\"$commit\"," \
+        | expect_run ${WITH_FAILURE} "$exe" --head-only "$fixture/repo"
+      }
+    )
+  )
+  (when "checking the object database first (--fsck-lite)"
+    it "succeeds" && {
+      echo $commit \
+      | expect_run ${SUCCESSFULLY} "$exe" --fsck-lite --head-only "$fixture/repo"
+    }
+  )
+  (when "reading objects from 'git cat-file --batch' (--objects-from-batch)"
+    it "finds the same commits as when reading the repository directly" && {
+      expect_run_sh ${SUCCESSFULLY} "diff <(echo $commit | '$exe' --head-only '$fixture/repo' 2>/dev/null | tr ' ' '\n' | sort) <(echo $commit | '$exe' --objects-from-batch 'git --git-dir \"$fixture/repo\" cat-file --batch' --tips \$(git --git-dir '$fixture/repo' rev-parse HEAD) '$fixture/repo' 2>/dev/null | tr ' ' '\n' | sort)"
+    }
+  )
+  (when "commits have unusual headers or trees which can't be read"
+    (sandbox '
+      git init -q quirky && cd quirky && echo content > file && git add . && tree=$(git write-tree) &&
+      printf "tree $tree\nauthor a <b> 0 +0000\ncommitter a <b> 0 +0000\nencoding ISO-8859-1\nmergetag object $tree\n type tree\n tag v1\n tagger a <b> 0 +0000\n \n signed\nx-custom header\ngpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\nquirky\n" > quirky &&
+      quirky=$(git hash-object -t commit -w --literally quirky) &&
+      printf "tree 1111111111111111111111111111111111111111\nparent $quirky\nauthor a <b> 0 +0000\ncommitter a <b> 0 +0000\n\nbroken\n" > broken &&
+      git update-ref refs/heads/quirky $(git hash-object -t commit -w --literally broken) &&
+      git symbolic-ref HEAD refs/heads/quirky && cd ..
+    '
+      for mode in "--head-only" "--objects-from-batch 'git --git-dir quirky/.git cat-file --batch' --tips \$(git -C quirky rev-parse HEAD)"; do
+        it "counts the tree which can't be read ($mode)" && {
+          expect_run_sh ${SUCCESSFULLY} "echo | '$exe' $mode quirky 2>&1 | grep -q '^READY:.*, 1 trees could not be read'"
+        }
+        it "finds the blob of the commit with unusual headers ($mode)" && {
+          expect_run_sh ${SUCCESSFULLY} "git -C quirky rev-parse HEAD~1:file | '$exe' $mode quirky 2>/dev/null | grep -qx \$(git -C quirky rev-parse HEAD~1)"
+        }
+        it "keeps the commit whose tree can't be read ($mode)" && {
+          expect_run_sh ${SUCCESSFULLY} "'$exe' $mode --dump-oids=commit quirky 2>/dev/null | grep -q \$(git -C quirky rev-parse HEAD)"
+        }
+      done
+    )
+  )
+  (when "capping the amount of commits (--max-commits)"
+    (sandbox '
+      git init -q capped && cd capped && echo old > old && git add . &&
+      git -c user.name=a -c user.email=b commit -qm first && git rm -q old && echo new > new &&
+      git add . && git -c user.name=a -c user.email=b commit -qm second && cd ..
+    '
+      it "finds blobs of the most recent commits" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C capped rev-parse HEAD:new | '$exe' --head-only --max-commits 1 capped 2>/dev/null | grep -qx \$(git -C capped rev-parse HEAD)"
+      }
+      it "does not find blobs only in older commits" && {
+        expect_run_sh ${SUCCESSFULLY} "test -z \"\$(git -C capped rev-parse HEAD~1:old | '$exe' --head-only --max-commits 1 capped 2>/dev/null)\""
+      }
+      it "warns that the graph is capped" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-commits 1 capped 2>&1 | grep -q 'WARNING: The graph is capped at the 1 most recent commits'"
+      }
+      it "records the cap with the cache" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-commits 1 --cache-path capped.bincode capped 2>/dev/null && echo | '$exe' --cache-path capped.bincode capped 2>&1 | grep -q 'traversal: .*max-commits=1'"
+      }
+    )
+  )
+  (when "capping the amount of commits of multiple branches (--max-count)"
+    (sandbox '
+      git init -q branches && cd branches && echo head > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm head &&
+      for branch in a-newest:2019 b-oldest:2015 c-older:2017; do
+        echo $branch > file && git add . &&
+        git update-ref refs/remotes/origin/${branch%:*} $(GIT_COMMITTER_DATE="${branch#*:}-06-01T00:00:00Z" git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m $branch)
+      done && cd ..
+    '
+      it "indexes the newest commits across branches" && {
+        expect_run ${SUCCESSFULLY} diff <(git -C branches rev-parse origin/a-newest origin/c-older | sort) <("$exe" --dump-oids=commit --max-count 2 branches 2>/dev/null | cut -d " " -f 2 | sort)
+      }
+    )
+  )
+  (when "a tree is very wide (--max-tree-entries)"
+    (sandbox '
+      git init -q wide && cd wide && blob=$(echo a | git hash-object -w --stdin) &&
+      last=$(echo last | git hash-object -w --stdin) && first=$(echo first | git hash-object -w --stdin) &&
+      root=$(git -c user.name=a -c user.email=b commit-tree $(printf "100644 blob $first\tfirst\n" | git mktree) -m root) &&
+      tree=$( (for i in $(seq 1 20000); do printf "100644 blob $blob\tf%05d\n" $i; done; printf "100644 blob $last\tzz\n") | git mktree) &&
+      git update-ref HEAD $(git -c user.name=a -c user.email=b commit-tree $tree -p $root -m wide) && cd ..
+    '
+      it "indexes all entries without a limit" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C wide rev-parse HEAD:zz | '$exe' --head-only wide 2>/dev/null | grep -qx \$(git -C wide rev-parse HEAD)"
+      }
+      it "reports the entries skipped beyond the limit" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-tree-entries 10 wide 2>&1 | grep -q 'skipped 19991 entries of 1 trees with more than 10 entries'"
+      }
+      it "reports the skipped entries as caveat of the coverage" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-tree-entries 10 wide 2>&1 | grep -q 'caveat: *skipped 19991 entries'"
+      }
+      it "keeps reporting them when loading the graph from its cache" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-tree-entries 10 --cache-path wide.bincode wide 2>/dev/null && echo | '$exe' --head-only --max-tree-entries 10 --cache-path wide.bincode wide 2>&1 | grep -q 'caveat: *skipped 19991 entries'"
+      }
+      it "does not find blobs of skipped entries" && {
+        expect_run_sh ${SUCCESSFULLY} "test -z \"\$(git -C wide rev-parse HEAD:zz | '$exe' --head-only --max-tree-entries 10 wide 2>/dev/null)\""
+      }
+      it "signals progress within the wide tree, every 10000 entries" && {
+        expect_run_sh 3 "set -o pipefail; echo | '$exe' --head-only --hang-timeout 1 --hang-abort --test-hang-at \$(git -C wide rev-parse HEAD~1) wide 2>&1 >/dev/null | grep \"thread 'build': last commit \$(git -C wide rev-parse HEAD~1) .*, 4 in total\""
+      }
+    )
+  )
+  (when "cross-checking a lookup against the repository (--self-check)"
+    (sandbox '
+      git init -q wide && cd wide && blob=$(echo a | git hash-object -w --stdin) &&
+      last=$(echo last | git hash-object -w --stdin) &&
+      tree=$( (for i in $(seq 1 20); do printf "100644 blob $blob\tf%02d\n" $i; done; printf "100644 blob $last\tzz\n") | git mktree) &&
+      git update-ref HEAD $(git -c user.name=a -c user.email=b commit-tree $tree -m wide) && cd ..
+    '
+      it "passes if graph and repository agree" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --self-check $commit '$fixture/repo' 2>&1 | grep -q 'Self-check of blob .* passed'"
+      }
+      it "fails listing the commits only the repository knows" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --head-only --max-tree-entries 10 --self-check \$(git -C wide rev-parse HEAD:zz) wide 2>&1 | grep -qx \"only in repository: \$(git -C wide rev-parse HEAD)\""
+      }
+    )
+  )
+  (when "a tree has an entry of unknown kind"
+    (sandbox '
+      git init -q odd && cd odd &&
+      blob=$(echo content | git hash-object -w --stdin) &&
+      { printf "100600 odd\0"; perl -e "print pack(q(H*), shift)" $blob; } > tree &&
+      tree=$(git hash-object -t tree -w --literally tree) &&
+      git -c user.name=a -c user.email=b commit-tree -m odd $tree > ../tip && cd ..
+    '
+      it "reports the amount of skipped entries in the build summary" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --objects-from-batch 'git --git-dir odd/.git cat-file --batch' --tips \$(cat tip) odd 2>&1 | grep -q '^READY:.*, skipped 1 tree entries of unknown kind'"
+      }
+    )
+  )
   (when "iterating all remote heads"
     it "succeeds" && {
       echo $commit \
       | expect_run ${SUCCESSFULLY} "$exe" "$fixture/repo"
     }
   )
+  (when "objects are stored in alternate object directories"
+    (sandbox '
+      git init -q base && echo a > base/a && git -C base add . &&
+      git -C base -c user.name=a -c user.email=b commit -qm base &&
+      git clone -q --shared base fork && git clone -q --shared base other &&
+      echo b > other/b && git -C other add . && git -C other -c user.name=a -c user.email=b commit -qm other &&
+      git -C other rev-parse HEAD > fork/.git/$(git -C fork symbolic-ref HEAD)
+    '
+      it "finds commits whose objects are only in an alternate given with --alternate-objects" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C other rev-parse HEAD:b | '$exe' --head-only --alternate-objects '$PWD/other/.git/objects' fork 2>/dev/null | grep -q \$(git -C other rev-parse HEAD)"
+      }
+      it "finds commits whose trees are only in the alternate of the objects/info/alternates file" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C base rev-parse HEAD:a | '$exe' --head-only --alternate-objects '$PWD/other/.git/objects' fork 2>/dev/null | grep -q \$(git -C base rev-parse HEAD)"
+      }
+      it "honors GIT_ALTERNATE_OBJECT_DIRECTORIES" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C other rev-parse HEAD:b | GIT_ALTERNATE_OBJECT_DIRECTORIES='$PWD/other/.git/objects' '$exe' --head-only fork 2>/dev/null | grep -q \$(git -C other rev-parse HEAD)"
+      }
+      it "fails without the alternate" && {
+        expect_run_sh ${WITH_FAILURE} "echo | '$exe' --head-only fork"
+      }
+      it "fails naming an alternate which can't be read" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo | '$exe' --head-only --alternate-objects missing fork 2>&1 | grep -q \"missing' is not readable\""
+      }
+    )
+  )
+  (when "showing notes of result commits (--show-notes)"
+    (sandbox '
+      git init -q annotated && cd annotated && echo a > a && git add . &&
+      git -c user.name=a -c user.email=b commit -qm first && echo b > b && git add . &&
+      git -c user.name=a -c user.email=b commit -qm second &&
+      git -c user.name=a -c user.email=b notes add -m "reviewed by Jörg ✓" HEAD &&
+      git -c user.name=a -c user.email=b notes --ref provenance add -m "imported" HEAD~1 && cd .. &&
+      mkdir tree && cp annotated/a annotated/b tree/
+    '
+      it "appends the notes of the default notes ref to the lookup results" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C annotated rev-parse HEAD:a | '$exe' --head-only --show-notes annotated 2>/dev/null | grep -qx \"note \$(git -C annotated rev-parse HEAD) reviewed by Jörg ✓\""
+      }
+      it "reads notes from the given notes ref" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C annotated rev-parse HEAD:a | '$exe' --head-only --show-notes=refs/notes/provenance annotated 2>/dev/null | grep -qx \"note \$(git -C annotated rev-parse HEAD~1) imported\""
+      }
+      it "truncates notes to --note-max-chars characters" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C annotated rev-parse HEAD:a | '$exe' --head-only --show-notes --note-max-chars 15 annotated 2>/dev/null | grep -qx \"note \$(git -C annotated rev-parse HEAD) reviewed by Jör...\""
+      }
+      it "appends the notes to the ranked candidates" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --show-notes annotated tree 2>/dev/null | grep -qx \"note \$(git -C annotated rev-parse HEAD) reviewed by Jörg ✓\""
+      }
+    )
+  )
+  (when "the graph ends up without any blob"
+    (sandbox '
+      git init -q empty && git -C empty -c user.name=a -c user.email=b commit -q --allow-empty -m initial
+    '
+      it "warns that all lookups will be empty" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' empty 2>&1 | grep -q 'WARNING: The graph has no commits reachable'"
+      }
+    )
+  )
+  (when "selecting the remote branches to traverse"
+    (sandbox '
+      git init -q remotes && cd remotes && echo head > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm head &&
+      for ref in origin/main origin/feature upstream/release-1 upstream/main fork/topic; do
+        echo $ref > file && git add . &&
+        git update-ref refs/remotes/$ref $(git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m $ref)
+      done && cd ..
+    '
+      tips() {
+        (cd remotes && git rev-parse "$@" | sort)
+      }
+      commits() {
+        "$exe" --dump-oids=commit "$@" remotes 2>/dev/null | cut -d " " -f 2 | sort
+      }
+      it "traverses only branches matching --remote-pattern" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main origin/feature) <(commits --remote-pattern "origin/*")
+      }
+      it "traverses branches matching any of multiple patterns" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main origin/feature upstream/release-1) <(commits --remote-pattern "origin/*" --remote-pattern "upstream/release-*")
+      }
+      it "does not traverse branches of excluded remotes" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main origin/feature upstream/release-1 upstream/main) <(commits --exclude-remote fork)
+      }
+      it "combines patterns and exclusions" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main upstream/main) <(commits --remote-pattern "*/main" --exclude-remote fork)
+      }
+      it "falls back to head if no pattern matches, naming the patterns" && {
+        expect_run ${SUCCESSFULLY} diff <(tips HEAD) <(commits --remote-pattern "nothing/*")
+      }
+      it "reports patterns that matched nothing" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --remote-pattern 'nothing/*' remotes 2>&1 >/dev/null | grep -q \"Remote pattern 'nothing/\\*' matched no branch\""
+      }
+      it "traverses only refs matching --refs" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main origin/feature upstream/release-1) <(commits --refs "refs/remotes/origin/*" --refs "refs/remotes/*/release-*")
+      }
+      it "traverses local branches matching --refs" && {
+        expect_run ${SUCCESSFULLY} diff <(tips HEAD) <(commits --refs "refs/heads/*")
+      }
+      it "warns about --refs patterns that matched nothing" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --refs 'refs/heads/*' --refs 'refs/nothing/*' remotes 2>&1 >/dev/null | grep -q \"Ref pattern 'refs/nothing/\\*' matched no ref\""
+      }
+      it "fails if no ref matches --refs" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --dump-oids=commit --refs 'refs/nothing/*' remotes 2>&1 | grep -q 'nothing to traverse'"
+      }
+      it "does not traverse remote branches matching --exclude-refs" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main upstream/release-1 upstream/main) <(commits --exclude-refs "refs/remotes/origin/feature" --exclude-refs "refs/remotes/fork/*")
+      }
+      it "applies --exclude-refs after --refs" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main upstream/main) <(commits --refs "refs/remotes/*" --exclude-refs "*/feature" --exclude-refs "*/release-*" --exclude-refs "*/topic")
+      }
+      it "shows how many refs were included and excluded" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --exclude-refs 'refs/remotes/fork/*' remotes 2>&1 >/dev/null | grep -q '^READY:.*, included 4 refs, excluded 1'"
+      }
+      it "fails listing the excluded refs if none remains" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --dump-oids=commit --exclude-refs 'refs/remotes/*' remotes 2>&1 | grep -qx '  refs/remotes/fork/topic'"
+      }
+    )
+  )
+  (when "traversing local branches (--local-branches)"
+    (sandbox '
+      git init -q locals && cd locals && echo head > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm head &&
+      for ref in heads/topic heads/wip remotes/origin/main; do
+        echo $ref > file && git add . &&
+        git update-ref refs/$ref $(git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m $ref)
+      done && git update-ref refs/heads/tracking refs/remotes/origin/main && cd ..
+    '
+      tips() {
+        (cd locals && git rev-parse "$@" | sort)
+      }
+      commits() {
+        "$exe" --dump-oids=commit "$@" locals 2>/dev/null | cut -d " " -f 2 | sort
+      }
+      it "traverses only remote branches without it" && {
+        expect_run ${SUCCESSFULLY} diff <(tips origin/main) <(commits)
+      }
+      it "traverses local and remote branches with it" && {
+        expect_run ${SUCCESSFULLY} diff <(tips HEAD topic wip origin/main) <(commits --local-branches)
+      }
+    )
+  )
+  (when "traversing tags (--include-tags)"
+    (sandbox '
+      git init -q tagged && cd tagged && echo head > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm head && echo release > file && git add . &&
+      release=$(git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m release) &&
+      git -c user.name=a -c user.email=b tag -a -m v1 v1 $release &&
+      git -c user.name=a -c user.email=b tag -a -m nested nested v1 &&
+      git tag blob-tag $(git rev-parse HEAD:file) && cd ..
+    '
+      tips() {
+        (cd tagged && git rev-parse "$@" | sort)
+      }
+      commits() {
+        "$exe" --dump-oids=commit "$@" tagged 2>/dev/null | cut -d " " -f 2 | sort
+      }
+      it "traverses only head without it" && {
+        expect_run ${SUCCESSFULLY} diff <(tips HEAD) <(commits)
+      }
+      it "traverses the commits of annotated and nested tags with it" && {
+        expect_run ${SUCCESSFULLY} diff <(tips HEAD "v1^{commit}") <(commits --include-tags)
+      }
+      it "reports the amount of tags in the READY line" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --include-tags tagged 2>&1 >/dev/null | grep -q '^READY:.*, pushed 2 tags'"
+      }
+      it "warns about tags which don't point to a commit" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --include-tags tagged 2>&1 >/dev/null | grep -q \"Skipping tag 'refs/tags/blob-tag' as it points to a blob\""
+      }
+    )
+  )
+  (when "traversing every ref (--all)"
+    (sandbox '
+      git init -q everything && cd everything && echo head > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm head && echo remote > file && git add . &&
+      git update-ref refs/remotes/origin/main $(git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m remote) &&
+      echo stashed > file && git -c user.name=a -c user.email=b stash -q &&
+      git -c user.name=a -c user.email=b notes add -m note HEAD && cd ..
+    '
+      it "traverses the commits of all refs, like git rev-list --all" && {
+        expect_run ${SUCCESSFULLY} diff <(git -C everything rev-list --all | sort) <("$exe" --dump-oids=commit --all everything 2>/dev/null | cut -d " " -f 2 | sort)
+      }
+      it "reports the amount of refs in the READY line" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --all everything 2>&1 >/dev/null | grep -q '^READY:.*, pushed 5 refs'"
+      }
+      it "can't be combined with --head-only" && {
+        expect_run_sh ${WITH_FAILURE} "'$exe' --dump-oids=commit --all --head-only everything"
+      }
+    )
+  )
+  (when "traversing a range of commits (--rev-range)"
+    (sandbox '
+      git init -q ranged && cd ranged &&
+      for n in 1 2 3 4; do
+        echo $n > file && git add . && git -c user.name=a -c user.email=b commit -qm $n && git tag v$n
+      done && cd ..
+    '
+      range() {
+        git -C ranged rev-list "$@" | sort
+      }
+      commits() {
+        "$exe" --dump-oids=commit "$@" ranged 2>/dev/null | cut -d " " -f 2 | sort
+      }
+      it "traverses only the commits of a two-dot range" && {
+        expect_run ${SUCCESSFULLY} diff <(range v2..HEAD) <(commits --rev-range v2..HEAD)
+      }
+      it "traverses only the commits of a revision not excluded with --not" && {
+        expect_run ${SUCCESSFULLY} diff <(range v3 --not v1) <(commits --rev-range v3 --not v1)
+      }
+      it "can't be combined with --head-only" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --dump-oids=commit --head-only --rev-range v2..HEAD ranged 2>&1 | grep -q 'can.t be combined with --head-only'"
+      }
+    )
+  )
+  (when "limiting commits by date (--since and --until)"
+    (sandbox '
+      git init -q dated && cd dated &&
+      for year in 2015 2017 2019; do
+        echo $year > file && git add . &&
+        GIT_COMMITTER_DATE="$year-06-01T00:00:00Z" git -c user.name=a -c user.email=b commit -qm $year
+      done && cd ..
+    '
+      commits() {
+        "$exe" --dump-oids=commit --head-only "$@" dated 2>/dev/null | cut -d " " -f 2 | sort
+      }
+      it "indexes only commits within the window" && {
+        expect_run ${SUCCESSFULLY} diff <(git -C dated rev-list --since=2016-01-01 --until=2018-01-01 HEAD) <(commits --since 2016-01-01 --until 2018-01-01T00:00:00Z)
+      }
+      it "accepts relative dates" && {
+        expect_run ${SUCCESSFULLY} diff <(git -C dated rev-list HEAD | sort) <(commits --since 100.years.ago)
+      }
+      it "fails if no commit is within the window" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --dump-oids=commit --head-only --since 2030-01-01 dated 2>&1 | grep -q 'None of the traversed commits was committed since'"
+      }
+      it "fails on dates it can't parse" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; '$exe' --dump-oids=commit --head-only --until yesterday-ish dated 2>&1 | grep -q \"Could not parse date 'yesterday-ish'\""
+      }
+    )
+  )
+  (when "following only first parents (--first-parent)"
+    (sandbox '
+      git init -q merged && cd merged && echo base > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm base && git checkout -qb side &&
+      echo side > side && git add . && git -c user.name=a -c user.email=b commit -qm side &&
+      git checkout -q - && echo main > file && git add . && git -c user.name=a -c user.email=b commit -qm main &&
+      git -c user.name=a -c user.email=b merge -q --no-edit side && cd ..
+    '
+      it "indexes only the first-parent chain" && {
+        expect_run ${SUCCESSFULLY} diff <(git -C merged rev-list --first-parent HEAD | sort) <("$exe" --dump-oids=commit --head-only --first-parent merged 2>/dev/null | cut -d " " -f 2 | sort)
+      }
+      it "notes it in the READY line" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --head-only --first-parent merged 2>&1 >/dev/null | grep -q '^READY:.*, following first parents only'"
+      }
+      it "records it with the cache" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --dump-oids=commit --head-only --first-parent --cache-path merged.bincode merged >/dev/null 2>&1 && echo | '$exe' --head-only --cache-path merged.bincode merged 2>&1 | grep -q 'traversal: .*first-parent=true'"
+      }
+    )
+  )
+  (when "indexing commits not reachable from any ref (--include-dangling)"
+    (sandbox '
+      git init -q rebased && cd rebased && echo base > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm base && echo lost > lost && git add . &&
+      git -c user.name=a -c user.email=b commit -qm lost && git rm -q lost &&
+      git -c user.name=a -c user.email=b commit -q --amend -m amended && cd ..
+    '
+      it "does not find blobs of rebased commits without it" && {
+        expect_run_sh ${SUCCESSFULLY} "test -z \"\$(git -C rebased rev-parse HEAD@{1}:lost | '$exe' --head-only rebased 2>/dev/null)\""
+      }
+      it "finds blobs of rebased commits and marks them as dangling" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C rebased rev-parse HEAD@{1}:lost | '$exe' --head-only --include-dangling rebased 2>/dev/null | grep -qx \"\$(git -C rebased rev-parse HEAD@{1}):dangling\""
+      }
+      it "does not mark reachable commits" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C rebased rev-parse HEAD:file | '$exe' --head-only --include-dangling rebased 2>/dev/null | tr ' ' '\\n' | grep -qx \$(git -C rebased rev-parse HEAD)"
+      }
+      it "remembers dangling commits in the cache" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --include-dangling --cache-path rebased.bincode rebased 2>/dev/null && git -C rebased rev-parse HEAD@{1}:lost | '$exe' --head-only --include-dangling --cache-path rebased.bincode rebased 2>/dev/null | grep -qx \"\$(git -C rebased rev-parse HEAD@{1}):dangling\""
+      }
+      it "reports them in the READY line" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --include-dangling rebased 2>&1 | grep -q '^READY:.*, indexed 1 dangling commits'"
+      }
+    )
+  )
+  (when "history is grafted with replace refs"
+    (sandbox '
+      git init -q grafted && cd grafted && echo old > old && git add old &&
+      git -c user.name=a -c user.email=b commit-tree $(git write-tree) -m old > ../old-commit &&
+      git rm -q --cached old && rm old && echo dropped > dropped && git add dropped &&
+      git -c user.name=a -c user.email=b commit -qm dropped-parent && git rm -q dropped &&
+      echo new > new && git add new &&
+      git -c user.name=a -c user.email=b commit -qm new && echo tip > tip && git add tip &&
+      git -c user.name=a -c user.email=b commit -qm tip && git replace --graft HEAD~1 $(cat ../old-commit) && cd ..
+    '
+      it "finds blobs of the grafted history" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C grafted rev-parse \$(cat old-commit):old | '$exe' --head-only grafted 2>/dev/null | grep -qx \$(cat old-commit)"
+      }
+      it "reports the replaced commit instead of its replacement" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C grafted rev-parse HEAD~1:new | '$exe' --head-only grafted 2>/dev/null | tr ' ' '\\n' | sort | diff - <(git -C grafted rev-parse HEAD HEAD~1 | sort)"
+      }
+      it "doesn't index the parent dropped by the replacement" && {
+        expect_run_sh ${SUCCESSFULLY} "test -z \"\$(echo dropped | git hash-object --stdin | '$exe' --head-only grafted 2>/dev/null)\""
+      }
+      it "indexes the dropped parent with --no-replace-objects" && {
+        expect_run_sh ${SUCCESSFULLY} "echo dropped | git hash-object --stdin | '$exe' --head-only --no-replace-objects grafted 2>/dev/null | grep -qx \$(git -C grafted --no-replace-objects rev-parse HEAD~2)"
+      }
+      it "reports the replace refs in the READY line" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only grafted 2>&1 | grep -q '^READY:.*, applied 1 replace refs'"
+      }
+      it "ignores replace refs with --no-replace-objects" && {
+        expect_run_sh ${SUCCESSFULLY} "test -z \"\$(git -C grafted rev-parse \$(cat old-commit):old | '$exe' --head-only --no-replace-objects grafted 2>/dev/null)\""
+      }
+    )
+  )
+  (when "the repository is a shallow clone"
+    (sandbox '
+      git clone -q --depth 1 "file://$fixture/repo" shallow
+    '
+      it "fails explaining how to proceed" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo | '$exe' --head-only shallow 2>&1 | grep -q 'is shallow.*--allow-shallow'"
+      }
+      it "lists the boundary commits with --allow-shallow" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --allow-shallow shallow 2>&1 | grep -qx \"  \$(git -C shallow rev-parse HEAD)\""
+      }
+      it "notes that results may be incomplete after the lookup" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --allow-shallow shallow 2>&1 | tail -1 | grep -q 'caveat: *the repository is shallow'"
+      }
+    )
+  )
+  (when "the repository is a partial clone"
+    (sandbox '
+      git init -q origin && cd origin && mkdir dir && echo a > dir/a && git add . &&
+      git -c user.name=a -c user.email=b commit -qm first && echo b > dir/b && git add . &&
+      git -c user.name=a -c user.email=b commit -qm second && git config uploadpack.allowFilter true && cd .. &&
+      git clone -q --no-checkout --filter=blob:none "file://$PWD/origin" blobless &&
+      git clone -q --no-checkout --filter=tree:0 "file://$PWD/origin" treeless
+    '
+      it "finds commits of blobs not available locally" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C origin rev-parse HEAD~1:dir/a | '$exe' --head-only blobless 2>/dev/null | tr ' ' '\n' | sort | diff - <(git -C origin rev-parse HEAD HEAD~1 | sort)"
+      }
+      it "says that it is a partial clone" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only blobless 2>&1 | grep -q 'is a partial clone, objects which .* are skipped'"
+      }
+      it "does not consider promised objects corrupt with --fsck-lite" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --fsck-lite blobless 2>&1 | grep -q 'Object database looks healthy'"
+      }
+      it "counts trees which are not available locally" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only treeless 2>&1 | grep -q '^READY:.*, [0-9]* trees could not be read'"
+      }
+      it "fetches missing trees with --fetch-missing" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C origin rev-parse HEAD:dir/b | '$exe' --head-only --fetch-missing treeless 2>&1 | grep -q '^READY:.*, fetched [0-9]* missing trees' && git -C origin rev-parse HEAD:dir/b | '$exe' --head-only treeless 2>/dev/null | grep -qx \"\$(git -C origin rev-parse HEAD)\""
+      }
+    )
+  )
+  (when "indexing multiple repositories into one graph (--repository)"
+    (sandbox '
+      git init -q upstream && cd upstream && echo shared > shared && git add . &&
+      git -c user.name=a -c user.email=b commit -qm shared && cd .. &&
+      git clone -q upstream fork && cd fork && echo forked > forked && git add . &&
+      git -c user.name=a -c user.email=b commit -qm forked && cd ..
+    '
+      it "finds blobs only in the other repository, prefixed with its name" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C fork rev-parse HEAD:forked | '$exe' --head-only --repository fork upstream 2>/dev/null | grep -qx \"fork:\$(git -C fork rev-parse HEAD)\""
+      }
+      it "indexes shared history once, attributing it to REPOSITORY" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C upstream rev-parse HEAD:shared | '$exe' --head-only --repository fork upstream 2>/dev/null | tr ' ' '\\n' | sort | diff - <( (echo \"upstream:\$(git -C upstream rev-parse HEAD)\"; echo \"fork:\$(git -C fork rev-parse HEAD)\") | sort)"
+      }
+      it "records the repositories with the cache" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --repository fork --cache-path multi.bincode upstream 2>/dev/null && git -C fork rev-parse HEAD:forked | '$exe' --head-only --repository fork --cache-path multi.bincode upstream 2>&1 | grep -q 'traversal: .*repositories=\\[\"fork\"\\]'"
+      }
+      it "labels commits by the repositories recorded with the cache" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C fork rev-parse HEAD:forked | '$exe' --head-only --cache-path multi.bincode upstream 2>/dev/null | grep -qx \"fork:\$(git -C fork rev-parse HEAD)\""
+      }
+      it "tells repositories of the same name apart by their parent directory" && {
+        expect_run_sh ${SUCCESSFULLY} "mkdir a b && git clone -q upstream a/repo && git clone -q fork b/repo && git -C fork rev-parse HEAD:forked | '$exe' --head-only --repository b/repo a/repo 2>/dev/null | grep -qx \"b/repo:\$(git -C fork rev-parse HEAD)\""
+      }
+    )
+  )
+  (when "linked worktrees have detached HEADs (--include-worktrees)"
+    (sandbox '
+      git init -q main && cd main && echo main > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm main &&
+      for wt in detached locked gone; do
+        git worktree add -q --detach ../$wt && (cd ../$wt && echo $wt > file &&
+        git -c user.name=a -c user.email=b commit -qam $wt)
+      done &&
+      git worktree lock ../locked && rm -rf ../gone && cd ..
+    '
+      it "finds blobs only in the HEAD of a linked worktree" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C detached rev-parse HEAD:file | '$exe' --head-only --include-worktrees main 2>/dev/null | grep -qx \"\$(git -C detached rev-parse HEAD)\""
+      }
+      it "finds blobs of the main worktree when pointed at a linked one" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C main rev-parse HEAD:file | '$exe' --head-only --include-worktrees detached 2>/dev/null | grep -qx \"\$(git -C main rev-parse HEAD)\""
+      }
+      it "skips locked and prunable worktrees with a warning" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --include-worktrees main 2>&1 | grep -q \"Skipping worktree 'locked' as it is locked\" && echo | '$exe' --head-only --include-worktrees main 2>&1 | grep -q \"Skipping worktree 'gone' as it is prunable\""
+      }
+      it "reports the pushed worktree HEADs" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --include-worktrees main 2>&1 | grep -q '^READY:.*, pushed 1 worktree HEADs'"
+      }
+    )
+  )
+  (when "the repository has stash entries (--include-stash)"
+    (sandbox '
+      git init -q && echo committed > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm committed &&
+      echo stashed > file && echo untracked > new &&
+      git -c user.name=a -c user.email=b stash push -qu &&
+      echo newer > file && git -c user.name=a -c user.email=b stash push -q
+    '
+      it "finds blobs only in a stash entry and labels its commit" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse stash@{1}:file | '$exe' --include-stash . 2>/dev/null | grep -qx \"\$(git rev-parse stash@{1}):stash@{1}\""
+      }
+      it "labels the commit recording untracked files" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse stash@{1}^3:new | '$exe' --include-stash . 2>/dev/null | grep -qx \"\$(git rev-parse stash@{1}^3):stash@{1}^3\""
+      }
+      it "reports the pushed stash entries" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --include-stash . 2>&1 | grep -q '^READY:.*, pushed 2 stash entries'"
+      }
+      it "does not find stashed blobs without it" && {
+        expect_run_sh 2 "git rev-parse stash@{0}:file | '$exe' --on-empty=fail . 2>/dev/null"
+      }
+    )
+    (sandbox '
+      git init -q && echo committed > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm committed
+    '
+      it "succeeds without a stash" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --include-stash . 2>&1 | grep -q '^READY:.*, pushed 0 stash entries'"
+      }
+    )
+  )
+  (when "blobs are staged but not committed (--include-index)"
+    (sandbox '
+      git init -q && echo committed > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm committed &&
+      echo staged > new && git add new
+    '
+      it "reports staged blobs with the index" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse :new | '$exe' --include-index . 2>/dev/null | grep -qx ':index'"
+      }
+      it "reports committed blobs which are staged as well with their commits and the index" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse :file | '$exe' --include-index . 2>/dev/null | grep -qx \"\$(git rev-parse HEAD) :index\""
+      }
+      it "reports the found staged blobs" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --include-index . 2>&1 | grep -q '^READY:.*, found 2 staged blobs'"
+      }
+      it "does not find staged blobs without it" && {
+        expect_run_sh 2 "git rev-parse :new | '$exe' --on-empty=fail . 2>/dev/null"
+      }
+      it "doesn't count the index as commit" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse :new :file | '$exe' --include-index . 2>&1 >/dev/null | grep -q '^DONE: Looked up 2 blobs with a total of 1 commits, 2 blobs staged in the index'"
+      }
+      it "considers blobs only staged in the index as not contained in any commit" && {
+        expect_run_sh 2 "git rev-parse :new | '$exe' --include-index --on-empty=fail . 2>/dev/null"
+      }
+    )
+  )
+  (when "a tree has the same blob at multiple paths"
+    (sandbox '
+      git init -q && echo same > a && echo same > b && echo other > c && git add . &&
+      git -c user.name=a -c user.email=b commit -qm same
+    '
+      it "stores the edge to the tree only once and reports the duplicate" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' . 2>&1 | grep -q '^READY:.* with graph with 4 vertices and 2 parent-edges, 3 parent-edges before removing 1 duplicates'"
+      }
+      it "finds the commit of the blob once" && {
+        expect_run_sh ${SUCCESSFULLY} "git rev-parse HEAD:a | '$exe' . 2>/dev/null | grep -qx \"\$(git rev-parse HEAD)\""
+      }
+    )
+    (sandbox '
+      git init -q && mkdir s && echo same > a && echo same > s/b && echo same > z && git add . &&
+      git -c user.name=a -c user.email=b commit -qm same
+    '
+      it "removes duplicates which are separated by the entries of a subtree" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' . 2>&1 | grep -q '^READY:.* with graph with 4 vertices and 2 parent-edges, 3 parent-edges before removing 1 duplicates'"
+      }
+    )
+  )
+  (when "restricting the indexed blobs to paths (--path)"
+    (sandbox '
+      git init -q mono && cd mono && mkdir -p services/payments/lib services/other vendor/lib &&
+      echo pay > services/payments/pay && echo other > services/other/other &&
+      echo shared > services/payments/lib/shared && cp services/payments/lib/shared vendor/lib/ &&
+      echo readme > README.md && git add . && git -c user.name=a -c user.email=b commit -qm mono && cd ..
+    '
+      it "finds blobs below the path" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C mono rev-parse HEAD:services/payments/pay | '$exe' --head-only --path services/payments/ mono 2>/dev/null | grep -qx \"\$(git -C mono rev-parse HEAD)\""
+      }
+      it "finds blobs in trees also present outside of the path" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C mono rev-parse HEAD:vendor/lib/shared | '$exe' --head-only --path services/payments mono 2>/dev/null | grep -qx \"\$(git -C mono rev-parse HEAD)\""
+      }
+      it "finds nothing for blobs outside of the path" && {
+        expect_run_sh 2 "git -C mono rev-parse HEAD:services/other/other | '$exe' --on-empty=fail --head-only --path services/payments mono 2>/dev/null"
+      }
+      it "matches globs against full paths" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C mono rev-parse HEAD:README.md | '$exe' --head-only --path '*.md' mono 2>/dev/null | grep -qx \"\$(git -C mono rev-parse HEAD)\""
+      }
+      it "refuses to answer from a cache built for other paths" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo | '$exe' --head-only --path services/payments --cache-path pay.bincode mono 2>/dev/null && echo | '$exe' --head-only --cache-path pay.bincode mono 2>&1 | grep -q 'indexes blobs at the paths'"
+      }
+    )
+  )
+  (when "skipping large blobs (--max-blob-size)"
+    (sandbox '
+      git init -q sized && cd sized && echo small > small && head -c 4096 /dev/zero > large &&
+      git add . && git -c user.name=a -c user.email=b commit -qm sized && cd ..
+    '
+      it "indexes blobs up to the size" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C sized rev-parse HEAD:small | '$exe' --head-only --max-blob-size 1024 sized 2>/dev/null | grep -qx \"\$(git -C sized rev-parse HEAD)\""
+      }
+      it "reports the skipped blobs" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-blob-size 1024 sized 2>&1 | grep -q '^READY:.*, skipped 1 blobs larger than --max-blob-size'"
+      }
+      it "marks lookups of skipped blobs as not indexed" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C sized rev-parse HEAD:large | '$exe' --head-only --max-blob-size 1024 sized 2>/dev/null | grep -qx \"not-indexed \$(git -C sized rev-parse HEAD:large) size-filter\""
+      }
+      it "marks them as not indexed when streaming" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C sized rev-parse HEAD:large | '$exe' --head-only --stream --max-blob-size 1024 sized 2>/dev/null | grep -qx \"not-indexed \$(git -C sized rev-parse HEAD:large) size-filter\""
+      }
+      it "keeps marking them when loading the graph from its cache" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --max-blob-size 1024 --cache-path sized.bincode sized 2>/dev/null && git -C sized rev-parse HEAD:large | '$exe' --head-only --max-blob-size 1024 --cache-path sized.bincode sized 2>/dev/null | grep -qx \"not-indexed \$(git -C sized rev-parse HEAD:large) size-filter\""
+      }
+      it "fails with --objects-from-batch" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; echo | '$exe' --max-blob-size 1024 --objects-from-batch 'git -C sized cat-file --batch' --tips \$(git -C sized rev-parse HEAD) sized 2>&1 | grep -q 'reads blobs entirely'"
+      }
+    )
+  )
+  (when "REPOSITORY is not given or a subdirectory"
+    (sandbox '
+      git init -q project && cd project && mkdir -p deep/er && echo found > deep/er/file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm project && cd .. && git clone -q --bare project bare.git
+    '
+      it "discovers the repository from the current directory" && {
+        expect_run_sh ${SUCCESSFULLY} "cd project/deep/er && git rev-parse HEAD:deep/er/file | '$exe' --head-only 2>/dev/null | grep -qx \"\$(git rev-parse HEAD)\""
+      }
+      it "discovers the repository from a subdirectory given as REPOSITORY" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C project rev-parse HEAD:deep/er/file | '$exe' --head-only project/deep 2>/dev/null | grep -qx \"\$(git -C project rev-parse HEAD)\""
+      }
+      it "uses GIT_DIR if REPOSITORY is not given" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C project rev-parse HEAD:deep/er/file | GIT_DIR=bare.git '$exe' --head-only 2>/dev/null | grep -qx \"\$(git -C project rev-parse HEAD)\""
+      }
+      it "names the searched path if there is no repository" && {
+        expect_run_sh ${WITH_FAILURE} "set -o pipefail; mkdir -p nothing && echo | '$exe' --head-only nothing 2>&1 | grep -q \"Could not find a git repository at '.*/nothing' or any of its parent directories\""
+      }
+    )
+  )
+  (when "the repository has an initialized submodule (--recurse-submodules)"
+    (sandbox '
+      git init -q lib && cd lib && echo inner > inner && git add . &&
+      git -c user.name=a -c user.email=b commit -qm inner && cd .. &&
+      git init -q super && cd super && echo outer > outer && git add . &&
+      git -c protocol.file.allow=always submodule add -q ../lib lib &&
+      git -c user.name=a -c user.email=b commit -qm outer && cd ..
+    '
+      it "does not find blobs of the submodule by default" && {
+        expect_run_sh 2 "git -C lib rev-parse HEAD:inner | '$exe' --on-empty=fail --head-only super 2>/dev/null"
+      }
+      it "attributes blobs of the submodule to the commit of the superproject" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD:inner | '$exe' --head-only --recurse-submodules super 2>/dev/null | head -1 | grep -qx \"\$(git -C super rev-parse HEAD)\""
+      }
+      it "finds the commits of the superproject which pinned the submodule at a commit" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD | '$exe' --head-only super 2>/dev/null | grep -qx \"\$(git -C super rev-parse HEAD)\""
+      }
+      it "does not record the commits of submodules with --no-gitlinks" && {
+        expect_run_sh 2 "git -C lib rev-parse HEAD | '$exe' --on-empty=fail --head-only --no-gitlinks super 2>/dev/null"
+      }
+      it "lists gitlinks as their own kind of vertex" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --dump-oids=gitlink super 2>/dev/null | cut -d ' ' -f 2 | grep -qx \"\$(git -C lib rev-parse HEAD)\""
+      }
+      it "indexes the commit of a gitlink as commit when its repository is given with --repository" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD:inner | '$exe' --head-only --repository lib super 2>/dev/null | grep -qx \"lib:\$(git -C lib rev-parse HEAD)\""
+      }
+      it "keeps a single vertex of kind commit for it" && {
+        expect_run_sh ${SUCCESSFULLY} "'$exe' --head-only --repository lib --dump-oids super 2>/dev/null | grep \" \$(git -C lib rev-parse HEAD) \" | cut -d ' ' -f 3 | tr '\\n' ' ' | grep -qx 'commit '"
+      }
+      it "still finds the commits of the superproject which pinned it" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD | '$exe' --head-only --repository lib super 2>/dev/null | grep -q \"\$(git -C super rev-parse HEAD)\$\""
+      }
+      it "still finds the commits of the superproject which pinned it when its repository comes first" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD | '$exe' --head-only --repository super lib 2>/dev/null | grep -q \"\$(git -C super rev-parse HEAD)\$\""
+      }
+      it "names the submodule the blob was found in" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C lib rev-parse HEAD:inner | '$exe' --head-only --recurse-submodules super 2>/dev/null | grep -qx \"submodule \$(git -C lib rev-parse HEAD:inner) lib\""
+      }
+    )
+  )
+  (when "the repository has a nested submodule and a clone without initialized submodules"
+    (sandbox '
+      git init -q deep && cd deep && echo deepest > deepest && git add . &&
+      git -c user.name=a -c user.email=b commit -qm deep && cd .. &&
+      git init -q lib && cd lib && echo inner > inner && git add . &&
+      git -c protocol.file.allow=always submodule add -q ../deep deep &&
+      git -c user.name=a -c user.email=b commit -qm inner && cd .. &&
+      git init -q super && cd super && echo outer > outer && git add . &&
+      git -c protocol.file.allow=always submodule add -q ../lib lib &&
+      git -c user.name=a -c user.email=b commit -qm outer && cd .. &&
+      git clone -q super clone
+    '
+      it "reports the gitlink of the nested submodule as skipped" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --recurse-submodules super 2>&1 | grep -q 'skipped 1 gitlinks whose commit is in no initialized submodule'"
+      }
+      it "reports the gitlinks of uninitialized submodules as skipped" && {
+        expect_run_sh ${SUCCESSFULLY} "echo | '$exe' --head-only --recurse-submodules clone 2>&1 | grep -q 'skipped 1 gitlinks whose commit is in no initialized submodule'"
+      }
+      it "does not report skipped gitlinks without --recurse-submodules" && {
+        expect_run_sh ${SUCCESSFULLY} "! (echo | '$exe' --head-only clone 2>&1 | grep -q 'gitlinks whose commit')"
+      }
+    )
+  )
+  (when "iterating a repository with a single branch and no remotes"
+    (sandbox '
+      git init -q single && cd single && echo content > file && git add . &&
+      git -c user.name=a -c user.email=b commit -qm initial && cd ..
+    '
+      it "uses head without warning about missing remotes" && {
+        expect_run_sh ${SUCCESSFULLY} "git -C single rev-parse HEAD:file | '$exe' single 2>&1 | grep -c \"Didn't find a single remote\" | grep -qx 0"
+      }
+    )
+  )
 )